@@ -0,0 +1,24 @@
+use detour::{DetourBuilder, DetourClientBuilder, SplitStrategy};
+
+// cargo run --example builder --features client -- https://example.com
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let url = std::env::args().nth(1).unwrap_or_else(|| "https://hyper.rs".into());
+
+    let mut detour = DetourBuilder::new();
+    detour
+        .strategy(SplitStrategy::SniFraction(0.5))
+        .retry(2);
+
+    // the connector and the client, in one go
+    let mut builder = DetourClientBuilder::new();
+    builder.detour(detour);
+    builder.hyper().pool_max_idle_per_host(4);
+    let client = builder.build::<hyper::Body>()?;
+
+    let res = client.get(url.parse()?).await?;
+
+    println!("Status: {}", res.status());
+    println!("Headers:\n{:#?}", res.headers());
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use hyper::{body::HttpBody as _, Client};
-use hyper_tls::HttpsConnector;
+use detour::HttpsConnector;
 use tokio::io::{self, AsyncWriteExt as _};
 
 #[tokio::main(flavor = "current_thread")]
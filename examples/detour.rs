@@ -0,0 +1,25 @@
+use hyper::Client;
+use detour::{DetourBuilder, HttpsConnector, SplitStrategy};
+
+// cargo run --example detour -- https://example.com
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let url = std::env::args().nth(1).unwrap_or_else(|| "https://hyper.rs".into());
+
+    // cut the ClientHello right in the middle of the hostname, so neither
+    // record carries it whole, and try another cut if the handshake fails
+    let mut detour = DetourBuilder::new();
+    detour
+        .strategy(SplitStrategy::SniFraction(0.5))
+        .flush_between(true)
+        .retry(2);
+
+    let https = HttpsConnector::with_detour(&detour);
+    let client = Client::builder().build::<_, hyper::Body>(https);
+
+    let res = client.get(url.parse()?).await?;
+
+    println!("Status: {}", res.status());
+    println!("Headers:\n{:#?}", res.headers());
+    Ok(())
+}
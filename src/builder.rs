@@ -0,0 +1,157 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+
+use hyper::body::HttpBody;
+use hyper::client::{Builder, HttpConnector};
+use hyper::Client;
+use tokio_native_tls::TlsConnector;
+
+use crate::client::{HttpsConnector, HttpsConnectorBuilder};
+use crate::config::DetourBuilder;
+
+/// A builder for a whole `hyper::Client`, detouring every HTTPS connection.
+///
+/// This puts an `HttpsConnectorBuilder` and hyper's own `client::Builder`
+/// behind one call, for when a client is all that's needed:
+///
+/// ```no_run
+/// use detour::{DetourBuilder, DetourClientBuilder, SplitStrategy};
+///
+/// # fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut detour = DetourBuilder::new();
+/// detour.strategy(SplitStrategy::SniFraction(0.5)).retry(2);
+///
+/// let client = DetourClientBuilder::new()
+///     .detour(detour)
+///     .build::<hyper::Body>()?;
+/// # let _ = client;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct DetourClientBuilder {
+    https: HttpsConnectorBuilder,
+    client: Builder,
+}
+
+impl DetourClientBuilder {
+    /// Construct a new builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the options used to wrap every new connection in a `Detour`.
+    ///
+    /// See `HttpsConnectorBuilder::detour`.
+    pub fn detour(&mut self, detour: DetourBuilder) -> &mut Self {
+        self.https.detour(detour);
+        self
+    }
+
+    /// Use a preconfigured `TlsConnector` instead of the default one.
+    ///
+    /// See `HttpsConnectorBuilder::tls`.
+    pub fn tls(&mut self, tls: TlsConnector) -> &mut Self {
+        self.https.tls(tls);
+        self
+    }
+
+    /// Get the builder of the connector, for the rest of its options.
+    pub fn https(&mut self) -> &mut HttpsConnectorBuilder {
+        &mut self.https
+    }
+
+    /// Get hyper's builder of the client, for its pool and HTTP options.
+    pub fn hyper(&mut self) -> &mut Builder {
+        &mut self.client
+    }
+
+    /// Build the `Client`.
+    ///
+    /// This fails if no `TlsConnector` was given and the default TLS context
+    /// could not be created.
+    pub fn build<B>(&self) -> Result<Client<HttpsConnector<HttpConnector>, B>, native_tls::Error>
+    where
+        B: HttpBody + Send,
+        B::Data: Send,
+    {
+        let https = self.https.build()?;
+        Ok(self.client.build(https))
+    }
+}
+
+impl fmt::Debug for DetourClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DetourClientBuilder")
+            .field("https", &self.https)
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_native_tls::TlsAcceptor;
+
+    use super::*;
+    use crate::mock::{tls_acceptor, tls_connector};
+    use crate::DetourInfo;
+
+    // An HTTPS server answering the first request of each connection
+    async fn server() -> u16 {
+        let acceptor = TlsAcceptor::from(tls_acceptor().build().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls = acceptor.accept(tcp).await.unwrap();
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        request.push(tls.read_u8().await.unwrap());
+                    }
+                    let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                    tls.write_all(response).await.unwrap();
+                    let _ = tls.read(&mut [0]).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn get() {
+        let port = server().await;
+        let fragmented = Arc::new(AtomicUsize::new(0));
+        let counter = fragmented.clone();
+
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new()).on_fragment(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let client = DetourClientBuilder::new()
+            .tls(tls_connector())
+            .detour(detour)
+            .build::<hyper::Body>()
+            .unwrap();
+
+        let uri = format!("https://localhost:{}/", port).parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), 200);
+        // The detour is seen through the response, and it fragmented
+        let info = response.extensions().get::<DetourInfo>().unwrap();
+        assert_eq!(info.sni(), Some("localhost"));
+        assert_eq!(fragmented.load(Ordering::Relaxed), 1);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+    }
+}
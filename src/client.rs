@@ -2,17 +2,31 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::collections::HashMap;
 use std::fmt;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use hyper::client::connect::dns::{GaiResolver, Name};
 use hyper::{client::connect::HttpConnector, service::Service, Uri};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
 use tokio_native_tls::TlsConnector;
 
-use crate::stream::MaybeHttpsStream;
+use crate::stream::{MaybeHttpsStream, TlsStream};
 use crate::detour::Detour;
+use crate::socket::AsTcpStream;
+use crate::proxy::ProxyProtocol;
+use crate::sni::{sni_override, tls_override};
+use crate::hello::decoy;
+use crate::config::{DetourBuilder, DetourConfig, DetourHandle};
+use crate::strategy::SplitStrategy;
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -20,8 +34,15 @@ type BoxError = Box<dyn std::error::Error + Send + Sync>;
 #[derive(Clone)]
 pub struct HttpsConnector<T> {
     force_https: bool,
+    force_tls: bool,
     http: T,
     tls: TlsConnector,
+    detour: DetourHandle,
+    handshake_timeout: Option<Duration>,
+    handshakes: Option<Arc<Semaphore>>,
+    proxy_protocol: Option<ProxyProtocol>,
+    connect_to: HashMap<String, String>,
+    tcp: Option<Arc<dyn TcpAccess<T>>>,
 }
 
 impl HttpsConnector<HttpConnector> {
@@ -50,10 +71,28 @@ impl HttpsConnector<HttpConnector> {
             .unwrap_or_else(|e| panic!("HttpsConnector::new() failure: {}", e))
     }
 
+    /// Construct a new HttpsConnector, wrapping every connection in a
+    /// `Detour` made with the given options.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the underlying TLS context could not be created,
+    /// just like `HttpsConnector::new`.
+    pub fn with_detour(detour: &DetourBuilder) -> Self {
+        let mut https = HttpsConnector::new();
+        https.set_detour(detour);
+        https
+    }
+
     fn new_(tls: TlsConnector) -> Self {
         let mut http = HttpConnector::new();
         http.enforce_http(false);
-        HttpsConnector::from((http, tls))
+        HttpsConnector::from((http, tls)).with_tcp_access()
+    }
+
+    /// Return a builder to configure a new HttpsConnector.
+    pub fn builder() -> HttpsConnectorBuilder {
+        HttpsConnectorBuilder::new()
     }
 }
 
@@ -66,11 +105,122 @@ impl<T: Default> Default for HttpsConnector<T> {
 impl<T> HttpsConnector<T> {
     /// Force the use of HTTPS when connecting.
     ///
-    /// If a URL is not `https` when connecting, an error is returned.
+    /// If a URL is not `https` when connecting, an error is returned:
+    /// `Error::ForceHttpsButUriNotHttps`.
     pub fn https_only(&mut self, enable: bool) {
         self.force_https = enable;
     }
 
+    /// Never let a connection go in plaintext, upgrading `http` URLs to TLS.
+    ///
+    /// Unlike `https_only`, which turns away every URL that isn't `https`,
+    /// this takes `http` ones as well: their connection is made over TLS
+    /// all the same, to the port of the URL, or 443 without one. If TLS
+    /// can't be used, e.g. because the server only speaks plaintext, the
+    /// connection fails with the error of the handshake instead of going on
+    /// unencrypted. Any other scheme fails with
+    /// `Error::ForceHttpsButUriNotHttps`.
+    pub fn force_tls(&mut self, enable: bool) {
+        self.force_tls = enable;
+    }
+
+    /// Set the options used to wrap every new connection in a `Detour`.
+    ///
+    /// This applies to every clone of this connector, as they share the
+    /// same `DetourHandle`.
+    pub fn set_detour(&mut self, detour: &DetourBuilder) {
+        self.detour.store(detour.build_shared());
+    }
+
+    /// Turn this connector into one detouring with `config`, keeping every
+    /// other option, TLS included; see `DetourBuilder::build_shared`.
+    ///
+    /// Unlike `set_detour`, the returned connector gets a `DetourHandle` of
+    /// its own, so clones made before are left as they were.
+    pub fn with_detour_config(mut self, config: Arc<DetourConfig>) -> Self {
+        self.detour = DetourHandle::new(config);
+        self
+    }
+
+    /// Get a handle to change the detour options at runtime.
+    ///
+    /// A new config takes effect from the next connection on.
+    pub fn detour_handle(&self) -> DetourHandle {
+        self.detour.clone()
+    }
+
+    /// Set a timeout for the TLS handshake.
+    ///
+    /// The timer starts after the underlying connection is established, and
+    /// is independent from any connect timeout of the inner connector.
+    pub fn set_handshake_timeout(&mut self, dur: Option<Duration>) {
+        self.handshake_timeout = dur;
+    }
+
+    /// Limit how many connections may be set up at once, or `None` for no
+    /// limit.
+    ///
+    /// A connection waits for its turn before dialing, and holds it through
+    /// every retried handshake until it's ready or failed. Clones of this
+    /// connector share the limit; `connect_over` isn't counted. A limit of
+    /// 0 is taken as 1.
+    pub fn set_max_concurrent_handshakes(&mut self, n: Option<usize>) {
+        self.handshakes = n.map(|n| Arc::new(Semaphore::new(n.max(1))));
+    }
+
+    /// Send a PROXY protocol header before anything else on every new
+    /// connection.
+    ///
+    /// This is for reaching a server behind a load balancer which expects
+    /// one. It's sent once per connection, before the TLS handshake.
+    pub fn set_proxy_protocol(&mut self, version: Option<ProxyProtocol>) {
+        self.proxy_protocol = version;
+    }
+
+    /// Dial another address than the one of the URL, like curl's
+    /// `--connect-to`.
+    ///
+    /// The keys and the values are both `host:port`, with an IPv6 literal in
+    /// brackets as in a URL; a URL whose host and port, or default port,
+    /// match a key is dialed at its value instead.
+    /// The URL's host is still used for SNI and for splitting the
+    /// ClientHello, and hyper still sends it in the `Host` header.
+    pub fn set_connect_to(&mut self, map: HashMap<String, String>) {
+        self.connect_to = map;
+    }
+
+    // the URI to dial for `dst`, as overridden by `connect_to`
+    fn dial_target(&self, dst: &Uri) -> Result<Uri, BoxError> {
+        let port = default_port(dst);
+        let key = format!("{}:{}", dst.host().unwrap_or(""), port);
+        let target = match self.connect_to.get(&key) {
+            Some(target) => target,
+            None => return Ok(dst.clone()),
+        };
+
+        let mut parts = dst.clone().into_parts();
+        parts.authority = Some(target.parse()?);
+        Ok(Uri::from_parts(parts)?)
+    }
+
+    /// Run a TLS handshake over an already connected stream.
+    ///
+    /// This is for streams dialed some other way, e.g. through a proxy. The
+    /// stream is wrapped in a `Detour` with the current options, and the
+    /// handshake timeout applies, but a failed handshake is not retried:
+    /// the stream can't be dialed again.
+    pub async fn connect_over<S>(
+        &self,
+        stream: S,
+        domain: &str,
+    ) -> Result<TlsStream<Detour<S>>, BoxError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let detoured = self.detour.wrap(stream);
+        handshake(&self.tls, tls_domain(domain), detoured, self.handshake_timeout).await
+    }
+
     /// With connector constructor
     ///
     pub fn new_with_connector(http: T) -> Self {
@@ -89,24 +239,275 @@ impl<T> From<(T, TlsConnector)> for HttpsConnector<T> {
     fn from(args: (T, TlsConnector)) -> HttpsConnector<T> {
         HttpsConnector {
             force_https: false,
+            force_tls: false,
             http: args.0,
             tls: args.1,
+            detour: DetourHandle::default(),
+            handshake_timeout: None,
+            handshakes: None,
+            proxy_protocol: None,
+            connect_to: HashMap::new(),
+            tcp: None,
         }
     }
 }
 
+impl<T> HttpsConnector<T>
+where
+    T: Service<Uri>,
+    T::Response: AsTcpStream,
+{
+    /// Reach the TCP stream under every new connection, through
+    /// `AsTcpStream`.
+    ///
+    /// This lets `DetourBuilder::separate_segments` turn on `TCP_NODELAY`,
+    /// and a PROXY protocol header carry the addresses of the connection.
+    /// The constructors using `HttpConnector` already do it; a connector
+    /// made with `From::from` or `new_with_connector` starts without it, as
+    /// its connections needn't be TCP, and gets it with this if they are.
+    pub fn with_tcp_access(mut self) -> Self {
+        self.tcp = Some(Arc::new(WithTcp));
+        self
+    }
+}
+
 impl<T: fmt::Debug> fmt::Debug for HttpsConnector<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("HttpsConnector")
             .field("force_https", &self.force_https)
+            .field("force_tls", &self.force_tls)
             .field("http", &self.http)
+            .field("detour", &self.detour)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("handshakes", &self.handshakes)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("connect_to", &self.connect_to)
+            .field("tcp_access", &self.tcp.is_some())
+            .finish()
+    }
+}
+
+/// A builder for an `HttpsConnector` using hyper's `HttpConnector`.
+///
+/// It can be cloned to build many connectors from the same template.
+#[derive(Clone)]
+pub struct HttpsConnectorBuilder {
+    https_only: bool,
+    force_tls: bool,
+    connect_timeout: Option<Duration>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    local_address: Option<IpAddr>,
+    handshake_timeout: Option<Duration>,
+    max_handshakes: Option<usize>,
+    detour: DetourBuilder,
+    proxy_protocol: Option<ProxyProtocol>,
+    connect_to: HashMap<String, String>,
+    tls: Option<TlsConnector>,
+}
+
+impl Default for HttpsConnectorBuilder {
+    fn default() -> Self {
+        Self {
+            https_only: false,
+            force_tls: false,
+            connect_timeout: None,
+            nodelay: true,
+            keepalive: None,
+            local_address: None,
+            handshake_timeout: None,
+            max_handshakes: None,
+            detour: DetourBuilder::default(),
+            proxy_protocol: None,
+            connect_to: HashMap::new(),
+            tls: None,
+        }
+    }
+}
+
+impl HttpsConnectorBuilder {
+    /// Construct a new builder with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Force the use of HTTPS when connecting.
+    ///
+    /// See `HttpsConnector::https_only`.
+    pub fn https_only(&mut self, enable: bool) -> &mut Self {
+        self.https_only = enable;
+        self
+    }
+
+    /// Never let a connection go in plaintext, upgrading `http` URLs to TLS.
+    ///
+    /// See `HttpsConnector::force_tls`.
+    pub fn force_tls(&mut self, enable: bool) -> &mut Self {
+        self.force_tls = enable;
+        self
+    }
+
+    /// Set a timeout for establishing the TCP connection.
+    ///
+    /// This is forwarded to `HttpConnector::set_connect_timeout`.
+    pub fn connect_timeout(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.connect_timeout = dur;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on every new connection, so the ClientHello, or
+    /// each of its fragments, goes out right away. Defaults to true.
+    ///
+    /// This is forwarded to `HttpConnector::set_nodelay`.
+    pub fn nodelay(&mut self, enable: bool) -> &mut Self {
+        self.nodelay = enable;
+        self
+    }
+
+    /// Set `SO_KEEPALIVE` on every new connection, with the given idle time
+    /// before the first probe. Defaults to `None`, leaving it unset.
+    ///
+    /// This keeps long-lived connections, e.g. for gRPC, from being dropped
+    /// silently by a middlebox. It's forwarded to
+    /// `HttpConnector::set_keepalive`.
+    pub fn keepalive(&mut self, dur: Option<Duration>) -> &mut Self {
+        self.keepalive = dur;
+        self
+    }
+
+    /// Bind every new connection to a local address before dialing, e.g. to
+    /// choose the interface to go out of on a host with several, or a VPN.
+    ///
+    /// This is forwarded to `HttpConnector::set_local_address`; the detour
+    /// and the TLS layer above leave the socket as it was dialed.
+    pub fn local_address(&mut self, addr: IpAddr) -> &mut Self {
+        self.local_address = Some(addr);
+        self
+    }
+
+    /// Set a timeout for the TLS handshake.
+    ///
+    /// A censored endpoint often accepts the TCP connection but stalls the
+    /// handshake, so this is applied only around the handshake, separately
+    /// from the connect timeout.
+    pub fn handshake_timeout(&mut self, dur: Duration) -> &mut Self {
+        self.handshake_timeout = Some(dur);
+        self
+    }
+
+    /// Limit how many connections may be set up at once.
+    ///
+    /// Fragmenting and retrying make each handshake heavier, which adds up
+    /// with many requests to blocked hosts at once. See
+    /// `HttpsConnector::set_max_concurrent_handshakes`.
+    pub fn max_concurrent_handshakes(&mut self, n: usize) -> &mut Self {
+        self.max_handshakes = Some(n);
+        self
+    }
+
+    /// Set the options used to wrap every new connection in a `Detour`.
+    pub fn detour(&mut self, detour: DetourBuilder) -> &mut Self {
+        self.detour = detour;
+        self
+    }
+
+    /// Send a PROXY protocol header before the TLS handshake.
+    ///
+    /// See `HttpsConnector::set_proxy_protocol`.
+    pub fn proxy_protocol(&mut self, version: ProxyProtocol) -> &mut Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    /// Dial another address than the one of the URL.
+    ///
+    /// See `HttpsConnector::set_connect_to`.
+    pub fn connect_to(&mut self, map: HashMap<String, String>) -> &mut Self {
+        self.connect_to = map;
+        self
+    }
+
+    /// Use a preconfigured `TlsConnector` instead of the default one.
+    ///
+    /// There's no need to validate it up front: a `TlsConnector` is built
+    /// eagerly, so a bad client identity or root certificate already fails
+    /// at `native_tls::TlsConnectorBuilder::build`, before it gets here.
+    pub fn tls(&mut self, tls: TlsConnector) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Build the HttpsConnector.
+    ///
+    /// This fails if no `TlsConnector` was given and the default TLS context
+    /// could not be created.
+    pub fn build(&self) -> Result<HttpsConnector<HttpConnector>, native_tls::Error> {
+        self.build_with_resolver(GaiResolver::new())
+    }
+
+    /// Build the HttpsConnector, resolving hostnames with `resolver`
+    /// instead of the system's resolver.
+    ///
+    /// The detour hides the hostname from the TLS handshake only; a DNS
+    /// query sent in plaintext gives it away just the same, before any
+    /// connection is made. A resolver of your own, e.g. over DoH or DoT,
+    /// closes that leak. It's any `Service<Name>` returning socket
+    /// addresses, as with `HttpConnector::new_with_resolver`.
+    pub fn build_with_resolver<R>(
+        &self,
+        resolver: R,
+    ) -> Result<HttpsConnector<HttpConnector<R>>, native_tls::Error>
+    where
+        R: Service<Name> + Clone + Send + Sync + 'static,
+        R::Response: Iterator<Item = SocketAddr>,
+        R::Error: Into<BoxError>,
+        R::Future: Send,
+    {
+        let tls = match &self.tls {
+            Some(tls) => tls.clone(),
+            None => native_tls::TlsConnector::new()?.into(),
+        };
+
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+        http.set_nodelay(self.nodelay);
+        http.set_keepalive(self.keepalive);
+        http.set_local_address(self.local_address);
+
+        let mut https = HttpsConnector::from((http, tls)).with_tcp_access();
+        https.https_only(self.https_only);
+        https.force_tls(self.force_tls);
+        https.set_detour(&self.detour);
+        https.set_handshake_timeout(self.handshake_timeout);
+        https.set_max_concurrent_handshakes(self.max_handshakes);
+        https.set_proxy_protocol(self.proxy_protocol);
+        https.set_connect_to(self.connect_to.clone());
+        Ok(https)
+    }
+}
+
+impl fmt::Debug for HttpsConnectorBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpsConnectorBuilder")
+            .field("https_only", &self.https_only)
+            .field("force_tls", &self.force_tls)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("nodelay", &self.nodelay)
+            .field("keepalive", &self.keepalive)
+            .field("local_address", &self.local_address)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("max_handshakes", &self.max_handshakes)
+            .field("detour", &self.detour)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("connect_to", &self.connect_to)
             .finish()
     }
 }
 
 impl<T> Service<Uri> for HttpsConnector<T>
 where
-    T: Service<Uri>,
+    T: Service<Uri> + Clone + Send + 'static,
     T::Response: AsyncRead + AsyncWrite + Send + Unpin,
     T::Future: Send + 'static,
     T::Error: Into<BoxError>,
@@ -124,51 +525,370 @@ where
     }
 
     fn call(&mut self, dst: Uri) -> Self::Future {
-        let is_https = dst.scheme_str() == Some("https");
+        let mut is_https = dst.scheme_str() == Some("https");
         // Early abort if HTTPS is forced but can't be used
         if !is_https && self.force_https {
-            return err(ForceHttpsButUriNotHttps.into());
-        }
-
-        let host = dst
-            .host()
-            .unwrap_or("")
-            .trim_matches(|c| c == '[' || c == ']')
-            .to_owned();
-        let connecting = self.http.call(dst);
-        let tls = self.tls.clone();
-        let fut = async move {
-            let tcp = connecting.await.map_err(Into::into)?;
-            // modified here; simple!
-            let tcp = Detour::new(tcp);
+            return err(Error::ForceHttpsButUriNotHttps.into());
+        }
+        // An `http` URI goes over TLS all the same with `force_tls`; it's
+        // dialed as an `https` one, on 443 without a port of its own
+        let dst = match dst.scheme_str() {
+            Some("http") if self.force_tls => match upgraded(dst) {
+                Ok(dst) => dst,
+                Err(e) => return err(e.into()),
+            },
+            _ if !is_https && self.force_tls => {
+                return err(Error::ForceHttpsButUriNotHttps.into());
+            },
+            _ => dst,
+        };
+        is_https |= self.force_tls;
+
+        // Only the host is the TLS domain, without any userinfo or port;
+        // `with_sni` overrides it. An IP literal is passed without the
+        // brackets of IPv6, which are only for dialing; the TLS backend
+        // sends no SNI for it, and the detour falls back to its
+        // `FallbackStrategy`.
+        let host = match dst.host() {
+            Some(host) if !host.is_empty() => host,
+            _ => return err(Error::MissingHost.into()),
+        };
+        let host = tls_domain(host).to_owned();
+        let dst = match self.dial_target(&dst) {
+            Ok(dst) => dst,
+            Err(e) => return err(e),
+        };
+        let detour = self.detour.load();
+        let enabled = self.detour.is_enabled() && detour.applies_to_port(default_port(&dst));
+
+        let decoy = detour.decoy.clone().filter(|_| is_https && enabled);
+        // The inner connector made ready dials the connection, and goes
+        // with the future to dial the decoy and any retry, made ready again
+        // for each; hence a clone of it is left in its place
+        let ready = self.http.clone();
+        let mut http = std::mem::replace(&mut self.http, ready);
+        let connecting = http.call(dst.clone());
+
+        let phase = PhaseCell::default();
+        let connect = Connect {
+            is_https,
+            host,
+            tls: self.tls.clone(),
+            detour,
+            enabled,
+            handshake_timeout: self.handshake_timeout,
+            proxy_protocol: self.proxy_protocol,
+            handshakes: self.handshakes.clone(),
+            progress: phase.clone(),
+            decoy,
+            connecting,
+            http,
+            dst,
+        };
+        let fut = match &self.tcp {
+            Some(tcp) => tcp.connecting(connect),
+            None => connection::<T, NoTcp>(connect),
+        };
+        HttpsConnecting { fut, phase }
+    }
+}
+
+// everything a connecting future needs, taken from the connector
+struct Connect<T: Service<Uri>> {
+    is_https: bool,
+    host: String,
+    tls: TlsConnector,
+    detour: Arc<DetourConfig>,
+    enabled: bool,
+    handshake_timeout: Option<Duration>,
+    proxy_protocol: Option<ProxyProtocol>,
+    handshakes: Option<Arc<Semaphore>>,
+    progress: PhaseCell,
+    decoy: Option<String>,
+    connecting: T::Future,
+    // to dial the decoy and any retry with
+    http: T,
+    dst: Uri,
+}
+
+// how a connecting future gets to the tcp stream under a connection
+trait TcpOf<S> {
+    fn tcp(conn: &S) -> Option<&TcpStream>;
+}
+
+// for connections of any kind, with no tcp stream to reach
+struct NoTcp;
+
+impl<S> TcpOf<S> for NoTcp {
+    fn tcp(_: &S) -> Option<&TcpStream> {
+        None
+    }
+}
+
+// for connections implementing AsTcpStream, see `with_tcp_access`
+struct WithTcp;
+
+impl<S: AsTcpStream> TcpOf<S> for WithTcp {
+    fn tcp(conn: &S) -> Option<&TcpStream> {
+        conn.as_tcp_stream()
+    }
+}
 
-            let maybe = if is_https {
+// Makes the connecting futures of a connector reaching the tcp stream of
+// its connections. An `AsTcpStream` bound on the `Service` impl would shut
+// out connectors whose connections are of a foreign type, for which it
+// can't be implemented; the bound is here instead, where it's opted into,
+// and the future reaching the stream is picked once the inner connector has
+// been called.
+trait TcpAccess<T>: Send + Sync {
+    fn connecting(&self, connect: Connect<T>) -> BoxedFut<Detour<T::Response>>
+    where
+        T: Service<Uri> + Send + 'static,
+        T::Response: AsyncRead + AsyncWrite + Send + Unpin,
+        T::Future: Send + 'static,
+        T::Error: Into<BoxError>;
+}
+
+impl<T> TcpAccess<T> for WithTcp
+where
+    T: Service<Uri>,
+    T::Response: AsTcpStream,
+{
+    fn connecting(&self, connect: Connect<T>) -> BoxedFut<Detour<T::Response>>
+    where
+        T: Send + 'static,
+        T::Response: AsyncRead + AsyncWrite + Send + Unpin,
+        T::Future: Send + 'static,
+        T::Error: Into<BoxError>,
+    {
+        connection::<T, WithTcp>(connect)
+    }
+}
 
-                let tls = tls.connect(&host, tcp).await?;
-                MaybeHttpsStream::Https(tls)
+// the future connecting `connect`, reaching its tcp streams through `A`
+fn connection<T, A>(connect: Connect<T>) -> BoxedFut<Detour<T::Response>>
+where
+    T: Service<Uri> + Send + 'static,
+    T::Response: AsyncRead + AsyncWrite + Send + Unpin,
+    T::Future: Send + 'static,
+    T::Error: Into<BoxError>,
+    A: TcpOf<T::Response>,
+{
+    let Connect {
+        is_https,
+        host,
+        tls,
+        detour,
+        enabled,
+        handshake_timeout,
+        proxy_protocol,
+        handshakes,
+        progress,
+        decoy,
+        connecting,
+        mut http,
+        dst,
+    } = connect;
+
+    Box::pin(async move {
+        // Held until the connection is ready or failed; the semaphore is
+        // never closed
+        let _permit = match handshakes {
+            Some(handshakes) => handshakes.acquire_owned().await.ok(),
+            None => None,
+        };
+        // A hostname from `with_sni` takes over the one of the URI
+        let host = sni_override().map_or(host, |sni| tls_domain(&sni).to_owned());
+        // and one from `with_tls` takes over the TLS options
+        let tls = tls_override().unwrap_or(tls);
+        if let Some(sni) = decoy {
+            send_decoy::<_, _, A>(redial(&mut http, dst.clone()), &sni, proxy_protocol).await;
+        }
+
+        let mut tcp = connecting.await.map_err(Into::into)?;
+        send_proxy_header::<_, A>(&mut tcp, proxy_protocol).await?;
+        // only the TLS branch is detoured; plaintext goes as is
+        if !is_https {
+            progress.set(ConnectPhase::Done);
+            return Ok(MaybeHttpsStream::Http(Detour::passthrough(tcp)));
+        }
+
+        // the TLS backend sends no SNI for an IP literal
+        let sni = Some(host.as_str()).filter(|host| host.parse::<IpAddr>().is_err());
+        let mut attempts = Vec::new();
+        let mut attempt = 0;
+        loop {
+            if detour.nodelay {
+                if let Some(tcp) = A::tcp(&tcp) {
+                    tcp.set_nodelay(true)?;
+                }
+            }
+
+            // modified here; simple!
+            let fragments = enabled && detour.fragments(attempt);
+            let mut detoured = if fragments {
+                Detour::from_config(tcp, detour.clone())
             } else {
-                MaybeHttpsStream::Http(tcp)
+                Detour::passthrough(tcp)
             };
-            Ok(maybe)
-        };
-        HttpsConnecting(Box::pin(fut))
+            detoured.attempt = attempt;
+            // the detour moves on to handshaking once the hello is out
+            if fragments {
+                progress.set(ConnectPhase::Fragmenting);
+                detoured.phase = Some(progress.clone());
+            } else {
+                progress.set(ConnectPhase::Handshaking);
+            }
+
+            let strategy = fragments.then(|| detour.strategy_for(attempt, sni));
+            let started = Instant::now();
+            match handshake(&tls, &host, detoured, handshake_timeout).await {
+                Ok(mut tls) => {
+                    let elapsed = started.elapsed();
+                    attempts.push(Attempt { strategy, error: None, elapsed });
+                    let detoured = tls.get_mut().get_mut().get_mut();
+                    detoured.attempts = attempts;
+                    detoured.phase = None;
+                    progress.set(ConnectPhase::Done);
+                    return Ok(MaybeHttpsStream::Https(tls));
+                },
+                // nothing to tell but the error itself
+                Err(e) if detour.retries() == 0 => return Err(e),
+                Err(e) => {
+                    let elapsed = started.elapsed();
+                    attempts.push(Attempt { strategy, error: Some(e), elapsed });
+                    if attempt >= detour.retries() {
+                        return Err(Error::AttemptsFailed(attempts).into());
+                    }
+                    attempt += 1;
+                },
+            }
+
+            // a failed handshake leaves the connection unusable; try again
+            // on a fresh one, which will be split differently
+            progress.set(ConnectPhase::Connecting);
+            tcp = redial(&mut http, dst.clone()).await?;
+            send_proxy_header::<_, A>(&mut tcp, proxy_protocol).await?;
+        }
+    })
+}
+
+// `dst` with the https scheme instead of http
+fn upgraded(dst: Uri) -> Result<Uri, hyper::http::uri::InvalidUriParts> {
+    let mut parts = dst.into_parts();
+    parts.scheme = Some(hyper::http::uri::Scheme::HTTPS);
+    Uri::from_parts(parts)
+}
+
+// dial another connection to `dst`, once the inner connector is ready
+async fn redial<T>(http: &mut T, dst: Uri) -> Result<T::Response, BoxError>
+where
+    T: Service<Uri>,
+    T::Error: Into<BoxError>,
+{
+    std::future::poll_fn(|cx| http.poll_ready(cx)).await.map_err(Into::into)?;
+    http.call(dst).await.map_err(Into::into)
+}
+
+// the port of `dst`, or the default one of its scheme
+pub(crate) fn default_port(dst: &Uri) -> u16 {
+    dst.port_u16().unwrap_or(match dst.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    })
+}
+
+// the name to verify and send in SNI for a host. SNI has no room for an
+// absolute name, and the TLS backend won't verify one, so the trailing dot
+// goes; it's still dialed with it. only the one: a name with more is no
+// valid name, and is left for the backend to reject
+fn tls_domain(host: &str) -> &str {
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    host.strip_suffix('.').unwrap_or(host)
+}
+
+async fn handshake<S>(
+    tls: &TlsConnector,
+    host: &str,
+    sock: S,
+    timeout: Option<Duration>,
+) -> Result<TlsStream<S>, BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = tls.connect(host, sock);
+    let tls = match timeout {
+        Some(dur) => tokio::time::timeout(dur, handshake)
+            .await
+            .map_err(|_| Error::HandshakeTimedOut)?,
+        None => handshake.await,
+    };
+    Ok(tls.map_err(Error::Tls)?)
+}
+
+async fn send_proxy_header<S, A>(
+    sock: &mut S,
+    version: Option<ProxyProtocol>,
+) -> Result<(), BoxError>
+where
+    S: AsyncWrite + Unpin,
+    A: TcpOf<S>,
+{
+    if let Some(version) = version {
+        let header = version.header(A::tcp(sock));
+        sock.write_all(&header).await?;
+    }
+    Ok(())
+}
+
+// send a hello to `sni` over a throwaway connection
+async fn send_decoy<F, S, A>(connecting: F, sni: &str, proxy_protocol: Option<ProxyProtocol>)
+where
+    F: Future<Output = Result<S, BoxError>>,
+    S: AsyncWrite + Unpin,
+    A: TcpOf<S>,
+{
+    let mut sock = match connecting.await {
+        Ok(sock) => sock,
+        Err(_) => return,
+    };
+    if send_proxy_header::<_, A>(&mut sock, proxy_protocol).await.is_ok() {
+        let _ = sock.write_all(&decoy(sni)).await;
+        let _ = sock.flush().await;
     }
 }
 
 fn err<T>(e: BoxError) -> HttpsConnecting<T> {
-    HttpsConnecting(Box::pin(async { Err(e) }))
+    HttpsConnecting {
+        fut: Box::pin(async { Err(e) }),
+        phase: PhaseCell::default(),
+    }
 }
 
 type BoxedFut<T> = Pin<Box<dyn Future<Output = Result<MaybeHttpsStream<T>, BoxError>> + Send>>;
 
 /// A Future representing work to connect to a URL, and a TLS handshake.
-pub struct HttpsConnecting<T>(BoxedFut<T>);
+pub struct HttpsConnecting<T> {
+    fut: BoxedFut<T>,
+    phase: PhaseCell,
+}
+
+impl<T> HttpsConnecting<T> {
+    /// Which phase of the connection this future is in, e.g. to show the
+    /// progress of a slow one.
+    ///
+    /// It's updated as the future is polled, so it tells where the last
+    /// poll left off. A failed future stays in the phase it failed in.
+    pub fn phase(&self) -> ConnectPhase {
+        self.phase.get()
+    }
+}
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Future for HttpsConnecting<T> {
     type Output = Result<MaybeHttpsStream<T>, BoxError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        Pin::new(&mut self.0).poll(cx)
+        Pin::new(&mut self.fut).poll(cx)
     }
 }
 
@@ -178,15 +898,771 @@ impl<T> fmt::Debug for HttpsConnecting<T> {
     }
 }
 
+/// A phase of an `HttpsConnecting` future, in the order they come.
+///
+/// A connector retrying a failed handshake goes back to `Connecting` for
+/// the next connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectPhase {
+    /// Dialing the connection, along with a PROXY header and a decoy.
+    Connecting,
+    /// Writing the fragments of the ClientHello; skipped if the detour
+    /// doesn't fragment this connection.
+    Fragmenting,
+    /// Waiting for the rest of the TLS handshake.
+    Handshaking,
+    /// The connection is ready.
+    Done,
+}
+
+// the phase of a connecting future, shared with the detour it makes
+#[derive(Clone, Default)]
+pub(crate) struct PhaseCell(Arc<AtomicU8>);
+
+impl PhaseCell {
+    pub(crate) fn set(&self, phase: ConnectPhase) {
+        self.0.store(phase as u8, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> ConnectPhase {
+        match self.0.load(Ordering::Relaxed) {
+            0 => ConnectPhase::Connecting,
+            1 => ConnectPhase::Fragmenting,
+            2 => ConnectPhase::Handshaking,
+            _ => ConnectPhase::Done,
+        }
+    }
+}
+
 // ===== Custom Errors =====
 
+/// An error from connecting, which the boxed error of `HttpsConnector` can
+/// be downcast to.
 #[derive(Debug)]
-struct ForceHttpsButUriNotHttps;
+#[non_exhaustive]
+pub enum Error {
+    /// The URI has no host, or an empty one.
+    MissingHost,
+    /// The URI isn't `https`, with `https_only`, or neither `https` nor
+    /// `http`, with `force_tls`.
+    ForceHttpsButUriNotHttps,
+    /// The TLS handshake didn't finish within the handshake timeout.
+    HandshakeTimedOut,
+    /// The TLS handshake failed, e.g. because the certificate of the server
+    /// couldn't be verified.
+    Tls(native_tls::Error),
+    /// Every attempt at the TLS handshake failed, when retrying; the error of
+    /// each one is in its `Attempt`, the last one being the source.
+    AttemptsFailed(Vec<Attempt>),
+}
 
-impl fmt::Display for ForceHttpsButUriNotHttps {
+/// One attempt at the TLS handshake of a connection.
+///
+/// A connector retrying failed handshakes keeps one for each, in order; see
+/// `Detour::attempts` and `Error::AttemptsFailed`.
+#[derive(Debug)]
+pub struct Attempt {
+    /// The strategy picked to split the ClientHello, or `None` if it was
+    /// sent whole, with the detour turned off or on the unfragmented retry.
+    pub strategy: Option<SplitStrategy>,
+    /// Why the handshake failed, or `None` if it got through.
+    pub error: Option<BoxError>,
+    /// How long the handshake took to get through or to fail.
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("https required but URI was not https")
+        match self {
+            Error::MissingHost => f.write_str("URI has no host"),
+            Error::ForceHttpsButUriNotHttps => f.write_str("https required but URI was not https"),
+            Error::HandshakeTimedOut => f.write_str("tls handshake timed out"),
+            Error::Tls(e) => write!(f, "tls handshake failed: {}", e),
+            Error::AttemptsFailed(attempts) => {
+                write!(f, "all {} tls handshake attempts failed", attempts.len())
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Tls(e) => Some(e),
+            Error::AttemptsFailed(attempts) => {
+                let last = attempts.last()?.error.as_ref()?;
+                Some(&**last)
+            },
+            _ => None,
+        }
     }
 }
 
-impl std::error::Error for ForceHttpsButUriNotHttps {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{tls_connector, tls_server as server};
+    use tokio::net::TcpListener;
+
+    // A builder of connectors trusting the test certificate.
+    fn builder() -> HttpsConnectorBuilder {
+        let mut builder = HttpsConnector::builder();
+        builder.tls(tls_connector());
+        builder
+    }
+
+    fn localhost(port: u16) -> Uri {
+        format!("https://localhost:{}/", port).parse().unwrap()
+    }
+
+    // A connector over in-memory pipes, a type of connection this crate
+    // can't implement `AsTcpStream` for.
+    #[derive(Clone)]
+    struct Pipes;
+
+    impl Service<Uri> for Pipes {
+        type Response = tokio::io::DuplexStream;
+        type Error = std::io::Error;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Uri) -> Self::Future {
+            std::future::ready(Ok(tokio::io::duplex(64).0))
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_without_tcp_stream() {
+        let mut https = HttpsConnector::new_with_connector(Pipes);
+        let conn = https.call("http://example.com/".parse().unwrap()).await.unwrap();
+        assert!(matches!(conn, MaybeHttpsStream::Http(_)));
+    }
+
+    #[tokio::test]
+    async fn nodelay() {
+        let port = server(0).await;
+
+        // On by default, and off when asked
+        for (builder, enable) in [(builder(), true), (builder().nodelay(false).clone(), false)] {
+            let mut https = builder.build().unwrap();
+            let conn = https.call(localhost(port)).await.unwrap();
+            assert_eq!(conn.as_tcp_stream().unwrap().nodelay().unwrap(), enable);
+        }
+    }
+
+    #[tokio::test]
+    async fn separate_segments_sets_nodelay() {
+        let port = server(0).await;
+        for enable in [false, true] {
+            let mut detour = DetourBuilder::new();
+            detour.separate_segments(enable);
+            let mut https = builder().nodelay(false).detour(detour).build().unwrap();
+            let conn = https.call(localhost(port)).await.unwrap();
+            assert_eq!(conn.as_tcp_stream().unwrap().nodelay().unwrap(), enable);
+        }
+    }
+
+    // A server accepting every connection, and never answering the
+    // ClientHello.
+    async fn silent_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((tcp, _)) = listener.accept().await {
+                held.push(tcp);
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let port = silent_server().await;
+
+        let mut https = builder()
+            .handshake_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let e = https.call(localhost(port)).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::HandshakeTimedOut)));
+    }
+
+    // The detour under a connection, with the attempts at its handshake.
+    fn attempts(conn: &MaybeHttpsStream<Detour<TcpStream>>) -> &[Attempt] {
+        match conn {
+            MaybeHttpsStream::Https(tls) => tls.get_ref().get_ref().get_ref().attempts(),
+            MaybeHttpsStream::Http(_) => panic!("not a TLS connection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_after_failed_handshake() {
+        let port = server(1).await;
+        let mut detour = DetourBuilder::new();
+        // The test server isn't on 443.
+        detour.retry(1).only_ports(Vec::new());
+        let mut https = builder().detour(detour).build().unwrap();
+
+        let conn = https.call(localhost(port)).await.unwrap();
+        let attempts = attempts(&conn);
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[0].error.is_some());
+        assert!(attempts[1].error.is_none());
+        // the retry is split at a random place instead
+        assert_eq!(attempts[0].strategy, Some(SplitStrategy::SniMidpoint));
+        assert_eq!(attempts[1].strategy, Some(SplitStrategy::Random));
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let port = server(2).await;
+        let mut detour = DetourBuilder::new();
+        detour.retry(1);
+        let mut https = builder().detour(detour).build().unwrap();
+
+        let e = https.call(localhost(port)).await.unwrap_err();
+        match e.downcast_ref() {
+            Some(Error::AttemptsFailed(attempts)) => {
+                assert_eq!(attempts.len(), 2);
+                assert!(attempts.iter().all(|attempt| attempt.error.is_some()));
+            },
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    // A connector dialing loopback as soon as it's called, and only once
+    // made ready for it, as Service asks; a clone isn't ready yet.
+    #[derive(Default)]
+    struct Eager {
+        ready: bool,
+        dials: Arc<AtomicU8>,
+    }
+
+    impl Clone for Eager {
+        fn clone(&self) -> Self {
+            Eager { ready: false, dials: self.dials.clone() }
+        }
+    }
+
+    impl Service<Uri> for Eager {
+        type Response = TcpStream;
+        type Error = std::io::Error;
+        type Future = Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.ready = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, dst: Uri) -> Self::Future {
+            assert!(std::mem::take(&mut self.ready), "called without poll_ready");
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            let addr = SocketAddr::from(([127, 0, 0, 1], dst.port_u16().unwrap()));
+            Box::pin(TcpStream::connect(addr))
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_dialed_when_due() {
+        let port = server(1).await;
+        let mut detour = DetourBuilder::new();
+        detour.retry(3).only_ports(Vec::new());
+        let eager = Eager::default();
+        let dials = eager.dials.clone();
+        let mut https = HttpsConnector::from((eager, tls_connector()));
+        https.set_detour(&detour);
+
+        // Ready once for the call, and again for the one retry it took
+        std::future::poll_fn(|cx| https.poll_ready(cx)).await.unwrap();
+        let conn = https.call(localhost(port)).await.unwrap();
+        assert_eq!(attempts(&conn).len(), 2);
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn decoy_dialed_when_ready() {
+        let port = server(0).await;
+        let mut detour = DetourBuilder::new();
+        detour.decoy_hello("decoy.example".to_owned()).only_ports(Vec::new());
+        let eager = Eager::default();
+        let dials = eager.dials.clone();
+        let mut https = HttpsConnector::from((eager, tls_connector()));
+        https.set_detour(&detour);
+
+        // Ready once for the call, and again for the decoy
+        std::future::poll_fn(|cx| https.poll_ready(cx)).await.unwrap();
+        https.call(localhost(port)).await.unwrap();
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn attempts_timed() {
+        let port = silent_server().await;
+        let mut detour = DetourBuilder::new();
+        detour.retry(1);
+        let mut https = builder()
+            .handshake_timeout(Duration::from_millis(50))
+            .detour(detour)
+            .build()
+            .unwrap();
+
+        // Each attempt timed out on its own, the last one being the source
+        let e = https.call(localhost(port)).await.unwrap_err();
+        let e = e.downcast_ref::<Error>().unwrap();
+        match e {
+            Error::AttemptsFailed(attempts) => {
+                assert_eq!(attempts.len(), 2);
+                for attempt in attempts {
+                    assert!(attempt.elapsed >= Duration::from_millis(50));
+                    let error = attempt.error.as_ref().unwrap();
+                    assert!(matches!(error.downcast_ref(), Some(Error::HandshakeTimedOut)));
+                }
+            },
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let source = std::error::Error::source(e).unwrap();
+        assert!(matches!(source.downcast_ref(), Some(Error::HandshakeTimedOut)));
+        assert_eq!(e.to_string(), "all 2 tls handshake attempts failed");
+    }
+
+    #[tokio::test]
+    async fn no_retry() {
+        let port = server(1).await;
+        let mut https = builder().build().unwrap();
+        let e = https.call(localhost(port)).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Tls(_))));
+    }
+
+    #[tokio::test]
+    async fn plaintext_refused() {
+        let port = server(0).await;
+        let http: Uri = format!("http://localhost:{}/", port).parse().unwrap();
+
+        // https_only turns the URI away before dialing anything
+        let mut https = builder().https_only(true).build().unwrap();
+        let e = https.call(http.clone()).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::ForceHttpsButUriNotHttps)));
+
+        // force_tls dials it over TLS instead, where it would go in plaintext
+        // without either
+        for (force_tls, tls) in [(true, true), (false, false)] {
+            let mut https = builder().force_tls(force_tls).build().unwrap();
+            let conn = https.call(http.clone()).await.unwrap();
+            assert_eq!(conn.is_https(), tls);
+        }
+
+        // and fails where TLS can't be used, rather than going on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain: Uri = format!("http://localhost:{}/", listener.local_addr().unwrap().port())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let _ = tcp.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+        let mut https = builder().force_tls(true).build().unwrap();
+        let e = https.call(plain).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Tls(_))));
+
+        // nor does it let any other scheme through
+        let ws = "ws://localhost/".parse().unwrap();
+        let e = builder().force_tls(true).build().unwrap().call(ws).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::ForceHttpsButUriNotHttps)));
+    }
+
+    #[tokio::test]
+    async fn proxy_header_first() {
+        use tokio::io::AsyncReadExt;
+
+        // Reads the first line the client sends and a byte after it, and
+        // hangs up
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let read = tokio::spawn(async move {
+            let (mut tcp, peer) = listener.accept().await.unwrap();
+            let mut read = Vec::new();
+            while !read.ends_with(b"\r\n") {
+                read.push(tcp.read_u8().await.unwrap());
+            }
+            read.push(tcp.read_u8().await.unwrap());
+            (read, peer.port())
+        });
+
+        let mut https = builder().proxy_protocol(ProxyProtocol::V1).build().unwrap();
+        assert!(https.call(localhost(port)).await.is_err());
+
+        // The header goes ahead of the ClientHello
+        let (read, src) = read.await.unwrap();
+        let header = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n", src, port);
+        assert_eq!(read, [header.as_bytes(), &[0x16]].concat());
+    }
+
+    #[tokio::test]
+    async fn decoy_first() {
+        use crate::hello::{record_len, ClientHello};
+        use crate::mock::tls_acceptor;
+        use tokio::io::AsyncReadExt;
+
+        // Reads the record sent on the first connection, and completes the
+        // handshake of the second one
+        let acceptor = tokio_native_tls::TlsAcceptor::from(tls_acceptor().build().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let mut record = vec![0; 5];
+            tcp.read_exact(&mut record).await.unwrap();
+            record.resize(record_len(&record), 0);
+            tcp.read_exact(&mut record[5..]).await.unwrap();
+
+            let (tcp, _) = listener.accept().await.unwrap();
+            let tls = acceptor.accept(tcp).await.unwrap();
+            (record, tls)
+        });
+
+        let mut detour = DetourBuilder::new();
+        detour.decoy_hello("decoy.example".to_owned()).only_ports(Vec::new());
+        let mut https = builder().detour(detour).build().unwrap();
+        https.call(localhost(port)).await.unwrap();
+
+        // The decoy is a whole hello of its own, to the other host
+        let (record, _tls) = server.await.unwrap();
+        let decoy = ClientHello::parse(&record).unwrap();
+        assert_eq!(decoy.sni(), Some("decoy.example"));
+    }
+
+    #[tokio::test]
+    async fn connect_to() {
+        let port = server(0).await;
+        let mut map = HashMap::new();
+        map.insert("localhost:443".to_owned(), format!("127.0.0.1:{}", port));
+        let mut https = builder().connect_to(map).build().unwrap();
+
+        // The certificate is still checked against the host of the URL
+        let conn = https.call("https://localhost/".parse().unwrap()).await.unwrap();
+        let peer = conn.as_tcp_stream().unwrap().peer_addr().unwrap();
+        assert_eq!(peer, ([127, 0, 0, 1], port).into());
+        assert!(matches!(conn, MaybeHttpsStream::Https(_)));
+
+        // Another port isn't in the map
+        assert!(https.call("https://localhost:1/".parse().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let port = server(0).await;
+        for keepalive in [None, Some(Duration::from_secs(60))] {
+            let mut https = builder().keepalive(keepalive).build().unwrap();
+            let conn = https.call(localhost(port)).await.unwrap();
+            let sock = socket2::SockRef::from(conn.as_tcp_stream().unwrap());
+            assert_eq!(sock.keepalive().unwrap(), keepalive.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn unfragmented_retry() {
+        let port = server(2).await;
+        let mut detour = DetourBuilder::new();
+        detour.retry(1).unfragmented_retry(true).only_ports(Vec::new());
+        let mut https = builder().detour(detour).build().unwrap();
+
+        // The last attempt, after the retry, sends the hello whole
+        let conn = https.call(localhost(port)).await.unwrap();
+        let attempts = attempts(&conn);
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[1].error.is_some());
+        assert!(attempts[2].error.is_none());
+        assert_eq!(attempts[1].strategy, Some(SplitStrategy::Random));
+        assert_eq!(attempts[2].strategy, None);
+    }
+
+    // Linux answers on every address of 127.0.0.0/8, not only 127.0.0.1
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn local_address() {
+        let port = server(0).await;
+        let local = IpAddr::from([127, 0, 0, 2]);
+        let mut https = builder().local_address(local).build().unwrap();
+        let conn = https.call(localhost(port)).await.unwrap();
+        assert_eq!(conn.as_tcp_stream().unwrap().local_addr().unwrap().ip(), local);
+    }
+
+    #[tokio::test]
+    async fn with_detour_config() {
+        let port = server(0).await;
+        let before = builder().build().unwrap();
+        let config = DetourBuilder::new().only_ports(Vec::new()).build_shared();
+        let mut https = before.clone().with_detour_config(config.clone());
+
+        // A clone made before keeps its own detour
+        assert!(Arc::ptr_eq(&https.detour_handle().load(), &config));
+        assert!(!Arc::ptr_eq(&before.detour_handle().load(), &config));
+        https.set_detour(&DetourBuilder::new());
+        assert!(!Arc::ptr_eq(&before.detour_handle().load(), &https.detour_handle().load()));
+
+        // and the TLS options are kept
+        https = https.with_detour_config(config);
+        let conn = https.call(localhost(port)).await.unwrap();
+        assert_eq!(attempts(&conn)[0].strategy, Some(SplitStrategy::SniMidpoint));
+    }
+
+    // A resolver answering 127.0.0.1 for every name, keeping the names it's
+    // asked for.
+    #[derive(Clone, Default)]
+    struct Loopback(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Service<Name> for Loopback {
+        type Response = std::vec::IntoIter<SocketAddr>;
+        type Error = std::io::Error;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, name: Name) -> Self::Future {
+            self.0.lock().unwrap().push(name.as_str().to_owned());
+            std::future::ready(Ok(vec![SocketAddr::from(([127, 0, 0, 1], 0))].into_iter()))
+        }
+    }
+
+    #[tokio::test]
+    async fn build_with_resolver() {
+        let port = server(0).await;
+        let resolver = Loopback::default();
+        let mut https = builder().build_with_resolver(resolver.clone()).unwrap();
+        let conn = https.call(localhost(port)).await.unwrap();
+
+        // The port is the URL's, whatever the resolver answers
+        let peer = conn.as_tcp_stream().unwrap().peer_addr().unwrap();
+        assert_eq!(peer, ([127, 0, 0, 1], port).into());
+        assert_eq!(*resolver.0.lock().unwrap(), ["localhost"]);
+    }
+
+    #[tokio::test]
+    async fn phase() {
+        let silent = silent_server().await;
+
+        let mut https = builder().build().unwrap();
+        let mut connecting = https.call(localhost(silent));
+        assert_eq!(connecting.phase(), ConnectPhase::Connecting);
+        let timeout = tokio::time::timeout(Duration::from_millis(100), &mut connecting).await;
+        assert!(timeout.is_err());
+        assert_eq!(connecting.phase(), ConnectPhase::Handshaking);
+
+        let port = server(0).await;
+        let mut connecting = https.call(localhost(port));
+        (&mut connecting).await.unwrap();
+        assert_eq!(connecting.phase(), ConnectPhase::Done);
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_handshakes() {
+        let (silent, port) = (silent_server().await, server(0).await);
+        let wait = Duration::from_millis(100);
+        let mut https = builder().max_concurrent_handshakes(1).build().unwrap();
+
+        // The first connection holds the only turn while it's handshaking
+        let mut first = https.call(localhost(silent));
+        assert!(tokio::time::timeout(wait, &mut first).await.is_err());
+        assert_eq!(first.phase(), ConnectPhase::Handshaking);
+
+        // so the next one, from a clone, isn't even dialed
+        let mut next = https.clone().call(localhost(port));
+        assert!(tokio::time::timeout(wait, &mut next).await.is_err());
+        assert_eq!(next.phase(), ConnectPhase::Connecting);
+
+        // until the first one gives it up
+        drop(first);
+        next.await.unwrap();
+    }
+
+    // A server reading the records of a hello, and hanging up; it returns
+    // the payload size of each record, and the whole payload.
+    async fn hello_reader() -> (u16, tokio::task::JoinHandle<(Vec<usize>, Vec<u8>)>) {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let hello_len = |p: &[u8]| 4 + u32::from_be_bytes([0, p[1], p[2], p[3]]) as usize;
+            let (mut sizes, mut payload) = (Vec::new(), Vec::new());
+            while payload.len() < 4 || payload.len() < hello_len(&payload) {
+                let mut header = [0; 5];
+                tcp.read_exact(&mut header).await.unwrap();
+                let mut record = vec![0; u16::from_be_bytes([header[3], header[4]]) as usize];
+                tcp.read_exact(&mut record).await.unwrap();
+                sizes.push(record.len());
+                payload.extend_from_slice(&record);
+            }
+            (sizes, payload)
+        });
+        (port, server)
+    }
+
+    // Check that a hello read by `hello_reader` has no server_name, and
+    // was cut at the middle instead.
+    fn assert_anonymous((sizes, payload): (Vec<usize>, Vec<u8>)) {
+        use crate::hello::ClientHello;
+
+        let len = (payload.len() as u16).to_be_bytes();
+        let record = [&[0x16, 0x03, 0x01, len[0], len[1]][..], &payload].concat();
+        let hello = ClientHello::parse(&record).unwrap();
+        assert_eq!(hello.sni(), None);
+        assert!(!hello.extensions().contains(&0));
+        assert_eq!(sizes, [payload.len() / 2, payload.len() - payload.len() / 2]);
+    }
+
+    #[tokio::test]
+    async fn ip_literal() {
+        let (port, server) = hello_reader().await;
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut https = builder().detour(detour).build().unwrap();
+        let uri = format!("https://127.0.0.1:{}/", port).parse().unwrap();
+        assert!(https.call(uri).await.is_err());
+        assert_anonymous(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn ipv6_literal() {
+        // Dialed at the loopback instead, as there may be no IPv6 here
+        let (port, server) = hello_reader().await;
+        let mut map = HashMap::new();
+        map.insert("[2001:db8::1]:443".to_owned(), format!("127.0.0.1:{}", port));
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut https = builder().detour(detour).connect_to(map).build().unwrap();
+        assert!(https.call("https://[2001:db8::1]/".parse().unwrap()).await.is_err());
+
+        // The address goes to the TLS backend without its brackets, which
+        // sends no SNI for it
+        assert_anonymous(server.await.unwrap());
+        assert_eq!(tls_domain("[2001:db8::1]"), "2001:db8::1");
+    }
+
+    #[tokio::test]
+    async fn connect_over() {
+        use crate::mock::tls_acceptor;
+        use tokio::io::AsyncReadExt;
+
+        // A stream connected already, with a TLS server at the other end
+        let (near, far) = tokio::io::duplex(1 << 16);
+        let acceptor = tokio_native_tls::TlsAcceptor::from(tls_acceptor().build().unwrap());
+        tokio::spawn(async move {
+            let mut tls = acceptor.accept(far).await.unwrap();
+            tls.write_all(b"ok").await.unwrap();
+            let _ = tls.read(&mut [0]).await;
+        });
+
+        let https = builder().build().unwrap();
+        let mut tls = https.connect_over(near, "localhost").await.unwrap();
+        let mut ok = [0; 2];
+        tls.read_exact(&mut ok).await.unwrap();
+        assert_eq!(&ok, b"ok");
+
+        // The hello went through the detour
+        assert_eq!(tls.get_ref().get_ref().get_ref().fragment_sizes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_host() {
+        let mut https = builder().build().unwrap();
+        for uri in ["/path", "https://:443/path"] {
+            let e = https.call(uri.parse().unwrap()).await.unwrap_err();
+            assert!(matches!(e.downcast_ref(), Some(Error::MissingHost)), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn scheme_routing() {
+        let (tls, plain) = (server(0).await, silent_server().await);
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut https = builder().detour(detour).build().unwrap();
+
+        // Plaintext for http, and the detour left out of it
+        let uri = format!("http://localhost:{}/", plain).parse().unwrap();
+        match https.call(uri).await.unwrap() {
+            MaybeHttpsStream::Http(tcp) => {
+                assert_eq!(tcp.decision(), Some(crate::DetourDecision::SkippedDisabled))
+            },
+            MaybeHttpsStream::Https(_) => panic!("TLS over http"),
+        }
+
+        // TLS for https, through the detour
+        let conn = https.call(localhost(tls)).await.unwrap();
+        assert!(matches!(conn, MaybeHttpsStream::Https(_)));
+        assert_eq!(attempts(&conn)[0].strategy, Some(SplitStrategy::SniMidpoint));
+    }
+
+    #[test]
+    fn with_detour() {
+        let mut detour = DetourBuilder::new();
+        detour.strategy(SplitStrategy::FixedOffset(3)).retry(2);
+        let https = HttpsConnector::with_detour(&detour);
+
+        // The options are copied; changing the builder after doesn't reach it
+        detour.retry(0);
+        let config = https.detour_handle().load();
+        assert_eq!(config.strategy, SplitStrategy::FixedOffset(3));
+        assert_eq!(config.retries(), 2);
+    }
+
+    #[tokio::test]
+    async fn tls_error() {
+        let port = server(0).await;
+
+        // The test certificate isn't trusted without its root
+        let mut https = HttpsConnector::builder().build().unwrap();
+        let e = https.call(localhost(port)).await.unwrap_err();
+        match e.downcast_ref() {
+            Some(e @ Error::Tls(_)) => {
+                let source = std::error::Error::source(e).unwrap();
+                assert!(source.downcast_ref::<native_tls::Error>().is_some());
+                assert!(e.to_string().starts_with("tls handshake failed: "));
+            },
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_domain_is_host() {
+        let port = server(0).await;
+
+        // The certificate is for localhost alone, so any userinfo or port
+        // sent along would fail its verification
+        let uri = format!("https://user@localhost:{}/", port).parse().unwrap();
+        let mut https = builder().build().unwrap();
+        assert!(https.call(uri).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn absolute_hostname() {
+        let port = server(0).await;
+        let resolver = Loopback::default();
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut https = builder().detour(detour).build_with_resolver(resolver.clone()).unwrap();
+
+        // Dialed with its trailing dot, but verified and split without it
+        let uri = format!("https://localhost.:{}/", port).parse().unwrap();
+        let conn = https.call(uri).await.unwrap();
+        assert_eq!(*resolver.0.lock().unwrap(), ["localhost."]);
+        let detour = match &conn {
+            MaybeHttpsStream::Https(tls) => tls.get_ref().get_ref().get_ref(),
+            MaybeHttpsStream::Http(_) => panic!("not a TLS connection"),
+        };
+        let info = detour.fragment_info().unwrap();
+        assert_eq!(info.sni.as_deref(), Some("localhost"));
+        assert_eq!(info.fragment_count, 2);
+        assert_eq!(tls_domain("localhost."), "localhost");
+        assert_eq!(tls_domain("example.com.."), "example.com.");
+        assert_eq!(tls_domain("[::1]"), "::1");
+    }
+}
@@ -0,0 +1,1150 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::io::AsyncWrite;
+
+use crate::detour::{Detour, DetourDecision, FragmentInfo, DEFAULT_MAX_FRAGMENTS};
+use crate::policy::{self, HostRule, PolicyMode};
+use crate::strategy::{FallbackStrategy, NetworkHint, Rng, SplitStrategy};
+
+// shorter hellos are left whole by default, see DetourBuilder::min_record_len
+const DEFAULT_MIN_RECORD_LEN: usize = 200;
+
+/// options on how a [`Detour`] fragments the ClientHello
+///
+/// a config is immutable once built; every detour made by a connector
+/// shares the same one behind an `Arc`. use [`DetourBuilder`] to make one,
+/// or turn one into a builder with `From` to change it.
+#[derive(Debug, Clone)]
+pub struct DetourConfig {
+    pub(crate) strategy: SplitStrategy,
+    pub(crate) fallback: FallbackStrategy,
+    pub(crate) max_fragments: usize,
+    // of the payload's length, up to which every cut is moved either way
+    pub(crate) split_jitter: f32,
+    // none for as many record headers as max_fragments takes
+    pub(crate) max_overhead: Option<usize>,
+    pub(crate) min_record_len: usize,
+    pub(crate) flush_between: bool,
+    pub(crate) nodelay: bool,
+    pub(crate) anti_coalesce: bool,
+    pub(crate) first_write_only: bool,
+    pub(crate) single_write: bool,
+    pub(crate) strict_splits: bool,
+    pub(crate) retries: usize,
+    // tried in order, one per attempt
+    pub(crate) strategies: Vec<SplitStrategy>,
+    // one more attempt after the others, with the hello sent whole
+    pub(crate) unfragmented_retry: bool,
+    pub(crate) deterministic: bool,
+    // where random cuts and jitter come from
+    pub(crate) rng: Rng,
+    // what SplitStrategy::ByNetwork picks by
+    pub(crate) network: NetworkHint,
+    // empty for every host
+    pub(crate) hosts: Vec<HostRule>,
+    // empty for every port
+    pub(crate) ports: Vec<u16>,
+    pub(crate) policy_mode: PolicyMode,
+    // strategies for some hosts instead of the default one; first match wins
+    pub(crate) host_strategies: Vec<(HostRule, SplitStrategy)>,
+    pub(crate) on_fragment: Option<OnFragment>,
+    pub(crate) on_decision: Option<OnDecision>,
+    pub(crate) tap: Option<Tap>,
+    pub(crate) decoy: Option<String>,
+}
+
+// a callback told of every fragmented hello
+#[derive(Clone)]
+pub(crate) struct OnFragment(Arc<dyn Fn(&FragmentInfo) + Send + Sync>);
+
+impl OnFragment {
+    pub(crate) fn call(&self, info: &FragmentInfo) {
+        (self.0)(info)
+    }
+}
+
+impl fmt::Debug for OnFragment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("OnFragment")
+    }
+}
+
+// a callback told of what was done with every hello, fragmented or not
+#[derive(Clone)]
+pub(crate) struct OnDecision(Arc<dyn Fn(DetourDecision) + Send + Sync>);
+
+impl OnDecision {
+    pub(crate) fn call(&self, decision: DetourDecision) {
+        (self.0)(decision)
+    }
+}
+
+impl fmt::Debug for OnDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("OnDecision")
+    }
+}
+
+// a callback told of every byte written to the socket. every detour
+// sharing the config calls the same one, so it's behind a lock
+#[derive(Clone)]
+pub(crate) struct Tap(Arc<Mutex<TapFn>>);
+
+type TapFn = dyn FnMut(&[u8]) + Send;
+
+impl Tap {
+    pub(crate) fn call(&self, bytes: &[u8]) {
+        match self.0.lock() {
+            Ok(mut tap) => tap(bytes),
+            Err(poisoned) => (poisoned.into_inner())(bytes),
+        }
+    }
+}
+
+impl fmt::Debug for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Tap")
+    }
+}
+
+impl Default for DetourConfig {
+    fn default() -> Self {
+        Self {
+            strategy: SplitStrategy::default(),
+            fallback: FallbackStrategy::default(),
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
+            split_jitter: 0.0,
+            max_overhead: None,
+            min_record_len: DEFAULT_MIN_RECORD_LEN,
+            flush_between: false,
+            nodelay: false,
+            anti_coalesce: false,
+            first_write_only: true,
+            single_write: false,
+            strict_splits: false,
+            retries: 0,
+            strategies: Vec::new(),
+            unfragmented_retry: false,
+            deterministic: false,
+            rng: Rng::default(),
+            network: NetworkHint::default(),
+            hosts: Vec::new(),
+            ports: vec![443],
+            policy_mode: PolicyMode::default(),
+            host_strategies: Vec::new(),
+            on_fragment: None,
+            on_decision: None,
+            tap: None,
+            decoy: None,
+        }
+    }
+}
+
+impl DetourConfig {
+    /// read a config from a policy file.
+    ///
+    /// a policy has one option per line, with the same names as the methods
+    /// of [`DetourBuilder`]; blank lines and ones starting with `#` are
+    /// skipped:
+    ///
+    /// ```text
+    /// # where to cut, for every host not listed below
+    /// strategy sni-fraction 0.25
+    /// fallback passthrough
+    /// max-fragments 4
+    /// max-overhead-bytes 15
+    /// split-jitter 0.1
+    /// min-record-len 200
+    /// flush-between on
+    /// retry 2
+    /// unfragmented-retry on
+    /// # fragment only these; without any, every host is
+    /// enable-for *.example.com
+    /// enable-for example.org
+    /// # or, to fragment every host but these
+    /// # policy-mode denylist
+    /// # and only on these ports
+    /// only-ports 443 8443
+    /// # cut differently for some hosts
+    /// host-strategy example.org fixed-offset 3
+    /// ```
+    ///
+    /// strategies are `sni-midpoint`, `sni-fraction <f>`, `before-sni`,
+    /// `after-sni`, `fixed-offset <n>`, `from-end <n>`, `random`,
+    /// `every-byte` and `identity`; fallbacks are `midpoint`, `passthrough`
+    /// and `fixed <n>`; policy modes are `allowlist` and `denylist`. to use
+    /// it with a connector, store it through its [`DetourHandle`].
+    pub fn from_reader<R: io::Read>(reader: R) -> io::Result<Self> {
+        policy::parse(reader)
+    }
+
+    /// read a config from the environment, e.g. to try another strategy
+    /// without rebuilding.
+    ///
+    /// these are read, each one optional; the rest of the options keep
+    /// their defaults:
+    ///
+    /// - `DETOUR_STRATEGY`: a strategy as in a policy file, like
+    ///   `sni-midpoint` or `sni-fraction 0.25`
+    /// - `DETOUR_SPLIT`: a number, short for `DETOUR_STRATEGY=fixed-offset
+    ///   <n>`, and taking over it
+    /// - `DETOUR_FALLBACK`: a fallback as in a policy file
+    /// - `DETOUR_MAX_FRAGMENTS`: see [`DetourBuilder::max_fragments`]
+    /// - `DETOUR_RETRY`: see [`DetourBuilder::retry`]
+    ///
+    /// an empty variable is as good as unset; one which can't be parsed is
+    /// an error of kind `InvalidData`.
+    pub fn from_env() -> io::Result<Self> {
+        policy::from_env()
+    }
+
+    // whether to fragment the hellos sent to `port`
+    pub(crate) fn applies_to_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.contains(&port)
+    }
+
+    // whether to fragment the hello carrying `sni`
+    pub(crate) fn applies_to(&self, sni: Option<&str>) -> bool {
+        if self.hosts.is_empty() {
+            return true;
+        }
+        let listed = sni.is_some_and(|sni| self.hosts.iter().any(|rule| rule.matches(sni)));
+        match self.policy_mode {
+            PolicyMode::Allowlist => listed,
+            PolicyMode::Denylist => !listed,
+        }
+    }
+
+    // every retry gets a random split, so it won't fail the same way again,
+    // unless there's a sequence of strategies to go through first
+    pub(crate) fn strategy_for(&self, attempt: usize, sni: Option<&str>) -> SplitStrategy {
+        let strategy = match self.strategies.get(attempt) {
+            Some(strategy) => strategy,
+            None => sni
+                .and_then(|sni| self.host_strategies.iter().find(|(rule, _)| rule.matches(sni)))
+                .map_or(&self.strategy, |(_, strategy)| strategy),
+        };
+        let strategy = strategy.for_network(&self.network);
+
+        match strategy {
+            SplitStrategy::Random if self.deterministic => SplitStrategy::SniMidpoint,
+            _ if self.deterministic => strategy.clone(),
+            _ if attempt > 0 && attempt >= self.strategies.len() => SplitStrategy::Random,
+            strategy => strategy.clone(),
+        }
+    }
+
+    // a sequence of strategies gets a retry for each of them, and the
+    // unfragmented retry comes after all of them
+    pub(crate) fn retries(&self) -> usize {
+        let retries = self.retries.max(self.strategies.len().saturating_sub(1));
+        retries + self.unfragmented_retry as usize
+    }
+
+    // how many records the hello may be cut into; each one after the first
+    // adds a header of its own
+    pub(crate) fn fragment_cap(&self) -> usize {
+        match self.max_overhead {
+            Some(bytes) => self.max_fragments.min(bytes / 5 + 1),
+            None => self.max_fragments,
+        }
+    }
+
+    // whether the hello of `attempt` is fragmented at all
+    pub(crate) fn fragments(&self, attempt: usize) -> bool {
+        !(self.unfragmented_retry && attempt == self.retries())
+    }
+}
+
+/// a handle to swap the config of a connector at runtime
+///
+/// clones of a handle share the same config. storing a new one affects the
+/// connections made afterwards; ones in the middle of fragmenting a
+/// ClientHello keep the config they started with.
+///
+/// this is also the way to fragment differently on each network, e.g. on
+/// cellular and on wifi, which only the application can tell apart: pair
+/// a strategy with each in [`SplitStrategy::ByNetwork`], and tell the
+/// handle of the current network whenever it changes. to change more than
+/// the strategy, build a whole config for each network up front, and store
+/// the one for the current network instead; storing is cheap, the configs
+/// being shared.
+///
+/// ```
+/// use detour::{DetourBuilder, HttpsConnector, NetworkHint, SplitStrategy};
+///
+/// let mut detour = DetourBuilder::new();
+/// detour.strategy(SplitStrategy::ByNetwork(vec![
+///     (NetworkHint::Cellular, SplitStrategy::EveryByte),
+///     (NetworkHint::Unknown, SplitStrategy::SniMidpoint),
+/// ]));
+///
+/// let https = HttpsConnector::with_detour(&detour);
+/// let handle = https.detour_handle();
+/// // on a change of the network
+/// handle.set_network(NetworkHint::Cellular);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DetourHandle {
+    config: Arc<Mutex<Arc<DetourConfig>>>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Default for DetourHandle {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl DetourHandle {
+    /// make a new handle holding `config`
+    pub fn new(config: Arc<DetourConfig>) -> Self {
+        Self {
+            config: Arc::new(Mutex::new(config)),
+            enabled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// turn fragmentation on or off for the connections made afterwards,
+    /// e.g. when switching to a network known not to need it.
+    ///
+    /// the connectors wrap every connection in a detour either way, but a
+    /// disabled one passes everything through, like
+    /// [`Detour::passthrough`]; the config is kept for when it's turned
+    /// back on. defaults to enabled.
+    pub fn set_enabled(&self, enable: bool) {
+        self.enabled.store(enable, Ordering::Relaxed);
+    }
+
+    /// whether fragmentation is turned on, see [`set_enabled`](Self::set_enabled)
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // a detour over `sock` with the current config, or one passing
+    // everything through if disabled
+    pub(crate) fn wrap<T: AsyncWrite>(&self, sock: T) -> Detour<T> {
+        if self.is_enabled() {
+            Detour::from_config(sock, self.load())
+        } else {
+            Detour::passthrough(sock)
+        }
+    }
+
+    // same as `wrap`, for a connection dialed to `port`
+    pub(crate) fn wrap_to<T: AsyncWrite>(&self, sock: T, port: u16) -> Detour<T> {
+        let config = self.load();
+        if self.is_enabled() && config.applies_to_port(port) {
+            Detour::from_config(sock, config)
+        } else {
+            Detour::passthrough(sock)
+        }
+    }
+
+    /// get the current config
+    pub fn load(&self) -> Arc<DetourConfig> {
+        // nothing can panic while storing, so even a poisoned lock holds
+        // a valid config
+        match self.config.lock() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// replace the current config
+    pub fn store(&self, config: Arc<DetourConfig>) {
+        match self.config.lock() {
+            Ok(mut current) => *current = config,
+            Err(poisoned) => *poisoned.into_inner() = config,
+        }
+    }
+
+    /// tell the connections made afterwards that they go out on `network`,
+    /// for [`SplitStrategy::ByNetwork`] to pick by.
+    ///
+    /// this stores a copy of the current config with the new hint, like
+    /// [`DetourBuilder::network`] would; the rest of it is kept as is.
+    pub fn set_network(&self, network: NetworkHint) {
+        let mut current = match self.config.lock() {
+            Ok(current) => current,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut config = DetourConfig::clone(&current);
+        config.network = network;
+        *current = Arc::new(config);
+    }
+}
+
+impl From<Arc<DetourConfig>> for DetourHandle {
+    fn from(config: Arc<DetourConfig>) -> Self {
+        Self::new(config)
+    }
+}
+
+/// a builder to configure how a [`Detour`] fragments the ClientHello
+///
+/// clone a builder to keep a base for tweaking into several configs. the
+/// callbacks, i.e. [`on_fragment`](Self::on_fragment),
+/// [`on_decision`](Self::on_decision), [`tap`](Self::tap) and
+/// [`SplitStrategy::Fn`], are behind an `Arc` and
+/// shared by the clones, state and all; set new ones for a clone to have
+/// its own.
+///
+/// every callback sees the ClientHello, but none can change it: the tls
+/// layer hashes the hello it wrote into the transcript, so a hello edited
+/// on the way, even by a single byte, fails the handshake. shape the hello
+/// through the tls backend instead; see [`Detour`] for more.
+#[derive(Debug, Clone, Default)]
+pub struct DetourBuilder {
+    config: DetourConfig,
+}
+
+// e.g. to tweak one read from a policy file
+impl From<DetourConfig> for DetourBuilder {
+    fn from(config: DetourConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl DetourBuilder {
+    /// make a new builder with the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set where to cut the ClientHello. defaults to
+    /// [`SplitStrategy::SniMidpoint`].
+    pub fn strategy(&mut self, strategy: SplitStrategy) -> &mut Self {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// set what to do when the strategy can't cut a ClientHello, e.g. one
+    /// without SNI. defaults to [`FallbackStrategy::Midpoint`].
+    pub fn fallback(&mut self, fallback: FallbackStrategy) -> &mut Self {
+        self.config.fallback = fallback;
+        self
+    }
+
+    /// cap the number of records the ClientHello is cut into.
+    ///
+    /// hundreds of records hurt throughput and may trip the server's limits.
+    /// if a strategy asks for more, its cuts are merged, keeping ones evenly
+    /// spread over the record. defaults to 16; 1 disables fragmentation,
+    /// passing the hello through as
+    /// [`DetourDecision::SkippedSingleFragment`](crate::DetourDecision::SkippedSingleFragment).
+    pub fn max_fragments(&mut self, n: usize) -> &mut Self {
+        self.config.max_fragments = n;
+        self
+    }
+
+    /// move every cut of the strategy by a random offset, up to `fraction`
+    /// of the record's payload either way.
+    ///
+    /// a strategy like [`SplitStrategy::SniMidpoint`] cuts every hello to
+    /// a host at the same place, which is a fingerprint of its own; `0.1`
+    /// moves each cut by up to a tenth of the payload. a cut is kept inside
+    /// the payload, so both fragments still carry some. with
+    /// [`deterministic`](Self::deterministic), the offset comes from the
+    /// hello's bytes instead, so the same hello is moved the same way; with
+    /// [`seed`](Self::seed), from the seeded sequence. the fraction is
+    /// clamped to `0.0..=1.0`; defaults to 0, for no jitter.
+    pub fn split_jitter(&mut self, fraction: f32) -> &mut Self {
+        self.config.split_jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// cap the bytes fragmenting adds to the ClientHello at `n`.
+    ///
+    /// each record after the first adds a header of 5 bytes, which some
+    /// servers count against the size of the handshake flight. this caps
+    /// the number of records like [`max_fragments`](Self::max_fragments),
+    /// to `n / 5 + 1`, and the lower of both applies; below 5, the hello
+    /// is passed through, as with `max_fragments(1)`. defaults to no cap
+    /// but that of `max_fragments`.
+    pub fn max_overhead_bytes(&mut self, n: usize) -> &mut Self {
+        self.config.max_overhead = Some(n);
+        self
+    }
+
+    /// leave ClientHello records shorter than `len` bytes, header included,
+    /// whole.
+    ///
+    /// a tiny hello, with no SNI and few extensions, has little to hide,
+    /// and its fragments are tiny enough to be coalesced back into one on
+    /// the way. defaults to 200, which keeps them out; every hello written
+    /// by a real tls stack to a hostname is longer than that. set it to 0
+    /// to fragment every hello.
+    pub fn min_record_len(&mut self, len: usize) -> &mut Self {
+        self.config.min_record_len = len;
+        self
+    }
+
+    /// flush the inner socket after each fragment is written.
+    ///
+    /// two tls records written back to back may be coalesced by the OS
+    /// into a single tcp segment, which some DPI boxes reassemble trivially.
+    /// flushing in between makes it more likely that each fragment lands in
+    /// its own segment, but this is best-effort: neither tokio nor the OS
+    /// guarantees segment boundaries. consider also enabling `TCP_NODELAY`
+    /// (e.g. `HttpConnector::set_nodelay`) on the underlying socket.
+    pub fn flush_between(&mut self, enable: bool) -> &mut Self {
+        self.config.flush_between = enable;
+        self
+    }
+
+    /// try harder to send each fragment in its own tcp segment.
+    ///
+    /// on top of [`flush_between`](Self::flush_between) and
+    /// [`anti_coalesce`](Self::anti_coalesce), the connector turns on
+    /// `TCP_NODELAY` for the raw socket it dials, found through
+    /// [`AsTcpStream`](crate::AsTcpStream) if it reaches it (see
+    /// `HttpsConnector::with_tcp_access`). it's still not a guarantee.
+    ///
+    /// going further, e.g. sending overlapping segments that carry
+    /// different bytes, needs a raw socket to craft them; that's out of
+    /// reach of a stream wrapper, so it isn't supported.
+    pub fn separate_segments(&mut self, enable: bool) -> &mut Self {
+        self.config.flush_between = enable;
+        self.config.nodelay = enable;
+        self.config.anti_coalesce = enable;
+        self
+    }
+
+    /// yield to the runtime after each fragment but the last one.
+    ///
+    /// a write returns once the data is queued in the kernel, not once it's
+    /// sent; if the next fragment is queued right away, the OS may still
+    /// coalesce both. yielding gives the first one a moment to leave alone,
+    /// at the cost of a round through the scheduler. this is best-effort,
+    /// and does nothing to a socket that buffers by itself.
+    pub fn anti_coalesce(&mut self, enable: bool) -> &mut Self {
+        self.config.anti_coalesce = enable;
+        self
+    }
+
+    /// write all the fragments to the socket at once, in a single write.
+    ///
+    /// the hello is still cut into records, but they're left to the OS to
+    /// pack into segments, most likely all into one. this saves the
+    /// syscalls of writing each fragment alone, for a DPI box which only
+    /// looks at whole records; [`flush_between`](Self::flush_between) and
+    /// [`anti_coalesce`](Self::anti_coalesce) have nothing to do then.
+    /// defaults to false.
+    pub fn single_write(&mut self, enable: bool) -> &mut Self {
+        self.config.single_write = enable;
+        self
+    }
+
+    /// fail the write of a ClientHello which the strategy would cut
+    /// outside of its payload, instead of dropping the bad cut.
+    ///
+    /// the built-in strategies never do; this is for catching the mistakes
+    /// of a [`SplitStrategy::Fn`] in tests. the write fails with
+    /// `InvalidInput`, wrapping [`ParseError::InvalidSplit`](crate::ParseError)
+    /// with the offset. defaults to false.
+    pub fn strict_splits(&mut self, enable: bool) -> &mut Self {
+        self.config.strict_splits = enable;
+        self
+    }
+
+    /// look for the ClientHello in the first write only.
+    ///
+    /// a tls client always starts with its hello, so by default, a first
+    /// write which isn't one means there's none to come; it and everything
+    /// after are passed through without a look. turn this off for a
+    /// protocol sending something else first, e.g. STARTTLS, so every write
+    /// is checked until a hello shows up. defaults to true.
+    pub fn first_write_only(&mut self, enable: bool) -> &mut Self {
+        self.config.first_write_only = enable;
+        self
+    }
+
+    /// retry a failed tls handshake up to `n` times.
+    ///
+    /// fragmentation may trip a broken middlebox and fail the handshake.
+    /// when retrying, the connector dials a fresh connection and splits the
+    /// ClientHello at a new random offset, regardless of the strategy, unless
+    /// told otherwise with [`strategies`](Self::strategies). after the last
+    /// retry, the error of every attempt is returned in
+    /// [`Error::AttemptsFailed`](crate::Error::AttemptsFailed); on success,
+    /// they're told by [`Detour::attempts`]. defaults to 0.
+    ///
+    /// the connection of each retry is dialed only once the attempt before
+    /// it failed, by a clone of the inner connector, made ready for it.
+    pub fn retry(&mut self, n: usize) -> &mut Self {
+        self.config.retries = n;
+        self
+    }
+
+    /// try each strategy in turn, one per attempt at the handshake.
+    ///
+    /// the first attempt is cut with the first strategy, the first retry
+    /// with the second one, and so on, for every host; there are at least
+    /// as many retries as it takes to try them all. retries left beyond
+    /// them split at random, as usual. the attempt which got through is
+    /// told by [`FragmentInfo::attempt`].
+    pub fn strategies(&mut self, strategies: Vec<SplitStrategy>) -> &mut Self {
+        self.config.strategies = strategies;
+        self
+    }
+
+    /// try cutting at `base` bytes into the payload, then `step` bytes
+    /// further on each retry, `count` offsets in all, in place of any
+    /// [`strategies`](Self::strategies) set before.
+    ///
+    /// this is for finding out where a DPI box stops catching the hostname,
+    /// e.g. the smallest first fragment it lets through. it's the same as
+    /// those strategies with a [`SplitStrategy::FixedOffset`] for each
+    /// offset, so the one which got through is the strategy of the last of
+    /// [`Detour::attempts`]; if none did, they're all in
+    /// [`Error::AttemptsFailed`](crate::Error::AttemptsFailed). to search
+    /// further, start another sweep from where this one ended.
+    pub fn offset_sweep(&mut self, base: usize, step: usize, count: usize) -> &mut Self {
+        let offsets = (0..count).map(|i| base.saturating_add(i.saturating_mul(step)));
+        self.strategies(offsets.map(SplitStrategy::FixedOffset).collect())
+    }
+
+    /// retry once more after every other attempt, sending the ClientHello
+    /// whole.
+    ///
+    /// some servers wrongly reject a hello split across records, so no
+    /// split will ever get through to them; this keeps them reachable
+    /// where there's nothing to detour around, at the cost of a last
+    /// handshake in plain sight. it's tried after any failure, as a
+    /// rejected split can't be told apart from others. its [`Attempt`]
+    /// has no strategy. defaults to false.
+    ///
+    /// [`Attempt`]: crate::Attempt
+    pub fn unfragmented_retry(&mut self, enable: bool) -> &mut Self {
+        self.config.unfragmented_retry = enable;
+        self
+    }
+
+    /// fragment only the ClientHellos to hosts matching `pattern`.
+    ///
+    /// a pattern is either a hostname, matched exactly, or a wildcard like
+    /// `*.example.com`, matching every subdomain of `example.com` but not
+    /// itself. both are case-insensitive. call this again to add more; once
+    /// there's any, a hello to other hosts, or without SNI, is sent as is.
+    /// with [`PolicyMode::Denylist`], it's the other way around.
+    ///
+    /// it's decided once per connection, from the SNI of its hello. if the
+    /// connection is later reused for another host, e.g. an http2 client
+    /// coalescing authorities served by the same certificate, nothing is
+    /// decided, nor fragmented, again.
+    pub fn enable_for(&mut self, pattern: &str) -> &mut Self {
+        self.config.hosts.push(HostRule::new(pattern));
+        self
+    }
+
+    /// choose whether the hosts given to [`enable_for`](Self::enable_for)
+    /// are the only ones fragmented, or the only ones not.
+    pub fn policy_mode(&mut self, mode: PolicyMode) -> &mut Self {
+        self.config.policy_mode = mode;
+        self
+    }
+
+    /// fragment only the ClientHellos sent to one of `ports`, e.g. to leave
+    /// internal services on 8443 alone, where there's no DPI to get around.
+    ///
+    /// the port is the one the connectors and [`connect`](crate::connect)
+    /// dial, after `HttpsConnector::set_connect_to`; a detour made by hand
+    /// doesn't know of it, and fragments regardless. defaults to
+    /// `vec![443]`, the port of https; an empty list fragments on every
+    /// port, as does an `only-ports` line of a policy file without any.
+    pub fn only_ports(&mut self, ports: Vec<u16>) -> &mut Self {
+        self.config.ports = ports;
+        self
+    }
+
+    /// cut the ClientHellos to hosts matching `pattern` with another
+    /// strategy.
+    ///
+    /// patterns are the same as for [`enable_for`](Self::enable_for); if
+    /// more than one matches a host, the first one added wins. this doesn't
+    /// enable fragmentation for the host by itself.
+    pub fn host_strategy(&mut self, pattern: &str, strategy: SplitStrategy) -> &mut Self {
+        self.config.host_strategies.push((HostRule::new(pattern), strategy));
+        self
+    }
+
+    /// fragment only the ClientHellos to hosts matching `regex`.
+    ///
+    /// same as [`enable_for`](Self::enable_for), but for rules too complex
+    /// for a wildcard. the hostname is matched as is, without a trailing
+    /// dot; anchor the pattern to match it whole.
+    #[cfg(feature = "regex")]
+    pub fn enable_for_regex(&mut self, regex: regex::Regex) -> &mut Self {
+        self.config.hosts.push(HostRule::Regex(regex));
+        self
+    }
+
+    /// call `f` with how each ClientHello was fragmented, e.g. for logging
+    /// or metrics. it's called in the middle of a write, so keep it quick.
+    pub fn on_fragment<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&FragmentInfo) + Send + Sync + 'static,
+    {
+        self.config.on_fragment = Some(OnFragment(Arc::new(f)));
+        self
+    }
+
+    /// call `f` with why each ClientHello was fragmented or not, e.g. for
+    /// logging; see [`DetourDecision`]. a first write which isn't a hello
+    /// is told of too, and so is every write after it until one is, unless
+    /// [`first_write_only`](Self::first_write_only). it's called in the
+    /// middle of a write, so keep it quick.
+    pub fn on_decision<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(DetourDecision) + Send + Sync + 'static,
+    {
+        self.config.on_decision = Some(OnDecision(Arc::new(f)));
+        self
+    }
+
+    /// call `f` with the bytes of every write the socket accepts, e.g. to
+    /// see exactly what a DPI box sees.
+    ///
+    /// this includes each fragment of the ClientHello, record header and
+    /// all, and everything written after it. a fragment the socket takes
+    /// in pieces is told piece by piece. all the detours made with this
+    /// config share `f`, one call at a time; without it, nothing is
+    /// tapped at all.
+    pub fn tap<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.config.tap = Some(Tap(Arc::new(Mutex::new(f))));
+        self
+    }
+
+    /// write every write the socket accepts to `writer` as a pcap file,
+    /// e.g. to look at the fragments in wireshark.
+    ///
+    /// this is a [`tap`](Self::tap), and replaces any other one. each write
+    /// becomes a tcp segment with its time, all in one made up flow to port
+    /// 443, so the detours sharing this config are best run one at a time.
+    /// the file is written by a thread of its own, off the socket's way; it
+    /// stops at the first error of `writer`, or once the config is dropped.
+    #[cfg(feature = "pcap")]
+    pub fn tap_pcap<W>(&mut self, writer: W) -> &mut Self
+    where
+        W: io::Write + Send + 'static,
+    {
+        self.tap(crate::pcap::tap(writer))
+    }
+
+    /// send a decoy ClientHello to `sni` before each real one.
+    ///
+    /// some DPI boxes track only the first hello from a client to a server.
+    /// a hello can't be followed by another on the same connection, so the
+    /// connector dials a separate one for the decoy, writes a minimal hello
+    /// on it, and drops it without waiting for an answer; dialing it is
+    /// best-effort. this only takes effect through a connector, not with
+    /// [`build`](Self::build).
+    pub fn decoy_hello(&mut self, sni: String) -> &mut Self {
+        self.config.decoy = Some(sni);
+        self
+    }
+
+    /// make every split reproducible, for tests and debugging.
+    ///
+    /// the same ClientHello is then always cut at the same places: retries
+    /// reuse the strategy instead of picking a random offset, and
+    /// [`SplitStrategy::Random`] acts like [`SplitStrategy::SniMidpoint`].
+    /// other options, e.g. [`max_fragments`](Self::max_fragments), still
+    /// apply.
+    pub fn deterministic(&mut self) -> &mut Self {
+        self.config.deterministic = true;
+        self
+    }
+
+    /// tell the detours that they go out on `network`, for
+    /// [`SplitStrategy::ByNetwork`] to pick by. use
+    /// [`DetourHandle::set_network`] to switch it later. defaults to
+    /// [`NetworkHint::Unknown`].
+    pub fn network(&mut self, network: NetworkHint) -> &mut Self {
+        self.config.network = network;
+        self
+    }
+
+    /// draw the random offsets, of [`SplitStrategy::Random`], the retries
+    /// and [`split_jitter`](Self::split_jitter), from a generator seeded
+    /// with `seed` instead of fresh entropy.
+    ///
+    /// unlike [`deterministic`](Self::deterministic), every hello is still
+    /// cut somewhere else, but a run can be replayed: the detours sharing
+    /// the config draw from the one sequence, in the order they cut, so
+    /// connections made one after another are cut the same way each time.
+    /// the configs built from this builder each start the sequence over.
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.config.rng = Rng::seeded(seed);
+        self
+    }
+
+    // take the options built so far
+    pub(crate) fn into_config(self) -> DetourConfig {
+        self.config
+    }
+
+    /// make a config with the current options, to share among detours
+    pub fn build_shared(&self) -> Arc<DetourConfig> {
+        Arc::new(self.config.clone())
+    }
+
+    /// wrap a stream into a detour with the current options
+    pub fn build<T: AsyncWrite>(&self, sock: T) -> Detour<T> {
+        Detour::from_config(sock, self.build_shared())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::hello::ClientHelloBuilder;
+    use crate::mock::{anonymous_hello, hello, Mock};
+
+    #[tokio::test]
+    async fn only_https_port() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+
+        // only 443 by default
+        for (port, fragmented) in [(443, true), (8443, false)] {
+            let handle = DetourHandle::new(builder.build_shared());
+            let mut detour = handle.wrap_to(Mock::new(), port);
+            detour.write_all(&hello).await.unwrap();
+            assert_eq!(detour.last_write_fragmented(), fragmented);
+        }
+
+        // every port, without any
+        let handle = DetourHandle::new(builder.only_ports(Vec::new()).build_shared());
+        let mut detour = handle.wrap_to(Mock::new(), 8443);
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.last_write_fragmented());
+    }
+
+    #[tokio::test]
+    async fn min_record_len() {
+        let small = ClientHelloBuilder::new().sni("example.com").build().unwrap();
+        assert!(small.len() < DEFAULT_MIN_RECORD_LEN);
+
+        // a tiny hello goes whole by default, one of a real size doesn't
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&small).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedTooShort));
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello("example.com")).await.unwrap();
+        assert!(detour.last_write_fragmented());
+
+        // unless told otherwise
+        let mut detour = DetourBuilder::new().min_record_len(0).build(Mock::new());
+        detour.write_all(&small).await.unwrap();
+        assert!(detour.last_write_fragmented());
+    }
+
+    #[tokio::test]
+    async fn seed() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+        builder.strategy(SplitStrategy::Random).split_jitter(0.1).seed(1);
+
+        // every config built starts the sequence over
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let config = builder.build_shared();
+            let mut cut = Vec::new();
+            for _ in 0..4 {
+                let mut detour = Detour::from_config(Mock::new(), config.clone());
+                detour.write_all(&hello).await.unwrap();
+                cut.push(detour.fragment_sizes().to_vec());
+            }
+            sizes.push(cut);
+        }
+        assert_eq!(sizes[0], sizes[1]);
+        assert!(sizes[0].windows(2).any(|w| w[0] != w[1]));
+    }
+
+    // the payload length of the first fragment the handle's config cuts
+    // `hello` into
+    async fn first_fragment(handle: &DetourHandle, hello: &[u8]) -> usize {
+        let mut detour = handle.wrap(Mock::new());
+        detour.write_all(hello).await.unwrap();
+        detour.fragment_sizes()[0]
+    }
+
+    #[tokio::test]
+    async fn by_network() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+        builder.strategy(SplitStrategy::ByNetwork(vec![
+            (NetworkHint::Cellular, SplitStrategy::FixedOffset(10)),
+            (NetworkHint::Unknown, SplitStrategy::FixedOffset(100)),
+        ]));
+
+        let handle = DetourHandle::new(builder.build_shared());
+        assert_eq!(first_fragment(&handle, &hello).await, 100);
+        handle.set_network(NetworkHint::Cellular);
+        assert_eq!(first_fragment(&handle, &hello).await, 10);
+        // without a pair of its own, like Unknown
+        handle.set_network(NetworkHint::Wifi);
+        assert_eq!(first_fragment(&handle, &hello).await, 100);
+
+        // without a pair for Unknown either, the fallback cuts at the middle
+        builder.strategy(SplitStrategy::ByNetwork(vec![(
+            NetworkHint::Other("office".into()),
+            SplitStrategy::FixedOffset(10),
+        )]));
+        let handle = DetourHandle::new(builder.network(NetworkHint::Wifi).build_shared());
+        assert_eq!(first_fragment(&handle, &hello).await, (hello.len() - 5) / 2);
+        handle.set_network(NetworkHint::Other("office".into()));
+        assert_eq!(first_fragment(&handle, &hello).await, 10);
+    }
+
+    #[tokio::test]
+    async fn store() {
+        let hello = hello("example.com");
+        let handle = DetourHandle::default();
+        let mut before = handle.wrap(Mock::new());
+
+        let mut builder = DetourBuilder::new();
+        handle.store(builder.strategy(SplitStrategy::FixedOffset(10)).build_shared());
+        assert_eq!(handle.load().strategy, SplitStrategy::FixedOffset(10));
+
+        // the connections made before keep the config they started with
+        let mut default = Detour::new(Mock::new());
+        default.write_all(&hello).await.unwrap();
+        before.write_all(&hello).await.unwrap();
+        assert_eq!(before.fragment_sizes(), default.fragment_sizes());
+        let mut after = handle.wrap(Mock::new());
+        after.write_all(&hello).await.unwrap();
+        assert_eq!(after.fragment_sizes()[0], 10);
+
+        // and so do clones of the handle
+        assert_eq!(handle.clone().load().strategy, SplitStrategy::FixedOffset(10));
+    }
+
+    #[tokio::test]
+    async fn clone_builder() {
+        let mut base = DetourBuilder::new();
+        base.strategy(SplitStrategy::FixedOffset(10)).retry(1);
+
+        // a clone is tweaked on its own, leaving the base as it was
+        let mut tweaked = base.clone();
+        tweaked.strategy(SplitStrategy::FromEnd(4));
+        assert_eq!(base.build_shared().strategy, SplitStrategy::FixedOffset(10));
+        assert_eq!(tweaked.build_shared().strategy, SplitStrategy::FromEnd(4));
+        assert_eq!(tweaked.build_shared().retries(), 1);
+
+        // and so is a builder made from a config read from a file
+        let config = DetourConfig::from_reader(&b"max-fragments 4"[..]).unwrap();
+        let mut from = DetourBuilder::from(config.clone());
+        from.max_fragments(2);
+        assert_eq!((config.max_fragments, from.build_shared().max_fragments), (4, 2));
+
+        // but the clones share a callback, unless given their own
+        let fragmented = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fragmented.clone();
+        base.on_fragment(move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        });
+        for builder in [base.clone(), base] {
+            builder.build(Mock::new()).write_all(&hello("example.com")).await.unwrap();
+        }
+        assert_eq!(fragmented.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    // what a detour built by `builder` decides about `hello`
+    async fn decision(builder: &DetourBuilder, hello: &[u8]) -> DetourDecision {
+        let mut detour = builder.build(Mock::new());
+        detour.write_all(hello).await.unwrap();
+        detour.decision().unwrap()
+    }
+
+    #[tokio::test]
+    async fn enable_for() {
+        let mut builder = DetourBuilder::new();
+        builder.enable_for("example.com").enable_for("*.example.org");
+
+        for (sni, expected) in [
+            ("example.com", DetourDecision::Fragmented),
+            ("www.example.org", DetourDecision::Fragmented),
+            ("www.example.com", DetourDecision::SkippedPolicy),
+            ("example.org", DetourDecision::SkippedPolicy),
+        ] {
+            assert_eq!(decision(&builder, &hello(sni)).await, expected, "{}", sni);
+        }
+
+        // a hello without SNI isn't to any of them
+        let anonymous = ClientHelloBuilder::new().build().unwrap();
+        assert_eq!(decision(&builder, &anonymous).await, DetourDecision::SkippedPolicy);
+    }
+
+    #[tokio::test]
+    async fn enable_for_once() {
+        let mut builder = DetourBuilder::new();
+        builder.enable_for("example.com");
+        let (com, org) = (hello("example.com"), hello("www.example.org"));
+
+        // a connection reused for another host, e.g. by http2 coalescing,
+        // keeps what was decided from its first hello
+        for (first, then, decision) in [
+            (&com, &org, DetourDecision::Fragmented),
+            (&org, &com, DetourDecision::SkippedPolicy),
+        ] {
+            let mut detour = builder.build(Mock::new());
+            detour.write_all(first).await.unwrap();
+            let written = detour.written().len();
+            detour.write_all(then).await.unwrap();
+            assert_eq!(detour.decision(), Some(decision));
+            assert_eq!(detour.written()[written..], then[..]);
+            assert!(!detour.last_write_fragmented());
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[tokio::test]
+    async fn enable_for_regex() {
+        let mut builder = DetourBuilder::new();
+        builder.enable_for_regex(regex::Regex::new(r"^cdn\d+\.example\.com$").unwrap());
+
+        for (sni, expected) in [
+            ("cdn1.example.com", DetourDecision::Fragmented),
+            ("cdn42.example.com", DetourDecision::Fragmented),
+            ("cdn.example.com", DetourDecision::SkippedPolicy),
+            ("www.cdn1.example.com", DetourDecision::SkippedPolicy),
+        ] {
+            assert_eq!(decision(&builder, &hello(sni)).await, expected, "{}", sni);
+        }
+    }
+
+    #[tokio::test]
+    async fn host_strategy() {
+        let mut builder = DetourBuilder::new();
+        builder
+            .host_strategy("*.example.org", SplitStrategy::FixedOffset(3))
+            .host_strategy("www.example.org", SplitStrategy::FixedOffset(4));
+        let handle = DetourHandle::new(builder.build_shared());
+        let default = DetourHandle::default();
+        let (com, org) = (hello("example.com"), hello("www.example.org"));
+
+        // the first rule matching wins, and the rest get the default
+        assert_eq!(first_fragment(&handle, &org).await, 3);
+        assert_eq!(first_fragment(&handle, &com).await, first_fragment(&default, &com).await);
+
+        // it doesn't enable fragmenting a host by itself
+        builder.enable_for("example.com");
+        assert_eq!(decision(&builder, &org).await, DetourDecision::SkippedPolicy);
+
+        // the same from a policy file
+        let policy = b"host-strategy *.example.org fixed-offset 3\n";
+        let handle = DetourHandle::new(Arc::new(DetourConfig::from_reader(&policy[..]).unwrap()));
+        assert_eq!(first_fragment(&handle, &org).await, 3);
+        assert_eq!(first_fragment(&handle, &com).await, first_fragment(&default, &com).await);
+    }
+
+    #[tokio::test]
+    async fn denylist() {
+        let mut builder = DetourBuilder::new();
+        builder.enable_for("*.example.org").policy_mode(PolicyMode::Denylist);
+
+        for (sni, expected) in [
+            ("www.example.org", DetourDecision::SkippedPolicy),
+            ("example.org", DetourDecision::Fragmented),
+            ("example.com", DetourDecision::Fragmented),
+        ] {
+            assert_eq!(decision(&builder, &hello(sni)).await, expected, "{}", sni);
+        }
+
+        // a hello without SNI isn't to any of them, so it's fragmented
+        assert_eq!(decision(&builder, &anonymous_hello()).await, DetourDecision::Fragmented);
+    }
+
+    #[test]
+    fn strategies() {
+        let mut builder = DetourBuilder::new();
+        builder.strategies(vec![SplitStrategy::FixedOffset(1), SplitStrategy::BeforeSni]);
+
+        // a retry for each one after the first, and random ones beyond them
+        let config = builder.build_shared();
+        assert_eq!(config.retries(), 1);
+        let config = builder.retry(3).build_shared();
+        assert_eq!(config.retries(), 3);
+        let attempts: Vec<_> = (0..4).map(|attempt| config.strategy_for(attempt, None)).collect();
+        let expected = [
+            SplitStrategy::FixedOffset(1),
+            SplitStrategy::BeforeSni,
+            SplitStrategy::Random,
+            SplitStrategy::Random,
+        ];
+        assert_eq!(attempts, expected);
+    }
+
+    #[test]
+    fn offset_sweep() {
+        let mut builder = DetourBuilder::new();
+        builder.strategies(vec![SplitStrategy::BeforeSni]).offset_sweep(10, 5, 3);
+
+        // the sweep takes over the strategies set before
+        let config = builder.build_shared();
+        let expected = [10, 15, 20].map(SplitStrategy::FixedOffset);
+        assert_eq!(config.strategies, expected);
+        assert_eq!(config.retries(), 2);
+
+        // and stops at the largest offset instead of overflowing
+        let config = builder.offset_sweep(usize::MAX - 1, 2, 2).build_shared();
+        let expected = [usize::MAX - 1, usize::MAX].map(SplitStrategy::FixedOffset);
+        assert_eq!(config.strategies, expected);
+    }
+
+    #[tokio::test]
+    async fn build_shared() {
+        let hello = hello("example.com");
+        let config = DetourBuilder::new().strategy(SplitStrategy::FixedOffset(10)).build_shared();
+
+        // every detour points at the one config, and cuts with it
+        let mut detours: Vec<_> =
+            (0..100).map(|_| Detour::from_config(Mock::new(), config.clone())).collect();
+        for detour in &mut detours {
+            detour.write_all(&hello).await.unwrap();
+            assert!(Arc::ptr_eq(detour.config(), &config));
+            assert_eq!(detour.fragment_sizes(), [10, hello.len() - 15]);
+        }
+        assert_eq!(Arc::strong_count(&config), 101);
+
+        // and lets go of it when dropped
+        drop(detours);
+        assert_eq!(Arc::strong_count(&config), 1);
+    }
+
+    #[tokio::test]
+    async fn deterministic() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+        builder.strategy(SplitStrategy::Random).split_jitter(0.2).deterministic();
+
+        // the same hello comes out the same, byte for byte, jitter and all
+        let mut written = Vec::new();
+        for _ in 0..10 {
+            let mut detour = builder.build(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+            written.push(detour.written());
+        }
+        assert!(written.windows(2).all(|pair| pair[0] == pair[1]));
+
+        // retries included, cut like the first attempt
+        let config = builder.build_shared();
+        assert_eq!(config.strategy_for(0, None), SplitStrategy::SniMidpoint);
+        assert_eq!(config.strategy_for(1, None), SplitStrategy::SniMidpoint);
+    }
+}
@@ -0,0 +1,245 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{service::Service, Uri};
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_native_tls::TlsConnector;
+
+use crate::client::default_port;
+use crate::config::{DetourBuilder, DetourConfig, DetourHandle};
+use crate::detour::Detour;
+use crate::stream::TlsStream;
+
+/// dial `addr`, and run a tls handshake to `domain` through a detour
+///
+/// this is for using a detour without hyper. it uses the default
+/// `TlsConnector`, and tries only once; for anything else, dial the stream
+/// yourself and use `HttpsConnector::connect_over`.
+pub async fn connect<A: ToSocketAddrs>(
+    domain: &str,
+    addr: A,
+    config: Arc<DetourConfig>,
+) -> io::Result<TlsStream<Detour<TcpStream>>> {
+    let tls = TlsConnector::from(native_tls::TlsConnector::new().map_err(io::Error::other)?);
+
+    let tcp = TcpStream::connect(addr).await?;
+    if config.nodelay {
+        tcp.set_nodelay(true)?;
+    }
+    let detoured = if config.applies_to_port(tcp.peer_addr()?.port()) {
+        Detour::from_config(tcp, config)
+    } else {
+        Detour::passthrough(tcp)
+    };
+    tls.connect(domain, detoured).await.map_err(io::Error::other)
+}
+
+/// a connector which wraps every connection of another one in a [`Detour`]
+///
+/// `HttpsConnector` already does this by itself; this is for putting the
+/// detour into a stack of your own, e.g. under another tls connector. the
+/// detour has to see the raw ClientHello, so it must sit right beneath the
+/// tls layer: a layer in between may only pass writes through unchanged,
+/// like a logger, and one below it may do anything, like a proxy.
+#[derive(Clone)]
+pub struct DetourConnector<C> {
+    inner: C,
+    detour: DetourHandle,
+}
+
+impl<C> DetourConnector<C> {
+    /// wrap the connections of `inner` with the default options
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            detour: DetourHandle::default(),
+        }
+    }
+
+    /// set the options used to wrap every new connection
+    pub fn set_detour(&mut self, detour: &DetourBuilder) {
+        self.detour.store(detour.build_shared());
+    }
+
+    /// get a handle to change the options at runtime
+    pub fn detour_handle(&self) -> DetourHandle {
+        self.detour.clone()
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for DetourConnector<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DetourConnector")
+            .field("inner", &self.inner)
+            .field("detour", &self.detour)
+            .finish()
+    }
+}
+
+impl<C> Service<Uri> for DetourConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: AsyncWrite,
+{
+    type Response = Detour<C::Response>;
+    type Error = C::Error;
+    type Future = DetourConnecting<C::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        DetourConnecting {
+            port: default_port(&dst),
+            connecting: self.inner.call(dst),
+            detour: Some(self.detour.clone()),
+        }
+    }
+}
+
+/// a future wrapping the connection of the inner connector in a detour
+pub struct DetourConnecting<F> {
+    connecting: F,
+    detour: Option<DetourHandle>,
+    port: u16,
+}
+
+impl<F, T, E> Future for DetourConnecting<F>
+where
+    F: Future<Output = Result<T, E>>,
+    T: AsyncWrite,
+{
+    type Output = Result<Detour<T>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // connecting is pinned along with self; detour is never pinned
+        let _self = unsafe { self.get_unchecked_mut() };
+        let connecting = unsafe { Pin::new_unchecked(&mut _self.connecting) };
+
+        match connecting.poll(cx) {
+            Poll::Ready(Ok(sock)) => {
+                let detour = _self.detour.take().expect("polled after completion");
+                Poll::Ready(Ok(detour.wrap_to(sock, _self.port)))
+            },
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F> fmt::Debug for DetourConnecting<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("DetourConnecting")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{ready, Ready};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::mock::{hello, Mock};
+
+    // a connector making mock sockets
+    struct Mocks;
+
+    impl Service<Uri> for Mocks {
+        type Response = Mock;
+        type Error = io::Error;
+        type Future = Ready<io::Result<Mock>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Uri) -> Self::Future {
+            ready(Ok(Mock::new()))
+        }
+    }
+
+    // the writes a hello goes out in, on a connection to `uri`
+    async fn writes(connector: &mut DetourConnector<Mocks>, uri: &str) -> usize {
+        let mut detour = connector.call(uri.parse().unwrap()).await.unwrap();
+        detour.write_all(&hello("example.com")).await.unwrap();
+        detour.writes().len()
+    }
+
+    #[tokio::test]
+    async fn wraps_connections() {
+        let mut connector = DetourConnector::new(Mocks);
+        assert_eq!(writes(&mut connector, "https://example.com/").await, 2);
+
+        // with the options set last
+        let mut detour = DetourBuilder::new();
+        detour.max_fragments(4).strategy(crate::SplitStrategy::EveryByte);
+        connector.set_detour(&detour);
+        assert_eq!(writes(&mut connector, "https://example.com/").await, 4);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let mut connector = DetourConnector::new(Mocks);
+        let handle = connector.detour_handle();
+        handle.set_enabled(false);
+        assert_eq!(writes(&mut connector, "https://example.com/").await, 1);
+
+        // the config was kept
+        handle.set_enabled(true);
+        assert_eq!(writes(&mut connector, "https://example.com/").await, 2);
+    }
+
+    #[tokio::test]
+    async fn only_ports() {
+        // the port of the uri, or the default of its scheme
+        let mut connector = DetourConnector::new(Mocks);
+        assert_eq!(writes(&mut connector, "https://example.com:443/").await, 2);
+        assert_eq!(writes(&mut connector, "https://example.com:8443/").await, 1);
+
+        let mut detour = DetourBuilder::new();
+        connector.set_detour(detour.only_ports(vec![8443]));
+        assert_eq!(writes(&mut connector, "https://example.com/").await, 1);
+        assert_eq!(writes(&mut connector, "https://example.com:8443/").await, 2);
+    }
+
+    // the records the first ClientHello sent to `listener` comes in
+    async fn hello_records(listener: TcpListener) -> usize {
+        let (mut tcp, _) = listener.accept().await.unwrap();
+        // the length of the hello, from the header of its handshake message
+        let hello_len = |p: &[u8]| 4 + u32::from_be_bytes([0, p[1], p[2], p[3]]) as usize;
+        let (mut records, mut payload) = (0, Vec::new());
+        while payload.len() < 4 || payload.len() < hello_len(&payload) {
+            let mut header = [0; 5];
+            tcp.read_exact(&mut header).await.unwrap();
+            let mut record = vec![0; u16::from_be_bytes([header[3], header[4]]) as usize];
+            tcp.read_exact(&mut record).await.unwrap();
+            payload.extend_from_slice(&record);
+            records += 1;
+        }
+        records
+    }
+
+    #[tokio::test]
+    async fn connect_fragments() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(hello_records(listener));
+
+        // the server hangs up without answering the hello
+        let config = DetourBuilder::new().only_ports(Vec::new()).build_shared();
+        assert!(connect("localhost", addr, config).await.is_err());
+        assert_eq!(server.await.unwrap(), 2);
+    }
+}
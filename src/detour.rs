@@ -2,149 +2,770 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
 
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use core::fmt;
 use core::ops::{Deref, DerefMut};
 use core::pin::Pin;
 use core::task::{Context, Poll};
 
 use hyper::client::connect::{Connection, Connected};
 
-// TLS protocol reference from RFC 8446
+use crate::hello::{hostname, is_hello, record_len, redacted, ParseError, MAX_RECORD_LEN};
+use crate::config::DetourConfig;
+use crate::strategy::{jittered, Rng, SplitStrategy};
+use crate::client::{Attempt, ConnectPhase, PhaseCell};
 
-// a magic that tells you if a tls record is client hello (2 comparisons!)
-fn is_hello(data: &[u8]) -> bool {
-    // type == handshake && msg_type == client_hello
-    data[0] == 0x16 && data[5] == 0x01
-}
+// split a tls record at each of `points` into fragments. every point is an
+// index into the whole record, header included, like every offset the
+// parser finds; the header is never part of a fragment's payload, so the
+// first one starts at 5 and a point must be past it.
+// if multiple tls records of same type are send, the server should
+// identify them as 'fragmented' and reassemble them up to a single record.
+fn fragmentate(data: &[u8], points: &[usize]) -> VecDeque<Vec<u8>> {
+    // struct {
+    //     ContentType type;
+    //     ProtocolVersion legacy_record_version;
+    //     uint16 length;
+    //     opaque fragment[TLSPlaintext.length];
+    // } TLSPlaintext;
 
-// find where to cut
-fn find_sni(data: &[u8]) -> Option<usize> {
-    // uint16 ProtocolVersion;
-    // opaque Random[32];
-    //
-    // uint8 CipherSuite[2];    /* Cryptographic suite selector */
+    // we'll keep `type` and `legacy_record_version` as same as original,
+    // but `length` will be changed to the chunk's size.
     //
-    // struct {
-    //     ProtocolVersion legacy_version = 0x0303;    /* TLS v1.2 */
-    //     Random random;
-    //     opaque legacy_session_id<0..32>;
-    //     CipherSuite cipher_suites<2..2^16-2>;
-    //     opaque legacy_compression_methods<1..2^8-1>;
-    //     Extension extensions<8..2^16-1>;
-    // } ClientHello;
+    // the handshake header inside the payload is left alone: its length
+    // is that of the whole ClientHello, which the server reassembles from
+    // every fragment. the fragments after the first carry no header of
+    // their own, only the bytes following the cut.
+    let mut fragments = VecDeque::with_capacity(points.len() + 1);
+
+    // the payload starts after the header
+    let mut start = 5;
+    for &end in points.iter().chain(Some(&data.len())) {
+        // an empty record may be rejected by the server; `normalize` must
+        // have dropped the cuts making one
+        debug_assert!(start < end, "empty fragment at {}", start);
+        let chunk = &data[start..end];
+        let size_bytes = (chunk.len() as u16).to_be_bytes();
 
-    // skip all headers we're not interested in
+        let mut fragment = Vec::with_capacity(5 + chunk.len());
+        fragment.extend_from_slice(&[
+            data[0],
+            data[1], data[2],
+            size_bytes[0], size_bytes[1]
+        ]);
+        fragment.extend_from_slice(chunk);
 
-    fn u16_at(data: &[u8], at: usize) -> u16 {
-        let arr = [data[at], data[at + 1]];
-        u16::from_be_bytes(arr)
+        fragments.push_back(fragment);
+        start = end;
     }
 
-    // skip up to `random`
-    let mut offset = 5 + 4 + 2 + 32;
+    // joined back at the record layer, the payloads must be the very same
+    // handshake message, or a compliant server would reject it
+    debug_assert!(
+        fragments.iter().flat_map(|f| &f[5..]).eq(&data[5..]),
+        "fragments don't reassemble into the original record"
+    );
 
-    // legacy_session_id
-    offset += 1 + data[offset] as usize;
+    fragments
+}
 
-    // cipher_suites
-    offset += 2 + u16_at(data, offset) as usize;
+// sort out the cuts, dropping those which would leave an empty fragment,
+// and merge fragments until there are at most `max` of them
+fn normalize(mut points: Vec<usize>, len: usize, max: usize) -> Vec<usize> {
+    points.retain(|&p| 5 < p && p < len);
+    points.sort_unstable();
+    points.dedup();
 
-    // legacy_compression_methods
-    offset += 1 + data[offset] as usize;
+    // n fragments take n - 1 cuts; keep ones evenly spread over the rest
+    let max_points = max.max(1) - 1;
+    if points.len() > max_points {
+        points = (0..max_points)
+            .map(|i| points[i * points.len() / max_points])
+            .collect();
+    }
 
-    // extensions
-    let ext_end = offset + 2 + u16_at(data, offset) as usize;
+    points
+}
 
-    offset += 2;
-    while offset < ext_end {
-        // extension_type == server_name
-        if u16_at(data, offset) == 0u16 {
-            offset += 2;
-            let sni_len = u16_at(data, offset) as usize;
+pub(crate) const DEFAULT_MAX_FRAGMENTS: usize = 16;
 
-            return Some(offset + sni_len / 2);
-        } else {
-            offset += 2;
-            offset += 2 + u16_at(data, offset) as usize;
-        }
+// how many short writes a single poll goes through before yielding
+const MAX_SHORT_WRITES: usize = 16;
+
+/// cut a captured tls record the way a detour with `strategy` would, e.g.
+/// to preview the splits in a tool
+///
+/// `record` starts with a whole record; anything after it is left to the
+/// last fragment, as a detour does. fragments are capped at the default of
+/// [`DetourBuilder::max_fragments`](crate::DetourBuilder::max_fragments).
+/// if the strategy can't cut the record, it's returned whole, unless it's a
+/// ClientHello which can't be parsed. a record longer than the spec allows
+/// is an error.
+pub fn fragment_record(
+    record: &[u8],
+    strategy: SplitStrategy,
+) -> Result<Vec<Vec<u8>>, ParseError> {
+    if record.len() < 5 {
+        return Err(ParseError::UnexpectedEof(record.len()));
+    }
+    // cut short, or longer than the spec allows
+    if record_len(record) > record.len() || record_len(record) > 5 + MAX_RECORD_LEN {
+        return Err(ParseError::LengthOverflow(3));
     }
 
-    None
+    // nothing to cut in a record with less than two bytes of payload
+    let (hello, rest) = record.split_at(record_len(record));
+    if hello.len() < 7 {
+        return Ok(vec![record.to_vec()]);
+    }
+
+    let points = match strategy.split_points(hello, &Rng::default()) {
+        Some(points) => normalize(points, hello.len(), DEFAULT_MAX_FRAGMENTS),
+        None => {
+            if is_hello(hello) {
+                hostname(hello)?;
+            }
+            Vec::new()
+        },
+    };
+
+    let mut fragments = fragmentate(hello, &points);
+    #[cfg(feature = "experimental")]
+    strategy.mislabel(&mut fragments);
+    if let Some(last) = fragments.back_mut() {
+        last.extend_from_slice(rest);
+    }
+    Ok(fragments.into())
 }
 
-// split a tls record half into fragments.
-// if multiple tls records of same type are send, the server should
-// identify them as 'fragmented' and reassemble them up to a single record.
-fn fragmentate(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    // struct {
-    //     ContentType type;
-    //     ProtocolVersion legacy_record_version;
-    //     uint16 length;
-    //     opaque fragment[TLSPlaintext.length];
-    // } TLSPlaintext; (Vec<u8>, Vec<u8>) {
+/// how a ClientHello was fragmented
+#[derive(Clone, PartialEq, Eq)]
+pub struct FragmentInfo {
+    /// the hostname in the hello, if any
+    pub sni: Option<String>,
+    /// the length of the hello record, including its header
+    pub record_len: usize,
+    /// the offsets into the record where it was cut, counted from the start
+    /// of its header; subtract 5 for the offsets into its payload
+    pub split_points: Vec<usize>,
+    /// the number of records it was cut into
+    pub fragment_count: usize,
+    /// the attempt at the handshake it was sent on, 0 for the first one
+    pub attempt: usize,
+}
+
+impl fmt::Debug for FragmentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FragmentInfo")
+            .field("sni", &redacted(self.sni.as_deref()))
+            .field("record_len", &self.record_len)
+            .field("split_points", &self.split_points)
+            .field("fragment_count", &self.fragment_count)
+            .field("attempt", &self.attempt)
+            .finish()
+    }
+}
 
-    // split the payload into half
-    let mid = find_sni(data).unwrap_or(5 + (data.len() - 5) / 2);
-    let (left, right) = data.split_at(mid);
+/// why a detour fragmented the first record it was given, or why not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetourDecision {
+    /// it was a ClientHello, and it was fragmented
+    Fragmented,
+    /// it wasn't a ClientHello, and went out as is
+    SkippedNotHello,
+    /// the hello was to a host left out by
+    /// [`DetourBuilder::enable_for`](crate::DetourBuilder::enable_for)
+    SkippedPolicy,
+    /// the hello was shorter than
+    /// [`DetourBuilder::min_record_len`](crate::DetourBuilder::min_record_len)
+    SkippedTooShort,
+    /// [`DetourBuilder::max_fragments`](crate::DetourBuilder::max_fragments)
+    /// or [`max_overhead_bytes`](crate::DetourBuilder::max_overhead_bytes)
+    /// leave room for a single record, so there's nothing to cut
+    SkippedSingleFragment,
+    /// the hello had no SNI to cut, and the fallback is passthrough
+    SkippedNoSni,
+    /// the hello couldn't be parsed, and the fallback is passthrough; see
+    /// [`Detour::parse_error`]
+    SkippedParseError,
+    /// the strategy couldn't cut the hello, e.g. `AfterSni` with the
+    /// hostname at its very end, and the fallback is passthrough
+    SkippedFallback,
+    /// the detour was turned off for the connection, e.g. with
+    /// [`DetourHandle::set_enabled`](crate::DetourHandle::set_enabled) or
+    /// [`DetourBuilder::only_ports`](crate::DetourBuilder::only_ports), or
+    /// made with [`Detour::passthrough`]
+    SkippedDisabled,
+}
 
-    // we'll keep `type` and `legacy_record_version` as same as original,
-    // but `length` will be changed to the chunk's size.
+// what to do with a buf starting with a ClientHello
+pub(crate) struct Plan {
+    pub(crate) sni: Option<String>,
+    pub(crate) parse_error: Option<ParseError>,
+    // whole records to write in order; None to write the buf as is
+    pub(crate) fragments: Option<VecDeque<Vec<u8>>>,
+    pub(crate) fragment_sizes: Vec<usize>,
+    pub(crate) info: Option<FragmentInfo>,
+    pub(crate) decision: Option<DetourDecision>,
+    // a strategy's cut out of the record, in strict mode; nothing to write
+    pub(crate) split_error: Option<ParseError>,
+}
+
+impl Plan {
+    // the error to fail the write with, if the hello can't be sent
+    pub(crate) fn error(&self) -> Option<tokio::io::Error> {
+        self.split_error.map(|e| tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, e))
+    }
+}
+
+// shared by the async and the sync detours
+pub(crate) fn plan(config: &DetourConfig, attempt: usize, buf: &[u8]) -> Plan {
+    // only the hello is split; records after it are left
+    // untouched and follow the last fragment
+    let (hello, rest) = buf.split_at(record_len(buf));
+
+    let (sni, parse_error) = match hostname(hello) {
+        Ok(sni) => (sni.map(String::from), None),
+        Err(e) => (None, Some(e)),
+    };
+
+    let applies = config.applies_to(sni.as_deref());
+    let long_enough = hello.len() >= config.min_record_len;
+    let single = config.fragment_cap() < 2;
+    let strategy = if applies && long_enough && !single {
+        Some(config.strategy_for(attempt, sni.as_deref()))
+    } else {
+        None
+    };
+    let points = strategy.as_ref().and_then(|strategy| {
+        strategy.split_points(hello, &config.rng).or_else(|| config.fallback.split_points(hello))
+    });
+
+    let mut plan = Plan {
+        sni,
+        parse_error,
+        fragments: None,
+        fragment_sizes: Vec::new(),
+        info: None,
+        decision: None,
+        split_error: None,
+    };
+    if let Some(points) = points {
+        // a cut at either end only makes an empty fragment, which is
+        // dropped; one beyond them is a bug of the strategy
+        if config.strict_splits {
+            if let Some(&point) = points.iter().find(|&&p| p < 5 || p > hello.len()) {
+                plan.split_error = Some(ParseError::InvalidSplit(point));
+                return plan;
+            }
+        }
+        let points = match config.split_jitter {
+            jitter if jitter > 0.0 => {
+                jittered(points, hello, jitter, config.deterministic, &config.rng)
+            },
+            _ => points,
+        };
+        let points = normalize(points, hello.len(), config.fragment_cap());
+        let mut fragments = fragmentate(hello, &points);
+        plan.fragment_sizes = fragments.iter().map(|f| f.len() - 5).collect();
+        #[cfg(feature = "experimental")]
+        if let Some(strategy) = &strategy {
+            strategy.mislabel(&mut fragments);
+        }
+        if let Some(last) = fragments.back_mut() {
+            last.extend_from_slice(rest);
+        }
+
+        let info = FragmentInfo {
+            sni: plan.sni.clone(),
+            record_len: hello.len(),
+            fragment_count: fragments.len(),
+            split_points: points,
+            attempt,
+        };
+        if let Some(on_fragment) = &config.on_fragment {
+            on_fragment.call(&info);
+        }
+        plan.info = Some(info);
 
-    // left contains header; payload length is len - 5
-    let size_bytes = ((left.len() - 5) as u16).to_be_bytes();
-    let mut first = left.to_vec();
-    first[3] = size_bytes[0];
-    first[4] = size_bytes[1];
+        // the records stay cut, but go out back to back in one write
+        if config.single_write {
+            fragments = VecDeque::from(vec![fragments.into_iter().flatten().collect()]);
+        }
+        plan.fragments = Some(fragments);
+    }
+
+    // a hello left whole despite applying fell back to passthrough
+    let decision = if plan.fragments.is_some() {
+        DetourDecision::Fragmented
+    } else if !applies {
+        DetourDecision::SkippedPolicy
+    } else if !long_enough {
+        DetourDecision::SkippedTooShort
+    } else if single {
+        DetourDecision::SkippedSingleFragment
+    } else if plan.parse_error.is_some() {
+        DetourDecision::SkippedParseError
+    } else if plan.sni.is_none() {
+        DetourDecision::SkippedNoSni
+    } else {
+        DetourDecision::SkippedFallback
+    };
+    decide(config, decision);
+    plan.decision = Some(decision);
+
+    #[cfg(feature = "metrics")]
+    record_metrics(&plan);
+    plan
+}
+
+// tell the callback, if any, of what was done with a record
+pub(crate) fn decide(config: &DetourConfig, decision: DetourDecision) {
+    if let Some(on_decision) = &config.on_decision {
+        on_decision.call(decision);
+    }
+}
 
-    let size_bytes = (right.len() as u16).to_be_bytes();
-    let mut second = vec![
-        data[0],
-        data[1], data[2], 
-        size_bytes[0], size_bytes[1]
-    ];
+// counters for every hello, and the size of each fragment
+#[cfg(feature = "metrics")]
+fn record_metrics(plan: &Plan) {
+    if plan.parse_error.is_some() {
+        metrics::counter!("detour_parse_errors_total").increment(1);
+    }
+    // a single fragment is the hello as is, e.g. with SplitStrategy::Identity
+    if plan.fragment_sizes.len() < 2 {
+        metrics::counter!("detour_passthroughs_total").increment(1);
+        return;
+    }
 
-    second.extend_from_slice(right);
+    metrics::counter!("detour_fragmented_hellos_total").increment(1);
+    metrics::counter!("detour_fragments_total").increment(plan.fragment_sizes.len() as u64);
+    let sizes = metrics::histogram!("detour_fragment_size_bytes");
+    for &size in &plan.fragment_sizes {
+        sizes.record(size as f64);
+    }
+}
 
-    (first, second)
+// how long the fragments of a hello took to go out
+#[cfg(feature = "metrics")]
+pub(crate) fn record_duration(duration: Option<Duration>) {
+    if let Some(duration) = duration {
+        metrics::histogram!("detour_fragment_duration_seconds").record(duration.as_secs_f64());
+    }
 }
 
-#[derive(Debug)]
 enum DetourState {
     // not sending a fragment; passthrough
     Normal,
-    // currently sending fragments
-    SendFirst(Vec<u8>, Vec<u8>), // first, second
-    SendSecond(Vec<u8>, usize),  // second, first_written
+    // currently sending fragments, front first
+    Send(VecDeque<Vec<u8>>, usize),  // fragments, written
+    // flushing the socket before sending the next fragment
+    Flush(VecDeque<Vec<u8>>, usize), // fragments, written
+}
+
+// the fragments are pieces of a ClientHello, which tells the hostname in
+// plain text; never dump them into logs, their lengths are enough
+impl fmt::Debug for DetourState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn lens(fragments: &VecDeque<Vec<u8>>) -> Vec<usize> {
+            fragments.iter().map(Vec::len).collect()
+        }
+
+        match self {
+            DetourState::Normal => f.write_str("Normal"),
+            DetourState::Send(fragments, written) => f
+                .debug_struct("Send")
+                .field("fragment_lens", &lens(fragments))
+                .field("written", written)
+                .finish(),
+            DetourState::Flush(fragments, written) => f
+                .debug_struct("Flush")
+                .field("fragment_lens", &lens(fragments))
+                .field("written", written)
+                .finish(),
+        }
+    }
 }
 
 /// a thin wrapper to bypass DPI(deep packet inspectation)
-#[derive(Debug)]
+///
+/// a detour lives and dies with its socket; so does everything it remembers
+/// about the connection. it fragments at most one ClientHello, the one
+/// starting the handshake, and passes everything else through. a connection
+/// reused from hyper's pool doesn't handshake again, so it isn't
+/// fragmented again either, nor is a protocol it's upgraded to, like
+/// websocket.
+///
+/// only the first write is checked for a ClientHello; if it isn't one,
+/// it goes out byte for byte, e.g. a plaintext http request sent to the
+/// wrong port, and so does everything after it. for a protocol upgrading
+/// to tls later on, like STARTTLS, see
+/// [`DetourBuilder::first_write_only`](crate::DetourBuilder::first_write_only).
+///
+/// only the write half is touched, so `T` needs nothing but `AsyncWrite`;
+/// a write-only sink works as well. reading, `Connection` and the like are
+/// passed through whenever `T` implements them.
+///
+/// nothing ties it to native-tls either: the detour only sees the bytes
+/// going out, and never looks into the tls library above it, so one
+/// taking an `AsyncRead + AsyncWrite` stream, like `tokio-rustls` or
+/// `tokio-openssl`, can run its handshake over a detour all the same; the
+/// tests run both native-tls and rustls on it. it's the `HttpsConnector`
+/// which uses native-tls; for another library, wrap the stream yourself,
+/// or stack a [`DetourConnector`](crate::DetourConnector) beneath its
+/// connector.
+///
+/// fragmenting only moves the record boundaries; the bytes of the hello
+/// are sent exactly as the tls layer wrote them. both ends hash the
+/// handshake messages into the transcript, so the detour can't rewrite the
+/// hello itself, e.g. to pad it to a uniform size, to strip its padding
+/// extension to shrink it, or to reorder its extensions for another
+/// fingerprint; the handshake would fail with a bad record mac. that goes
+/// for the legacy_session_id too, legacy or not: the server echoes it
+/// back, and the tls layer rejects any other than the one it wrote, so
+/// it's up to the tls backend to pick it. nor can another record go in
+/// between the fragments, e.g. a ChangeCipherSpec: a handshake message
+/// must not be interleaved with other records, and servers reject it as
+/// unexpected. records written along with the hello, e.g. the early data
+/// of 0-RTT, are left whole and follow the last fragment.
+///
+/// the fragments are queued in the detour, not in the write future, so
+/// dropping a write midway never leaves a record half sent: the next
+/// write, flush or shutdown sends the rest first. that write has to be the
+/// same one retried, or it fails, the stream being out of step with its
+/// caller; `into_inner` drops whatever is still queued, so flush before.
 pub struct Detour<T: AsyncWrite> {
     sock: T,
     state: DetourState,
+    // whether the hello of this connection has been dealt with
+    seen_hello: bool,
+    sni: Option<String>,
+    parse_error: Option<ParseError>,
+    // payload length of each fragment of the hello
+    fragment_sizes: Vec<usize>,
+    fragment_info: Option<FragmentInfo>,
+    decision: Option<DetourDecision>,
+    // when the first fragment went to the socket, and how long it took
+    // until the last one did
+    fragments_started: Option<Instant>,
+    fragment_duration: Option<Duration>,
+    // whether the last write was a fragmented hello
+    last_fragmented: bool,
+    // whether the fragments were sent by a flush, and their write hasn't
+    // been told yet
+    unreported: bool,
+    // the length of the buf the fragments were cut from; the write told
+    // of them has to be retried with it
+    queued_len: usize,
+    config: Arc<DetourConfig>,
+    // how many times the connector has tried before this connection
+    pub(crate) attempt: usize,
+    pub(crate) attempts: Vec<Attempt>,
+    // the phase of the connector's future, moved on once the hello is out
+    pub(crate) phase: Option<PhaseCell>,
 }
 
 impl<T: AsyncWrite> Detour<T> {
     /// make a new detour from a stream
     pub fn new(sock: T) -> Self {
+        Self::from_config(sock, Arc::default())
+    }
+
+    /// make a new detour from a stream, sharing a config with others.
+    ///
+    /// this allocates nothing but what the detour is put in, so it's cheap
+    /// enough to wrap every socket of a pool of your own with the same
+    /// config. the buffers for the fragments are only made for the hello,
+    /// when it's cut.
+    pub fn from_config(sock: T, config: Arc<DetourConfig>) -> Self {
         Self {
             sock,
             state: DetourState::Normal,
+            seen_hello: false,
+            sni: None,
+            parse_error: None,
+            fragment_sizes: Vec::new(),
+            fragment_info: None,
+            decision: None,
+            fragments_started: None,
+            fragment_duration: None,
+            last_fragmented: false,
+            unreported: false,
+            queued_len: 0,
+            config,
+            attempt: 0,
+            attempts: Vec::new(),
+            phase: None,
         }
     }
 
+    /// make a detour which never fragments, and writes everything as is.
+    ///
+    /// it has the same type as one which does, e.g. to compare the two
+    /// without changing any other code. the connector uses it for
+    /// plaintext, where a request body may happen to look like a
+    /// ClientHello.
+    pub fn passthrough(sock: T) -> Self {
+        let mut detour = Self::new(sock);
+        detour.seen_hello = true;
+        detour.decision = Some(DetourDecision::SkippedDisabled);
+        detour
+    }
+
     // consume a pin to self into a pin to sock
     fn sock(self: Pin<&mut Self>) -> Pin<&mut T> {
         unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().sock) }
     }
 
-    /// consume self, return inner socket
+    // send as many fragments as the socket takes; ready once there's none
+    // left. every arm keeps to the same contract:
+    // - an error from the socket drops the fragments left and goes back to
+    //   Normal, so nothing is ever sent half; the connection is dead, and
+    //   the error is the caller's
+    // - Pending keeps the state as is, to go on from there when polled
+    //   again. it comes either from the socket, which registered the waker
+    //   by itself, or from us giving way, after waking the task ourselves
+    // - a fragment fully written moves on to the next one, or to a flush
+    //   before it; the last one ends in Normal, with the hello reported
+    fn poll_drain(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        // we must not move self and its member from now on
+        let _self = unsafe { self.get_unchecked_mut() };
+        let mut short_writes = 0;
+
+        loop {
+            // both _self and _self.sock won't move so it's safe to pin
+            let sock = unsafe { Pin::new_unchecked(&mut _self.sock) };
+
+            match &mut _self.state {
+                DetourState::Normal => return Poll::Ready(Ok(())),
+                DetourState::Send(fragments, written) => {
+                    match sock.poll_write(cx, &fragments[0]) {
+                        Poll::Ready(Ok(0)) => {
+                            _self.abort();
+                            return Poll::Ready(Err(tokio::io::ErrorKind::WriteZero.into()));
+                        },
+                        Poll::Ready(Ok(n)) => {
+                            if let Some(tap) = &_self.config.tap {
+                                tap.call(&fragments[0][..n]);
+                            }
+                            *written += n;
+
+                            // the socket took only a part; send the rest of
+                            // this fragment before moving on to the next
+                            if n < fragments[0].len() {
+                                fragments[0].drain(..n);
+
+                                // a socket taking a byte at a time would keep
+                                // us here for the whole hello; give the rest of
+                                // the task a turn now and then
+                                short_writes += 1;
+                                if short_writes >= MAX_SHORT_WRITES {
+                                    cx.waker().wake_by_ref();
+                                    return Poll::Pending;
+                                }
+                                continue;
+                            }
+                            fragments.pop_front();
+
+                            // all fragments are send; go back to Normal. the
+                            // write they came from is told so when it's
+                            // polled again, if it isn't being polled now
+                            if fragments.is_empty() {
+                                let duration = _self.fragments_started.map(|t| t.elapsed());
+                                #[cfg(feature = "metrics")]
+                                record_duration(duration);
+                                _self.fragment_duration = duration;
+                                _self.state = DetourState::Normal;
+                                _self.last_fragmented = true;
+                                _self.unreported = true;
+                                _self.hello_sent();
+                                return Poll::Ready(Ok(()));
+                            }
+
+                            // some fragments are left
+                            if _self.config.flush_between {
+                                let fragments = std::mem::take(fragments);
+                                _self.state = DetourState::Flush(fragments, *written);
+                            } else if _self.config.anti_coalesce {
+                                cx.waker().wake_by_ref();
+                                return Poll::Pending;
+                            }
+                        },
+                        // the connection is as good as dead; drop the
+                        // rest, so nothing is left half sent
+                        Poll::Ready(Err(e)) => {
+                            _self.abort();
+                            return Poll::Ready(Err(e));
+                        },
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+                DetourState::Flush(fragments, written) => {
+                    // push the last fragment out before queueing the next one
+                    match sock.poll_flush(cx) {
+                        Poll::Ready(Ok(())) => {
+                            let fragments = std::mem::take(fragments);
+                            _self.state = DetourState::Send(fragments, *written);
+
+                            // give the socket a moment to send it alone
+                            if _self.config.anti_coalesce {
+                                cx.waker().wake_by_ref();
+                                return Poll::Pending;
+                            }
+                        },
+                        Poll::Ready(Err(e)) => {
+                            _self.abort();
+                            return Poll::Ready(Err(e));
+                        },
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+            }
+        }
+    }
+
+    // tell the write of the hello that it's done. one dropped while the
+    // fragments were queued may be followed by a write of something else,
+    // which mustn't be taken for written; the stream is out of step with
+    // its caller then, so nothing after it can go right either
+    fn report(&self, buf: &[u8]) -> tokio::io::Result<usize> {
+        if buf.len() == self.queued_len {
+            return Ok(buf.len());
+        }
+        Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::InvalidInput,
+            "write of the ClientHello retried with another buf",
+        ))
+    }
+
+    // tell the connector's future the hello is out, if it's waiting for it
+    fn hello_sent(&self) {
+        if let Some(phase) = &self.phase {
+            phase.set(ConnectPhase::Handshaking);
+        }
+    }
+
+    // give up on the fragments left, when the socket failed
+    fn abort(&mut self) {
+        self.state = DetourState::Normal;
+        self.fragments_started = None;
+    }
+
+    /// every handshake the connector tried to get this connection through,
+    /// this one last.
+    ///
+    /// empty unless the detour was made by an `HttpsConnector`.
+    pub fn attempts(&self) -> &[Attempt] {
+        &self.attempts
+    }
+
+    /// the hostname in the ClientHello fragmented by this detour, if any.
+    ///
+    /// this is the form sent on the wire, so an internationalized name comes
+    /// in punycode (`xn--...`); see `sni_unicode` to decode it.
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// the hostname in the ClientHello, decoded from punycode to unicode.
+    ///
+    /// `None` if there's no hostname, or if it isn't a valid domain name.
+    #[cfg(feature = "idna")]
+    pub fn sni_unicode(&self) -> Option<String> {
+        match idna::domain_to_unicode(self.sni()?) {
+            (name, Ok(())) => Some(name),
+            (_, Err(_)) => None,
+        }
+    }
+
+    /// why the ClientHello couldn't be parsed, if it couldn't.
+    ///
+    /// such a hello is still fragmented, as the [`FallbackStrategy`] says.
+    ///
+    /// [`FallbackStrategy`]: crate::FallbackStrategy
+    pub fn parse_error(&self) -> Option<ParseError> {
+        self.parse_error
+    }
+
+    /// the payload length of each record the ClientHello was cut into.
+    ///
+    /// the lengths sum up to the payload length of the original record.
+    /// empty until a hello is fragmented, and if it was sent as is.
+    pub fn fragment_sizes(&self) -> &[usize] {
+        &self.fragment_sizes
+    }
+
+    /// how the ClientHello was fragmented; `None` until a hello is
+    /// fragmented, and if it was sent as is
+    pub fn fragment_info(&self) -> Option<&FragmentInfo> {
+        self.fragment_info.as_ref()
+    }
+
+    /// why the first record was fragmented or not; `None` until one is
+    /// written, unless the detour was turned off from the start.
+    ///
+    /// until [`first_write_only`](crate::DetourBuilder::first_write_only)
+    /// finds a hello, this tells of the last write checked for one.
+    pub fn decision(&self) -> Option<DetourDecision> {
+        self.decision
+    }
+
+    /// how long it took from writing the first fragment of the ClientHello
+    /// to writing the last one.
+    ///
+    /// this is the latency fragmenting adds, mostly from `flush_between`
+    /// and `anti_coalesce`, to tune them by. `None` until all the fragments
+    /// are written, and if the hello was sent as is.
+    pub fn fragment_duration(&self) -> Option<Duration> {
+        self.fragment_duration
+    }
+
+    /// whether the last write fragmented a ClientHello; cheaper than
+    /// [`fragment_info`](Self::fragment_info) for a yes or no
+    pub fn last_write_fragmented(&self) -> bool {
+        self.last_fragmented
+    }
+
+    /// the config this detour fragments with, shared with the others made
+    /// from it
+    pub fn config(&self) -> &Arc<DetourConfig> {
+        &self.config
+    }
+
+    /// consume self, return inner socket. fragments still queued are
+    /// dropped with the detour, so flush it first
     pub fn into_inner(self) -> T {
         self.sock
     }
 }
 
+impl<T: AsyncWrite + fmt::Debug> fmt::Debug for Detour<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Detour")
+            .field("sock", &self.sock)
+            .field("state", &self.state)
+            .field("seen_hello", &self.seen_hello)
+            .field("sni", &redacted(self.sni.as_deref()))
+            .field("parse_error", &self.parse_error)
+            .field("fragment_sizes", &self.fragment_sizes)
+            .field("fragment_info", &self.fragment_info)
+            .field("decision", &self.decision)
+            .field("fragment_duration", &self.fragment_duration)
+            .field("last_fragmented", &self.last_fragmented)
+            .field("unreported", &self.unreported)
+            .field("queued_len", &self.queued_len)
+            .field("config", &self.config)
+            .field("attempt", &self.attempt)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
 // to access internal socket
 impl<T: AsyncWrite> Deref for Detour<T> {
     type Target = T;
@@ -159,88 +780,1393 @@ impl<T: AsyncWrite> DerefMut for Detour<T> {
     }
 }
 
+impl<T: AsyncWrite> AsRef<T> for Detour<T> {
+    fn as_ref(&self) -> &T {
+        &self.sock
+    }
+}
+
+impl<T: AsyncWrite> AsMut<T> for Detour<T> {
+    fn as_mut(&mut self) -> &mut T {
+        &mut self.sock
+    }
+}
+
+// same as Detour::new
+impl<T: AsyncWrite> From<T> for Detour<T> {
+    fn from(sock: T) -> Self {
+        Self::new(sock)
+    }
+}
+
+// only for a socket which is a connection itself; a detour over any other
+// is still a plain stream, just not one hyper can dial with
 impl<T: AsyncWrite + Connection> Connection for Detour<T> {
     fn connected(&self) -> Connected {
-        self.sock.connected()
+        self.sock.connected().extra(DetourInfo {
+            sni: self.sni.clone(),
+        })
+    }
+}
+
+/// what a detour saw of its connection, attached to `Connected` by it
+///
+/// hyper copies it into the extensions of every response received over the
+/// connection, like [`HttpsInfo`](crate::HttpsInfo).
+#[derive(Clone)]
+pub struct DetourInfo {
+    sni: Option<String>,
+}
+
+impl DetourInfo {
+    /// the hostname in the ClientHello of the connection, if any
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+}
+
+impl fmt::Debug for DetourInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DetourInfo").field("sni", &redacted(self.sni.as_deref())).finish()
     }
 }
 
 // handy boilerplate for generics which require both read and write
 impl<T: AsyncRead + AsyncWrite> AsyncRead for Detour<T> {
     fn poll_read(
-        self: Pin<&mut Self>, 
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>
     ) -> Poll<tokio::io::Result<()>> {
-        self.sock().poll_read(cx, buf)
+        // reads go straight through; whatever the socket filled is the
+        // caller's, untouched by the state of the write half
+        let filled = buf.filled().len();
+        let res = self.sock().poll_read(cx, buf);
+        debug_assert!(buf.filled().len() >= filled, "read took back filled bytes");
+        res
+    }
+}
+
+impl<T: AsyncBufRead + AsyncWrite> AsyncBufRead for Detour<T> {
+    fn poll_fill_buf(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<&[u8]>> {
+        self.sock().poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.sock().consume(amt)
     }
 }
 
+// show the tap what the socket took of `buf`, if it took any
+fn tapped(
+    config: &DetourConfig,
+    buf: &[u8],
+    written: Poll<tokio::io::Result<usize>>,
+) -> Poll<tokio::io::Result<usize>> {
+    if let (Some(tap), Poll::Ready(Ok(n))) = (&config.tap, &written) {
+        tap.call(&buf[..*n]);
+    }
+    written
+}
+
 impl<T: AsyncWrite> AsyncWrite for Detour<T> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8]
     ) -> Poll<tokio::io::Result<usize>> {
-        // passthrough if the message isn't client hello (need not be fragmented)
-        if !is_hello(buf) {
-            return self.sock().poll_write(cx, buf);
-        }
-
         // consume the pin out; we must not move self and its member from now on
         let _self = unsafe { self.get_unchecked_mut() };
 
-        match &_self.state {
-            // this call is the first time to be polled to send this buf
-            DetourState::Normal => {
-                // the fragments will be send in the next poll
-                let (first, second) = fragmentate(buf);
-                _self.state = DetourState::SendFirst(first, second);
-                Poll::Pending
-            },
-            DetourState::SendFirst(first, second) => {
-                // both ref_self and ref_self.sock won't move so it's safe to pin
-                let sock = unsafe { Pin::new_unchecked(&mut _self.sock) };
-
-                match sock.poll_write(cx, first) {
-                    // the second fragment is left
-                    Poll::Ready(Ok(n)) => {
-                        // can't move out of second so it's the only option...
-                        let second = second.clone();
-                        _self.state = DetourState::SendSecond(second, n);
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
-                    },
-                    others => others
-                }
+        // nothing to write, let alone to fragment
+        if buf.is_empty() {
+            _self.last_fragmented = false;
+            return Poll::Ready(Ok(0));
+        }
+
+        // a flush already sent the fragments of the hello this is retrying
+        if std::mem::take(&mut _self.unreported) {
+            return Poll::Ready(_self.report(buf));
+        }
+
+        // passthrough if the message isn't client hello (need not be fragmented),
+        // or if the handshake has already begun. once the hello is cut, the
+        // fragments are drained first, whatever the caller retries with;
+        // it's told of the hello being written only when all of them are,
+        // so it must retry with the same buf, as `write_all` does
+        let idle = matches!(_self.state, DetourState::Normal);
+        if idle && (_self.seen_hello || !is_hello(buf)) {
+            // the hello, if any, always comes first. nearly every write goes
+            // this way; keep it to a few flags, with no allocation nor copy
+            if !_self.seen_hello {
+                _self.decision = Some(DetourDecision::SkippedNotHello);
+                decide(&_self.config, DetourDecision::SkippedNotHello);
+                _self.hello_sent();
+            }
+            _self.seen_hello |= _self.config.first_write_only;
+            _self.last_fragmented = false;
+            let sock = unsafe { Pin::new_unchecked(&mut _self.sock) };
+            return tapped(&_self.config, buf, sock.poll_write(cx, buf));
+        }
+
+        // this call is the first time to be polled to send this buf
+        if let DetourState::Normal = _self.state {
+            let plan = plan(&_self.config, _self.attempt, buf);
+            if let Some(e) = plan.error() {
+                return Poll::Ready(Err(e));
+            }
+            _self.sni = plan.sni;
+            _self.parse_error = plan.parse_error;
+            _self.decision = plan.decision;
+            _self.seen_hello = true;
+
+            match plan.fragments {
+                Some(fragments) => {
+                    _self.fragment_sizes = plan.fragment_sizes;
+                    _self.fragment_info = plan.info;
+                    _self.fragments_started = Some(Instant::now());
+                    _self.queued_len = buf.len();
+                    _self.state = DetourState::Send(fragments, 0);
+                },
+                // configured not to fragment this one, or not this host
+                None => {
+                    _self.last_fragmented = false;
+                    _self.hello_sent();
+                    let sock = unsafe { Pin::new_unchecked(&mut _self.sock) };
+                    return tapped(&_self.config, buf, sock.poll_write(cx, buf));
+                },
             }
-            DetourState::SendSecond(second, written) => {
-                let sock = unsafe { Pin::new_unchecked(&mut _self.sock) };
-
-                match sock.poll_write(cx, &second) {
-                    // all fragments are send; go back to Normal
-                    Poll::Ready(Ok(n)) => {
-                        let res = written + n;
-                        _self.state = DetourState::Normal;
-                        Poll::Ready(Ok(res))
-                    },
-                    others => others
-                }
+        }
+
+        // the caller only knows of its own buf, not of the headers we've added
+        let drained = unsafe { Pin::new_unchecked(&mut *_self) }.poll_drain(cx);
+        match drained {
+            Poll::Ready(Ok(())) => {
+                _self.unreported = false;
+                Poll::Ready(_self.report(buf))
             },
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
         }
     }
 
     fn poll_flush(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        self.sock().poll_flush(cx)
+        // a flush means everything written so far, fragments included
+        match self.as_mut().poll_drain(cx) {
+            Poll::Ready(Ok(())) => self.sock().poll_flush(cx),
+            others => others,
+        }
     }
 
     fn poll_shutdown(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        self.sock().poll_shutdown(cx)
+        match self.as_mut().poll_drain(cx) {
+            Poll::Ready(Ok(())) => self.sock().poll_shutdown(cx),
+            others => others,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Wake, Waker};
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::config::DetourBuilder;
+    use crate::mock::{anonymous_hello, hello, Event, Mock};
+    use crate::strategy::FallbackStrategy;
+
+    #[cfg(feature = "metrics")]
+    use metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata};
+    #[cfg(feature = "metrics")]
+    use metrics::{SharedString, Unit};
+    #[cfg(feature = "metrics")]
+    use std::sync::{atomic::AtomicU64, Mutex};
+
+    // the records a default detour cuts `hello` into
+    fn fragments(hello: &[u8]) -> Vec<Vec<u8>> {
+        fragment_record(hello, SplitStrategy::default()).unwrap()
+    }
+
+    #[test]
+    fn handshake_header() {
+        let hello = hello("example.com");
+        let fragments = fragmentate(&hello, &[9, 100]);
+
+        // only the first fragment holds the handshake header, as it was;
+        // the others go on right after the cut
+        assert_eq!(fragments[0][5..], hello[5..9]);
+        assert_eq!(fragments[1][5..], hello[9..100]);
+        assert_eq!(fragments[2][5..], hello[100..]);
+        for fragment in &fragments {
+            assert_eq!(fragment[..3], hello[..3]);
+            let len = u16::from_be_bytes([fragment[3], fragment[4]]);
+            assert_eq!(usize::from(len), fragment.len() - 5);
+        }
+    }
+
+    #[test]
+    fn reassembled() {
+        let hello = hello("example.com");
+        let strategies = [
+            SplitStrategy::SniMidpoint,
+            SplitStrategy::BeforeSni,
+            SplitStrategy::FixedOffset(1),
+            SplitStrategy::FromEnd(3),
+            SplitStrategy::EveryByte,
+        ];
+
+        // joined at the record layer, every strategy's fragments make one
+        // handshake message, as long as its header says
+        for strategy in strategies {
+            let fragments = fragment_record(&hello, strategy.clone()).unwrap();
+            let payload: Vec<_> = fragments.iter().flat_map(|f| &f[5..]).copied().collect();
+            assert_eq!(payload[0], 0x01, "{:?}", strategy);
+            let len = u32::from_be_bytes([0, payload[1], payload[2], payload[3]]);
+            assert_eq!(len as usize, payload.len() - 4, "{:?}", strategy);
+            assert_eq!(payload, hello[5..], "{:?}", strategy);
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_between_fragments() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+        assert_eq!(fragments.len(), 2);
+
+        for enable in [false, true] {
+            let mut detour = DetourBuilder::new().flush_between(enable).build(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+
+            let mut expected = vec![Event::Write(fragments[0].clone())];
+            if enable {
+                expected.push(Event::Flush);
+            }
+            expected.push(Event::Write(fragments[1].clone()));
+            assert_eq!(detour.events, expected);
+        }
+    }
+
+    // a dummy change_cipher_spec, as tls 1.3 sends right after the hello
+    const CCS: [u8; 6] = [0x14, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+    #[tokio::test]
+    async fn ccs_after_hello() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // written along with the hello, it follows the last fragment whole
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&[&hello[..], &CCS].concat()).await.unwrap();
+        let last = [&fragments[1][..], &CCS].concat();
+        assert_eq!(detour.events, vec![Event::Write(fragments[0].clone()), Event::Write(last)]);
+
+        // written on its own, it goes out as is
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        detour.write_all(&CCS).await.unwrap();
+        assert_eq!(detour.events.len(), 3);
+        assert_eq!(detour.events[2], Event::Write(CCS.to_vec()));
+        assert_eq!(detour.fragment_sizes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn last_write_fragmented() {
+        let hello = hello("example.com");
+
+        // set after the hello, and cleared by every write after it,
+        // empty or plaintext
+        let mut detour = Detour::new(Mock::new());
+        assert!(!detour.last_write_fragmented());
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.last_write_fragmented());
+        assert_eq!(detour.write(&[]).await.unwrap(), 0);
+        assert!(!detour.last_write_fragmented());
+
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        detour.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(!detour.last_write_fragmented());
+
+        // never set for a plaintext connection
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(!detour.last_write_fragmented());
+    }
+
+    #[tokio::test]
+    async fn one_hello_per_connection() {
+        let hello = hello("example.com");
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.last_write_fragmented());
+
+        // a connection reused from the pool doesn't handshake again; even
+        // a write looking like a hello goes out as is
+        detour.write_all(&hello).await.unwrap();
+        assert!(!detour.last_write_fragmented());
+        assert_eq!(detour.events.len(), 3);
+        assert_eq!(detour.events[2], Event::Write(hello));
+        assert_eq!(detour.fragment_sizes().len(), 2);
+    }
+
+    #[cfg(not(feature = "debug-sni"))]
+    #[tokio::test]
+    async fn sni_redacted() {
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello("example.com")).await.unwrap();
+
+        // once for the detour, once for its FragmentInfo
+        let debug = format!("{:?}", detour);
+        assert!(!debug.contains("example.com"));
+        assert_eq!(debug.matches("<redacted>").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn identity() {
+        // the hello goes out as it is, but told of as fragmented into one
+        let hello = hello("example.com");
+        let mut detour = DetourBuilder::new().strategy(SplitStrategy::Identity).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.events, vec![Event::Write(hello.clone())]);
+        assert_eq!(detour.decision(), Some(DetourDecision::Fragmented));
+        assert!(detour.last_write_fragmented());
+        assert_eq!(detour.fragment_sizes(), [hello.len() - 5]);
+        let info = detour.fragment_info().unwrap();
+        assert_eq!((info.fragment_count, &info.split_points[..]), (1, &[][..]));
+    }
+
+    #[tokio::test]
+    async fn max_fragments() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+        builder.strategy(SplitStrategy::EveryByte);
+
+        // a hundred cuts and more, merged into 16 records
+        let mut detour = builder.build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.events.len(), 16);
+        assert_eq!(detour.fragment_sizes().len(), 16);
+
+        // a single record is no fragmenting at all
+        let mut detour = builder.max_fragments(1).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.events, vec![Event::Write(hello.clone())]);
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedSingleFragment));
+        assert!(!detour.last_write_fragmented());
+        assert!(detour.fragment_info().is_none());
+    }
+
+    #[tokio::test]
+    async fn short_writes() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // the write is told of the hello once both fragments are out
+        let mut detour = Detour::new(Mock::short(7));
+        assert_eq!(detour.write(&hello).await.unwrap(), hello.len());
+        assert_eq!(detour.written(), fragments.concat());
+
+        // each fragment is written to its end before the next one starts
+        let writes = detour.writes();
+        assert!(writes.iter().all(|write| write.len() <= 7));
+        let ends: Vec<_> = writes
+            .iter()
+            .scan(0, |end, write| {
+                *end += write.len();
+                Some(*end)
+            })
+            .collect();
+        assert!(ends.contains(&fragments[0].len()));
+    }
+
+    // poll a write of `buf` once, and drop it
+    fn poll_once(detour: &mut Detour<Mock>, buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        Pin::new(detour).poll_write(&mut cx, buf)
+    }
+
+    #[tokio::test]
+    async fn drain_before_flush() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // the write is dropped before the socket took anything
+        let mut detour = Detour::new(Mock::pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert!(detour.events.is_empty());
+
+        // the flush sends the fragments first, and only then flushes
+        detour.flush().await.unwrap();
+        let mut expected: Vec<_> = fragments.iter().cloned().map(Event::Write).collect();
+        expected.push(Event::Flush);
+        assert_eq!(detour.events, expected);
+
+        // the write retried is told of the hello, with nothing sent again
+        assert_eq!(detour.write(&hello).await.unwrap(), hello.len());
+        assert_eq!(detour.events.len(), 3);
+        assert!(detour.last_write_fragmented());
+    }
+
+    #[test]
+    fn pending_first_fragment() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // the socket isn't ready for the first fragment; the same write is
+        // polled again until the whole hello is out, and told of it once
+        let mut detour = Detour::new(Mock::pending());
+        let mut polls = 0;
+        let n = loop {
+            polls += 1;
+            match poll_once(&mut detour, &hello) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => assert!(polls < 100),
+            }
+        };
+        assert_eq!(n, hello.len());
+        assert!(polls > fragments.len());
+        assert_eq!(detour.writes(), fragments.iter().map(Vec::as_slice).collect::<Vec<_>>());
+
+        // and what follows goes out right after, only once too
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        while poll_once(&mut detour, request).is_pending() {}
+        assert_eq!(detour.written(), [fragments.concat(), request.to_vec()].concat());
+    }
+
+    #[tokio::test]
+    async fn fragment_duration() {
+        let hello = hello("example.com");
+
+        // none until the last fragment is out, the socket giving way
+        // between them
+        let mut detour = Detour::new(Mock::pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert_eq!(detour.fragment_duration(), None);
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.fragment_duration().is_some());
+
+        // nor for a write sent as is
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(detour.fragment_duration(), None);
+    }
+
+    #[tokio::test]
+    async fn failed_fragment() {
+        let hello = hello("example.com");
+
+        // the socket takes nothing of the first fragment
+        let mut detour = Detour::new(Mock::short(0));
+        let e = detour.write(&hello).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::WriteZero);
+
+        // the rest is dropped: nothing is left to drain, and a write after
+        // goes out as is
+        detour.max_write = None;
+        detour.flush().await.unwrap();
+        detour.write_all(b"more").await.unwrap();
+        assert_eq!(detour.written(), b"more");
+        assert_eq!(detour.events.last(), Some(&Event::Write(b"more".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn drain_contract() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // Pending keeps the fragments left, to go on with them
+        let mut detour = Detour::new(Mock::pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert_eq!(detour.written(), fragments[0]);
+
+        // an error drops them, the last one never half sent
+        detour.pending = false;
+        detour.max_write = Some(0);
+        match poll_once(&mut detour, &hello) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), tokio::io::ErrorKind::WriteZero),
+            poll => panic!("{:?}", poll),
+        }
+        assert_eq!(detour.fragment_duration(), None);
+
+        detour.max_write = None;
+        detour.write_all(b"more").await.unwrap();
+        assert_eq!(detour.written(), [&fragments[0][..], b"more"].concat());
+        assert_eq!(detour.fragment_duration(), None);
+    }
+
+    #[tokio::test]
+    async fn retried_with_another_buf() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // dropped in the middle of the first fragment
+        let mut detour = Detour::new(Mock::short(7));
+        detour.pending = true;
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        assert_eq!(detour.written(), fragments[0][..7]);
+
+        // another write still gets the hello out whole, but fails
+        let e = detour.write(b"GET / HTTP/1.1\r\n\r\n").await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::InvalidInput);
+        assert_eq!(detour.written(), fragments.concat());
+
+        // the same, after a flush sent the fragments
+        let mut detour = Detour::new(Mock::pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        detour.flush().await.unwrap();
+        let e = detour.write(&hello[..10]).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tiny_payloads() {
+        let strategies = [
+            SplitStrategy::FixedOffset(0),
+            SplitStrategy::FromEnd(5),
+            SplitStrategy::Random,
+            SplitStrategy::EveryByte,
+        ];
+        let record = |payload: &[u8]| {
+            [&[0x17, 0x03, 0x03, 0x00, payload.len() as u8][..], payload].concat()
+        };
+
+        // with nowhere to cut, the record is left whole
+        for payload in [&[][..], &[0xaa]] {
+            for strategy in strategies.iter().cloned() {
+                let record = record(payload);
+                assert_eq!(fragment_record(&record, strategy).unwrap(), vec![record]);
+            }
+        }
+
+        // two bytes go one to each fragment, whatever the strategy
+        for strategy in strategies.iter().cloned() {
+            let fragments = fragment_record(&record(&[0xaa, 0xbb]), strategy).unwrap();
+            assert_eq!(fragments, vec![record(&[0xaa]), record(&[0xbb])]);
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_error() {
+        // a legacy_session_id running past the record
+        let hello = hello("example.com");
+        let mut bad = hello.clone();
+        bad[43] = 0xff;
+
+        // the fallback still cuts it
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&bad).await.unwrap();
+        assert_eq!(detour.parse_error(), Some(ParseError::LengthOverflow(43)));
+        assert_eq!(detour.decision(), Some(DetourDecision::Fragmented));
+        let payload_len = bad.len() - 5;
+        assert_eq!(detour.fragment_sizes(), [payload_len / 2, payload_len - payload_len / 2]);
+
+        // unless it's told not to
+        let mut builder = DetourBuilder::new();
+        let mut detour = builder.fallback(FallbackStrategy::Passthrough).build(Mock::new());
+        detour.write_all(&bad).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedParseError));
+        assert_eq!(detour.written(), bad);
+
+        // a well-formed hello has none
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.parse_error(), None);
+    }
+
+    #[cfg(feature = "idna")]
+    #[tokio::test]
+    async fn sni_unicode() {
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello("xn--bcher-kva.example")).await.unwrap();
+        assert_eq!(detour.sni(), Some("xn--bcher-kva.example"));
+        assert_eq!(detour.sni_unicode().as_deref(), Some("bücher.example"));
+
+        // punycode that doesn't decode
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello("xn--a.example")).await.unwrap();
+        assert_eq!(detour.sni_unicode(), None);
+    }
+
+    #[test]
+    fn anti_coalesce() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+        let first = Event::Write(fragments[0].clone());
+        let second = Event::Write(fragments[1].clone());
+
+        // the write yields after the first fragment, and after the flush
+        // following it if there's one
+        for flush in [false, true] {
+            let mut builder = DetourBuilder::new();
+            let mut detour = builder.anti_coalesce(true).flush_between(flush).build(Mock::new());
+            assert!(poll_once(&mut detour, &hello).is_pending());
+            let mut expected = vec![first.clone()];
+            if flush {
+                expected.push(Event::Flush);
+            }
+            assert_eq!(detour.events, expected);
+
+            let poll = poll_once(&mut detour, &hello);
+            assert!(matches!(poll, Poll::Ready(Ok(n)) if n == hello.len()));
+            expected.push(second.clone());
+            assert_eq!(detour.events, expected);
+        }
+
+        // and goes on right away without
+        let mut detour = Detour::new(Mock::new());
+        assert!(poll_once(&mut detour, &hello).is_ready());
+        assert_eq!(detour.events, [first, second]);
+    }
+
+    #[tokio::test]
+    async fn single_write() {
+        let hello = hello("example.com");
+        let records = fragments(&hello).concat();
+
+        // the fragments go out together, even with flushes asked for
+        let mut builder = DetourBuilder::new();
+        let mut detour = builder.single_write(true).flush_between(true).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.events, [Event::Write(records.clone())]);
+        assert_eq!(detour.fragment_sizes().len(), 2);
+
+        // a socket taking less gets the rest in the writes after
+        let mut detour = builder.build(Mock::short(100));
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.written(), records);
+        assert_eq!(detour.events[0], Event::Write(records[..100].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn strict_splits() {
+        let hello = hello("example.com");
+        let beyond = hello.len() + 1;
+        let strategy = SplitStrategy::Fn(Arc::new(move |_| vec![10, beyond]));
+        let mut builder = DetourBuilder::new();
+        builder.strategy(strategy);
+
+        // a cut out of the record is dropped
+        let mut detour = builder.build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.fragment_sizes(), [5, hello.len() - 10]);
+
+        // or fails the write, with nothing sent
+        let mut detour = builder.strict_splits(true).build(Mock::new());
+        let e = detour.write_all(&hello).await.unwrap_err();
+        assert_eq!(e.kind(), tokio::io::ErrorKind::InvalidInput);
+        let e = e.get_ref().and_then(|e| e.downcast_ref::<ParseError>());
+        assert_eq!(e, Some(&ParseError::InvalidSplit(beyond)));
+        assert!(detour.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_write_only() {
+        let hello = hello("example.com");
+
+        // a hello after anything else goes out as is by default, and is
+        // cut otherwise
+        for (enable, fragmented) in [(true, false), (false, true)] {
+            let mut detour = DetourBuilder::new().first_write_only(enable).build(Mock::new());
+            detour.write_all(b"STARTTLS\r\n").await.unwrap();
+            detour.write_all(&hello).await.unwrap();
+            assert_eq!(detour.last_write_fragmented(), fragmented, "{}", enable);
+            assert_eq!(detour.writes().len(), if fragmented { 3 } else { 2 });
+        }
+    }
+
+    #[tokio::test]
+    async fn on_decision() {
+        let hello = hello("example.com");
+        let decisions = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let told = decisions.clone();
+        let mut builder = DetourBuilder::new();
+        builder.on_decision(move |decision| told.lock().unwrap().push(decision));
+
+        // only the first write is looked at by default
+        let mut detour = builder.build(Mock::new());
+        detour.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(*decisions.lock().unwrap(), [DetourDecision::SkippedNotHello]);
+
+        // otherwise every one until the hello is, and none after it
+        decisions.lock().unwrap().clear();
+        let mut detour = builder.first_write_only(false).build(Mock::new());
+        for buf in [&b"EHLO"[..], b"STARTTLS", &hello, &hello] {
+            detour.write_all(buf).await.unwrap();
+        }
+        let expected = [
+            DetourDecision::SkippedNotHello,
+            DetourDecision::SkippedNotHello,
+            DetourDecision::Fragmented,
+        ];
+        assert_eq!(*decisions.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn max_overhead_bytes() {
+        let hello = hello("example.com");
+        let mut builder = DetourBuilder::new();
+        builder.strategy(SplitStrategy::EveryByte).max_fragments(usize::MAX);
+
+        // a header of 5 bytes for each record after the first
+        for (bytes, records) in [(15, 4), (19, 4), (20, 5), (5, 2)] {
+            let mut detour = builder.max_overhead_bytes(bytes).build(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+            assert_eq!(detour.fragment_sizes().len(), records, "{}", bytes);
+            assert!(detour.written().len() - hello.len() <= bytes);
+        }
+
+        // the lower of both caps applies
+        let mut detour = builder.max_overhead_bytes(100).max_fragments(3).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.fragment_sizes().len(), 3);
+
+        // and with no room for one more header, the hello goes out whole
+        let mut detour = builder.max_overhead_bytes(4).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedSingleFragment));
+        assert_eq!(detour.written(), hello);
+    }
+
+    #[test]
+    fn queued_fragments_redacted() {
+        let hello = hello("example.com");
+        let lens: Vec<_> = fragments(&hello).iter().map(Vec::len).collect();
+
+        // the socket took nothing yet, so every fragment is queued; their
+        // lengths show
+        let mut detour = Detour::new(Mock::pending());
+        assert!(poll_once(&mut detour, &hello).is_pending());
+        let debug = format!("{:?}", detour);
+        assert!(debug.contains(&format!("Send {{ fragment_lens: {:?}, written: 0 }}", lens)));
+
+        // but not their bytes, from the record header on, nor the hostname
+        // unless asked for
+        let header = format!("{}, {}, {}", hello[0], hello[1], hello[2]);
+        assert!(!debug.contains(&header));
+        #[cfg(not(feature = "debug-sni"))]
+        assert!(!debug.contains("example.com"));
+    }
+
+    #[tokio::test]
+    async fn almost_hello() {
+        let hello = hello("example.com");
+        let hello_len = hello.len() - 9;
+
+        // a ServerHello, and hellos claiming to be longer than the record,
+        // or shorter than a hello can be
+        let mut records = Vec::new();
+        let mut record = hello.clone();
+        record[5] = 0x02;
+        records.push(record);
+        for len in [hello_len + 1, 40] {
+            let mut record = hello.clone();
+            record[6..9].copy_from_slice(&(len as u32).to_be_bytes()[1..]);
+            records.push(record);
+        }
+
+        for record in records {
+            let mut detour = Detour::new(Mock::new());
+            detour.write_all(&record).await.unwrap();
+            assert_eq!(detour.decision(), Some(DetourDecision::SkippedNotHello));
+            assert_eq!(detour.events, [Event::Write(record)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn plaintext() {
+        // a request sent in the clear, in one write and byte by byte
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&request).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedNotHello));
+        assert_eq!(detour.events, [Event::Write(request.clone())]);
+
+        let mut detour = Detour::new(Mock::new());
+        for byte in &request {
+            detour.write_all(&[*byte]).await.unwrap();
+        }
+        assert_eq!(detour.written(), request);
+    }
+
+    #[tokio::test]
+    async fn passthrough() {
+        // the hello goes out whole, as would any write after it
+        let hello = hello("example.com");
+        let mut detour = Detour::passthrough(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.fragment_sizes().is_empty());
+        assert_eq!(detour.events, [Event::Write(hello.clone()), Event::Write(hello)]);
+    }
+
+    #[tokio::test]
+    async fn oversized_record() {
+        // a record as long as its length can claim, past the limit of the
+        // spec; it goes out as is rather than being held on to
+        let mut record = hello("example.com");
+        record[3..5].copy_from_slice(&u16::MAX.to_be_bytes());
+        record.resize(5 + usize::from(u16::MAX), 0);
+
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&record).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedNotHello));
+        assert_eq!(detour.events, [Event::Write(record)]);
+    }
+
+    #[tokio::test]
+    async fn fragment_record_limit() {
+        // a record claiming more than the spec allows isn't cut at all,
+        // even with every byte it claims there
+        let mut record = hello("example.com");
+        record[3..5].copy_from_slice(&(MAX_RECORD_LEN as u16 + 1).to_be_bytes());
+        record.resize(6 + MAX_RECORD_LEN, 0);
+        let fragments = fragment_record(&record, SplitStrategy::FixedOffset(10));
+        assert_eq!(fragments, Err(ParseError::LengthOverflow(3)));
+
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&record).await.unwrap();
+        assert!(detour.fragment_sizes().is_empty());
+        assert_eq!(detour.events, [Event::Write(record)]);
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let hello = anonymous_hello();
+        let payload_len = hello.len() - 5;
+
+        for (fallback, first) in [
+            (FallbackStrategy::Midpoint, payload_len / 2),
+            (FallbackStrategy::Fixed(3), 3),
+            // clamped so that both fragments carry some payload
+            (FallbackStrategy::Fixed(0), 1),
+            (FallbackStrategy::Fixed(payload_len), payload_len - 1),
+        ] {
+            let mut detour = DetourBuilder::new().fallback(fallback).build(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+            assert_eq!(detour.fragment_sizes(), [first, payload_len - first], "{:?}", fallback);
+        }
+
+        let mut builder = DetourBuilder::new();
+        let mut detour = builder.fallback(FallbackStrategy::Passthrough).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedNoSni));
+        assert_eq!(detour.events, [Event::Write(hello)]);
+    }
+
+    #[tokio::test]
+    async fn fragment_sizes() {
+        let hello = hello("example.com");
+        let strategies = [
+            SplitStrategy::SniMidpoint,
+            SplitStrategy::Random,
+            SplitStrategy::EveryByte,
+            SplitStrategy::FromEnd(3),
+        ];
+
+        // the payload of each record written, adding up to the hello's
+        for strategy in strategies.iter() {
+            let mut detour = DetourBuilder::new().strategy(strategy.clone()).build(Mock::new());
+            assert!(detour.fragment_sizes().is_empty());
+            detour.write_all(&hello).await.unwrap();
+            let sizes = detour.fragment_sizes();
+            assert_eq!(sizes.iter().sum::<usize>(), hello.len() - 5, "{:?}", strategy);
+            assert_eq!(detour.written().len(), hello.len() + 5 * (sizes.len() - 1));
+        }
+
+        // and none for a hello sent as is
+        let mut detour = Detour::passthrough(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.fragment_sizes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn write_only() {
+        // Mock has no AsyncRead; every constructor, and the socket under
+        // the detour, are there all the same
+        let config = DetourBuilder::new().build_shared();
+        for mut detour in [
+            Detour::new(Mock::new()),
+            Detour::passthrough(Mock::new()),
+            Detour::from_config(Mock::new(), config),
+        ] {
+            detour.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            detour.max_write = Some(1);
+            assert_eq!(detour.written(), b"GET / HTTP/1.1\r\n\r\n");
+            assert_eq!(detour.into_inner().max_write, Some(1));
+        }
+    }
+
+    #[test]
+    fn shared_config() {
+        // every detour of a pool holds the one config, not a copy of it
+        let config = DetourBuilder::new().max_fragments(4).build_shared();
+        let detours: Vec<_> =
+            (0..64).map(|_| Detour::from_config(Mock::new(), config.clone())).collect();
+        assert!(detours.iter().all(|detour| Arc::ptr_eq(detour.config(), &config)));
+        assert_eq!(Arc::strong_count(&config), 65);
+
+        // a detour of its own has a config of its own
+        assert!(!Arc::ptr_eq(Detour::new(Mock::new()).config(), &config));
+    }
+
+    #[tokio::test]
+    async fn reads_forwarded() {
+        use tokio::io::AsyncReadExt;
+
+        let (client, mut server) = tokio::io::duplex(64);
+        server.write_all(b"response").await.unwrap();
+
+        // a read after what the buffer already holds adds exactly what the
+        // socket gave, and leaves the rest of the buffer alone
+        let mut detour = Detour::new(client);
+        let mut buf = [0; 16];
+        let mut read = ReadBuf::new(&mut buf);
+        read.put_slice(b"head");
+        std::future::poll_fn(|cx| Pin::new(&mut detour).poll_read(cx, &mut read)).await.unwrap();
+        assert_eq!(read.filled(), b"headresponse");
+
+        drop(server);
+        assert_eq!(detour.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn buffered_lines() {
+        use tokio::io::{AsyncBufReadExt, BufStream};
+
+        let (near, mut far) = tokio::io::duplex(64);
+        far.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        let mut detour = Detour::new(BufStream::new(near));
+
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while detour.read_line(&mut line).await.unwrap() > 2 {
+            lines.push(std::mem::take(&mut line));
+        }
+        assert_eq!(lines, ["HTTP/1.1 200 OK\r\n", "Content-Length: 0\r\n"]);
+    }
+
+    #[tokio::test]
+    async fn empty_write() {
+        let hello = hello("example.com");
+
+        // it's neither forwarded, nor taken for the first write
+        let mut detour = Detour::new(Mock::new());
+        assert!(matches!(poll_once(&mut detour, &[]), Poll::Ready(Ok(0))));
+        assert!(detour.events.is_empty());
+        assert_eq!(detour.decision(), None);
+
+        detour.write_all(&hello).await.unwrap();
+        assert!(detour.last_write_fragmented());
+        assert_eq!(detour.events.len(), 2);
+
+        // the same after the hello
+        assert!(matches!(poll_once(&mut detour, &[]), Poll::Ready(Ok(0))));
+        assert_eq!(detour.events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn no_empty_fragments() {
+        let hello = hello("example.com");
+        let len = hello.len();
+
+        // cuts at either end of the payload, or twice at one place, would
+        // each leave a record without payload; they're dropped instead
+        for (cuts, sizes) in [
+            (vec![5, 10, 10, len], vec![5, len - 10]),
+            (vec![len, 6], vec![1, len - 6]),
+        ] {
+            let strategy = SplitStrategy::Fn(Arc::new(move |_| cuts.clone()));
+            let mut detour = DetourBuilder::new().strategy(strategy).build(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+            assert_eq!(detour.fragment_sizes(), sizes);
+        }
+    }
+
+    // a waker counting how many times it's woken
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref()
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn bounded_short_writes() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+        let wakes = Arc::new(Wakes::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // a byte a write; each poll takes a few, and wakes the task again
+        let mut detour = Detour::new(Mock::short(1));
+        let (mut polls, mut writes) = (0, 0);
+        while Pin::new(&mut detour).poll_write(&mut cx, &hello).is_pending() {
+            polls += 1;
+            // the byte ending a fragment isn't a short write
+            let taken = detour.writes().len() - writes;
+            assert!((MAX_SHORT_WRITES..=MAX_SHORT_WRITES + 1).contains(&taken));
+            assert_eq!(wakes.0.load(Ordering::Relaxed), polls);
+            writes += taken;
+        }
+
+        // and at last, every byte is out once
+        assert_eq!(detour.written(), fragments.concat());
+        assert!(polls >= hello.len() / MAX_SHORT_WRITES);
+    }
+
+    #[test]
+    fn no_busy_polls() {
+        let hello = hello("example.com");
+        let wakes = Arc::new(Wakes::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let woken = || wakes.0.load(Ordering::Relaxed);
+
+        // a ready socket takes every fragment in the one poll, with no wake
+        let mut detour = Detour::new(Mock::new());
+        let poll = Pin::new(&mut detour).poll_write(&mut cx, &hello);
+        assert!(matches!(poll, Poll::Ready(Ok(n)) if n == hello.len()));
+        assert_eq!(detour.writes().len(), 2);
+        assert_eq!(woken(), 0);
+
+        // one which isn't is woken by the socket alone, once a Pending
+        let mut detour = Detour::new(Mock::pending());
+        let mut polls = 1;
+        while Pin::new(&mut detour).poll_write(&mut cx, &hello).is_pending() {
+            polls += 1;
+        }
+        assert_eq!(woken(), polls - 1);
+    }
+
+    #[tokio::test]
+    async fn record_versions() {
+        // tls 1.0, as most hellos have for compatibility, and tls 1.2
+        for version in [[0x03, 0x01], [0x03, 0x03]] {
+            let mut hello = hello("example.com");
+            hello[1..3].copy_from_slice(&version);
+
+            let mut detour = Detour::new(Mock::new());
+            detour.write_all(&hello).await.unwrap();
+            assert!(detour.last_write_fragmented());
+            for record in detour.writes() {
+                assert_eq!(record[..3], [0x16, version[0], version[1]]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn message_after_hello() {
+        // another handshake message in the record of the hello; the
+        // extensions as the hello's length says end before it, so the
+        // message bytes aren't read as one of them
+        let hello = hello("example.com");
+        let message = [0x0f, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00];
+        let mut record = [&hello[..], &message].concat();
+        let len = (record.len() - 5) as u16;
+        record[3..5].copy_from_slice(&len.to_be_bytes());
+
+        let mut alone = Detour::new(Mock::new());
+        alone.write_all(&hello).await.unwrap();
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&record).await.unwrap();
+        assert_eq!(detour.sni(), Some("example.com"));
+        assert_eq!(detour.parse_error(), None);
+
+        // cut where the hello alone is, the message going with the rest
+        let sizes = detour.fragment_sizes();
+        assert_eq!(sizes[0], alone.fragment_sizes()[0]);
+        assert_eq!(sizes[1], alone.fragment_sizes()[1] + message.len());
+    }
+
+    #[tokio::test]
+    async fn early_data() {
+        // 0-RTT application data written along with the hello; only the
+        // hello is cut, the early data going out whole after it
+        let hello = hello("example.com");
+        let early = [0x17, 0x03, 0x03, 0x00, 0x04, 0xaa, 0xbb, 0xcc, 0xdd];
+
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&[&hello[..], &early].concat()).await.unwrap();
+        let sizes: Vec<_> = fragments(&hello).iter().map(|f| f.len() - 5).collect();
+        assert_eq!(detour.fragment_sizes(), sizes);
+        assert_eq!(detour.written(), [fragments(&hello).concat(), early.to_vec()].concat());
+        // it may share the write of the last fragment, but stays one record
+        assert!(detour.writes().last().unwrap().ends_with(&early));
+    }
+
+    #[tokio::test]
+    async fn dtls_passthrough() {
+        // a dtls 1.2 ClientHello record: epoch and sequence number after the
+        // version, then a handshake header with its own sequence and offset
+        let hello = hello("example.com");
+        let body = &hello[9..];
+        let mut record = vec![0x16, 0xfe, 0xfd, 0, 0, 0, 0, 0, 0, 0, 0];
+        record.extend_from_slice(&(12 + body.len() as u16).to_be_bytes());
+        let len = (body.len() as u32).to_be_bytes();
+        record.extend_from_slice(&[0x01, len[1], len[2], len[3], 0, 0, 0, 0, 0]);
+        record.extend_from_slice(&len[1..]);
+        record.extend_from_slice(body);
+
+        // and a tls hello but for the version, that of dtls 1.0; the version
+        // alone tells them apart
+        let mut version = hello.clone();
+        version[1..3].copy_from_slice(&[0xfe, 0xff]);
+
+        for record in [record, version] {
+            let mut detour = Detour::new(Mock::new());
+            detour.write_all(&record).await.unwrap();
+            assert_eq!(detour.events, [Event::Write(record)]);
+            assert_eq!(detour.decision(), Some(DetourDecision::SkippedNotHello));
+        }
+    }
+
+    #[tokio::test]
+    async fn pre_fragmented() {
+        // a hello cut into records by a layer above already; none of them
+        // holds the whole hello, so each goes out as it is
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&fragments.concat()).await.unwrap();
+        assert_eq!(detour.decision(), Some(DetourDecision::SkippedNotHello));
+        assert_eq!(detour.events, [Event::Write(fragments.concat())]);
+
+        let mut detour = DetourBuilder::new().first_write_only(false).build(Mock::new());
+        for fragment in &fragments {
+            detour.write_all(fragment).await.unwrap();
+        }
+        assert!(detour.fragment_sizes().is_empty());
+        assert_eq!(detour.writes(), fragments.iter().map(Vec::as_slice).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn fragment_info() {
+        let hello = hello("example.com");
+        let len = hello.len();
+
+        for (strategy, split_points) in [
+            (SplitStrategy::FixedOffset(10), vec![15]),
+            (SplitStrategy::FromEnd(4), vec![len - 4]),
+        ] {
+            let told = Arc::new(std::sync::Mutex::new(None));
+            let on_fragment = told.clone();
+            let mut builder = DetourBuilder::new();
+            builder.strategy(strategy).on_fragment(move |info| {
+                *on_fragment.lock().unwrap() = Some(info.clone());
+            });
+            let mut detour = builder.build(Mock::new());
+            assert_eq!(detour.fragment_info(), None);
+            detour.write_all(&hello).await.unwrap();
+
+            let expected = FragmentInfo {
+                sni: Some("example.com".to_owned()),
+                record_len: len,
+                split_points,
+                fragment_count: 2,
+                attempt: 0,
+            };
+            assert_eq!(detour.fragment_info(), Some(&expected));
+            assert_eq!(told.lock().unwrap().as_ref(), Some(&expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn conversions() {
+        let hello = hello("example.com");
+        let mut detour: Detour<_> = Mock::new().into();
+        detour.write_all(&hello).await.unwrap();
+
+        // the same socket as through deref, and fragmenting as Detour::new
+        assert!(std::ptr::eq(detour.as_ref(), &*detour));
+        assert_eq!(detour.as_ref().written(), fragments(&hello).concat());
+        detour.as_mut().max_write = Some(1);
+        assert_eq!(detour.max_write, Some(1));
+    }
+
+    // a recorder keeping the counters and the histogram samples by name
+    #[cfg(feature = "metrics")]
+    #[derive(Default)]
+    struct Recorder {
+        counters: Mutex<Vec<(String, Arc<AtomicU64>)>>,
+        histograms: Mutex<Vec<(String, Arc<Samples>)>>,
+    }
+
+    #[cfg(feature = "metrics")]
+    #[derive(Default)]
+    struct Samples(Mutex<Vec<f64>>);
+
+    #[cfg(feature = "metrics")]
+    impl HistogramFn for Samples {
+        fn record(&self, value: f64) {
+            self.0.lock().unwrap().push(value);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl Recorder {
+        fn counter(&self, name: &str) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            let counter = counters.iter().find(|(n, _)| n == name);
+            counter.map_or(0, |(_, value)| value.load(Ordering::Relaxed))
+        }
+
+        fn samples(&self, name: &str) -> Vec<f64> {
+            let histograms = self.histograms.lock().unwrap();
+            let histogram = histograms.iter().find(|(n, _)| n == name);
+            histogram.map_or_else(Vec::new, |(_, samples)| samples.0.lock().unwrap().clone())
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    impl metrics::Recorder for Recorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let mut counters = self.counters.lock().unwrap();
+            let counter = match counters.iter().find(|(name, _)| name == key.name()) {
+                Some((_, counter)) => counter.clone(),
+                None => {
+                    let counter = Arc::default();
+                    counters.push((key.name().to_owned(), Arc::clone(&counter)));
+                    counter
+                },
+            };
+            Counter::from_arc(counter)
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            let mut histograms = self.histograms.lock().unwrap();
+            let histogram = match histograms.iter().find(|(name, _)| name == key.name()) {
+                Some((_, histogram)) => histogram.clone(),
+                None => {
+                    let histogram = Arc::default();
+                    histograms.push((key.name().to_owned(), Arc::clone(&histogram)));
+                    histogram
+                },
+            };
+            Histogram::from_arc(histogram)
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        let hello = hello("example.com");
+        let mut bad = hello.clone();
+        bad[43] = 0xff;
+
+        // a fragmented hello, one which can't be parsed, and one left whole
+        let recorder = Recorder::default();
+        metrics::with_local_recorder(&recorder, || {
+            for (builder, record) in [
+                (DetourBuilder::new(), &hello),
+                (DetourBuilder::new().fallback(FallbackStrategy::Passthrough).clone(), &bad),
+                (DetourBuilder::new().max_fragments(1).clone(), &hello),
+            ] {
+                let mut detour = builder.build(Mock::new());
+                assert!(matches!(poll_once(&mut detour, record), Poll::Ready(Ok(_))));
+            }
+        });
+
+        let sizes: Vec<_> = fragments(&hello).iter().map(|f| (f.len() - 5) as f64).collect();
+        assert_eq!(recorder.counter("detour_fragmented_hellos_total"), 1);
+        assert_eq!(recorder.counter("detour_fragments_total"), sizes.len() as u64);
+        assert_eq!(recorder.counter("detour_parse_errors_total"), 1);
+        assert_eq!(recorder.counter("detour_passthroughs_total"), 2);
+        assert_eq!(recorder.samples("detour_fragment_size_bytes"), sizes);
+        assert_eq!(recorder.samples("detour_fragment_duration_seconds").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn detour_info() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let info_of = |detour: &Detour<tokio::net::TcpStream>| {
+            let mut extensions = hyper::http::Extensions::new();
+            detour.connected().get_extras(&mut extensions);
+            extensions.remove::<DetourInfo>().unwrap()
+        };
+
+        // the hostname shows up once the hello is written, redacted
+        let mut detour = Detour::new(tcp);
+        assert_eq!(info_of(&detour).sni(), None);
+        detour.write_all(&hello("example.com")).await.unwrap();
+        let info = info_of(&detour);
+        assert_eq!(info.sni(), Some("example.com"));
+        #[cfg(not(feature = "debug-sni"))]
+        assert_eq!(format!("{:?}", info), r#"DetourInfo { sni: Some("<redacted>") }"#);
+    }
+
+    #[tokio::test]
+    async fn tap() {
+        let hello = hello("example.com");
+        let fragments = fragments(&hello);
+
+        // every piece the socket takes, fragments and all after them
+        let tapped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let told = tapped.clone();
+        let mut builder = DetourBuilder::new();
+        builder.tap(move |bytes| told.lock().unwrap().push(bytes.to_vec()));
+        let mut detour = builder.build(Mock::short(100));
+        detour.write_all(&hello).await.unwrap();
+        detour.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let tapped = tapped.lock().unwrap();
+        assert_eq!(*tapped, detour.writes());
+        assert!(tapped.iter().all(|piece| piece.len() <= 100));
+        let written = fragments.concat().len();
+        assert_eq!(tapped.concat()[..written], fragments.concat()[..]);
+        let pieces: Vec<&[u8]> = tapped.iter().map(Vec::as_slice).collect();
+        assert_eq!(crate::parse_sni_from_fragments(&pieces).as_deref(), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn split_points_index_record() {
+        let hello = hello("example.com");
+        let start = hello.windows(11).position(|window| window == b"example.com").unwrap();
+        // the length field of the server_name extension, 7 bytes before the
+        // hostname, and the 16 bytes of its data
+        let point = start - 7 + 16 / 2;
+
+        // the middle of the extension is a point into the record, header
+        // and all; the first payload is that long but for the header
+        let mut detour = Detour::new(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.fragment_info().unwrap().split_points, [point]);
+        assert_eq!(detour.fragment_sizes()[0], point - 5);
+        let writes = detour.writes();
+        assert_eq!(writes[0][5..], hello[5..point]);
+        assert_eq!(writes[1][5..], hello[point..]);
     }
 }
@@ -0,0 +1,965 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+
+// TLS protocol reference from RFC 8446
+
+// enum { ... } ContentType;
+const HANDSHAKE: u8 = 0x16;
+
+// the major byte of every dtls version, from RFC 6347 and RFC 9147
+const DTLS_MAJOR: u8 = 0xfe;
+
+// enum { ... } HandshakeType;
+const CLIENT_HELLO: u8 = 0x01;
+
+// uint16 length; /* The length MUST NOT exceed 2^14 bytes. */
+pub(crate) const MAX_RECORD_LEN: usize = 1 << 14;
+
+// legacy_version (2) + random (32) + legacy_session_id (1) +
+// cipher_suites (2 + 2) + legacy_compression_methods (1 + 1)
+const MIN_HELLO_LEN: usize = 41;
+
+// enum { ... } ExtensionType; (RFC 6066 for server_name)
+const SERVER_NAME: u16 = 0;
+
+// enum { host_name(0), (255) } NameType;
+const HOST_NAME: u8 = 0;
+
+// length of the first record in the buffer, including its header
+pub(crate) fn record_len(data: &[u8]) -> usize {
+    5 + u16::from_be_bytes([data[3], data[4]]) as usize
+}
+
+// the hostname to show in Debug output. it's the very thing a detour hides
+// from DPI, so it's kept out of logs as well, unless the debug-sni feature
+// is on
+pub(crate) fn redacted(sni: Option<&str>) -> Option<&str> {
+    #[cfg(not(feature = "debug-sni"))]
+    let sni = sni.map(|_| "<redacted>");
+    sni
+}
+
+// a magic that tells you if a tls record is client hello
+pub(crate) fn is_hello(data: &[u8]) -> bool {
+    // record header (5) + handshake header (4) + legacy_version (2)
+    if data.len() < 11 {
+        return false;
+    }
+
+    // struct {
+    //     HandshakeType msg_type;    /* handshake type */
+    //     uint24 length;             /* remaining bytes in message */
+    //     ...
+    // } Handshake;
+
+    // the two bytes could be a coincidence, e.g. in an encrypted record.
+    // a real hello has to fit in the record, which has to fit in the buffer;
+    // one already cut into records by a layer above doesn't, and its
+    // fragments go out as they are instead of being cut again.
+    // nothing is buffered across writes, so a hello split over two of them
+    // goes out as is, however long it claims to be; so does a record over
+    // the limit of the spec, which the server rejects anyway
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    let hello_len = u32::from_be_bytes([0, data[6], data[7], data[8]]) as usize;
+
+    // only a handshake record may carry a hello. tls 1.3 sends a dummy
+    // change_cipher_spec right after the hello, and early data may follow
+    // it too; these, like alerts, must go out exactly as they are
+    matches!(data[0], HANDSHAKE)
+        // legacy_record_version isn't checked; it's 0x0301 for compatibility
+        // in most hellos, 0x0303 in some, and the fragments keep it as is.
+        // but a dtls record, of version 0xfeff or 0xfefd, has an epoch and a
+        // sequence number in its header, and frames its handshake messages
+        // differently; it's never taken for a hello, and goes out as is
+        && data[1] != DTLS_MAJOR
+        // any other handshake message goes out as is, e.g. a
+        // ClientKeyExchange. a hello renegotiating a tls 1.2 session is
+        // encrypted, so it hardly ever looks like one either
+        && data[5] == CLIENT_HELLO
+        // a hello is never shorter than its fixed fields, and starts with a
+        // legacy_version of 0x03XX; an encrypted record, whose bytes are as
+        // good as random, hardly ever passes all of these. a detour looks
+        // only at the first write by default, which never is one anyway
+        && record_len <= MAX_RECORD_LEN
+        && 5 + record_len <= data.len()
+        && MIN_HELLO_LEN <= hello_len
+        && 4 + hello_len <= record_len
+        && data[9] == 0x03
+}
+
+/// why a ClientHello couldn't be parsed, with the offset into its record
+/// of the field at fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// the record ends before the field at the offset
+    UnexpectedEof(usize),
+    /// the length field at the offset is out of range, or runs past the
+    /// structure enclosing it
+    LengthOverflow(usize),
+    /// the extension starting at the offset is malformed
+    BadExtension(usize),
+    /// a strategy asked to cut the record at the offset, which is outside
+    /// of it or in its header; see `DetourBuilder::strict_splits`
+    InvalidSplit(usize),
+}
+
+impl ParseError {
+    /// the offset of the faulty field, counted from the record header
+    pub fn offset(&self) -> usize {
+        match *self {
+            ParseError::UnexpectedEof(offset)
+            | ParseError::LengthOverflow(offset)
+            | ParseError::BadExtension(offset)
+            | ParseError::InvalidSplit(offset) => offset,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ParseError::UnexpectedEof(_) => "unexpected end of record",
+            ParseError::LengthOverflow(_) => "length out of range",
+            ParseError::BadExtension(_) => "malformed extension",
+            ParseError::InvalidSplit(_) => "split out of the record",
+        };
+        write!(f, "bad ClientHello: {} at offset {}", reason, self.offset())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// whether `record` is a single, well-formed ClientHello record.
+///
+/// this parses the record through to the last extension, unlike the
+/// quick check a detour makes on each write: the record must hold exactly
+/// its header and payload, and every length inside it must be consistent,
+/// down to the hostname if there's one. the contents are taken as they
+/// are; e.g. unknown cipher suites or extensions are fine.
+pub fn is_valid_client_hello(record: &[u8]) -> bool {
+    is_hello(record) && record_len(record) == record.len() && validate(record).is_ok()
+}
+
+/// the hostname in a ClientHello cut into `fragments`, e.g. the writes a
+/// detour made, to check they still carry the same one.
+///
+/// the fragments are records in order, and may be split or joined across
+/// the slices any way; they're put back into one record up to the end of
+/// the hello, which is parsed as usual. `None` if they don't make a whole
+/// hello, or if it has no SNI.
+pub fn parse_sni_from_fragments(fragments: &[&[u8]]) -> Option<String> {
+    let data = fragments.concat();
+    let (header, mut rest) = (data.get(..5)?, &data[..]);
+
+    let mut payload = Vec::new();
+    while rest.len() >= 5 && rest[..3] == header[..3] {
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        payload.extend_from_slice(rest.get(5..5 + len)?);
+        rest = &rest[5 + len..];
+    }
+
+    // any records after the hello are left out
+    let head = payload.get(..4)?;
+    let hello_len = 4 + u32::from_be_bytes([0, head[1], head[2], head[3]]) as usize;
+    payload.truncate(hello_len);
+    if payload.len() > MAX_RECORD_LEN {
+        return None;
+    }
+    let len = (payload.len() as u16).to_be_bytes();
+
+    let mut record = vec![header[0], header[1], header[2], len[0], len[1]];
+    record.extend_from_slice(&payload);
+    if !is_hello(&record) {
+        return None;
+    }
+    hostname(&record).ok()?.map(String::from)
+}
+
+/// the structure of a ClientHello, for looking into one
+///
+/// this holds only what tells hellos apart, e.g. for a support ticket: no
+/// random, no session id and no key shares. use
+/// [`describe`](Self::describe) to dump it into a log.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    legacy_version: u16,
+    sni: Option<String>,
+    cipher_suites: usize,
+    extensions: Vec<u16>,
+}
+
+impl ClientHello {
+    /// parse a ClientHello record; `None` unless
+    /// [`is_valid_client_hello`] holds for it
+    pub fn parse(record: &[u8]) -> Option<Self> {
+        if !is_valid_client_hello(record) {
+            return None;
+        }
+
+        // it's valid, so every field is where it's expected to be
+        let session_id_len = u8_at(record, 43).ok()? as usize;
+        let suites_len = u16_at(record, 44 + session_id_len).ok()? as usize;
+
+        let mut extensions = Vec::new();
+        if let Some((mut offset, ext_end)) = find_extensions(record).ok()? {
+            while offset < ext_end {
+                let (this_type, _, next) = next_extension(record, offset, ext_end).ok()?;
+                extensions.push(this_type);
+                offset = next;
+            }
+        }
+
+        Some(Self {
+            legacy_version: u16_at(record, 9).ok()?,
+            sni: hostname(record).ok()?.map(String::from),
+            cipher_suites: suites_len / 2,
+            extensions,
+        })
+    }
+
+    /// the legacy_version of the hello, e.g. `0x0303`; the versions really
+    /// offered are in the supported_versions extension
+    pub fn legacy_version(&self) -> u16 {
+        self.legacy_version
+    }
+
+    /// the hostname in the hello, if any
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// the number of cipher suites offered
+    pub fn cipher_suites(&self) -> usize {
+        self.cipher_suites
+    }
+
+    /// the type of every extension, in order.
+    ///
+    /// GREASE extensions are listed where they are, like any other: where
+    /// they sit is part of the fingerprint. the detour never reorders nor
+    /// strips an extension, GREASE or not, so they reach the server as the
+    /// tls layer wrote them.
+    pub fn extensions(&self) -> &[u16] {
+        &self.extensions
+    }
+
+    /// a summary of the hello over multiple lines, for logs.
+    ///
+    /// unlike `Debug`, it shows the hostname whatever the features are.
+    pub fn describe(&self) -> String {
+        use std::fmt::Write;
+
+        // writing to a string never fails
+        let mut out = String::from("ClientHello\n");
+        let _ = writeln!(out, "  legacy_version: {:#06x}", self.legacy_version);
+        let _ = writeln!(out, "  sni: {}", self.sni().unwrap_or("(none)"));
+        let _ = writeln!(out, "  cipher_suites: {}", self.cipher_suites);
+        let _ = writeln!(out, "  extensions: {}", self.extensions.len());
+        for &ext_type in &self.extensions {
+            let _ = writeln!(out, "    {} ({:#06x})", extension_name(ext_type), ext_type);
+        }
+        out
+    }
+}
+
+impl fmt::Debug for ClientHello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientHello")
+            .field("legacy_version", &self.legacy_version)
+            .field("sni", &redacted(self.sni.as_deref()))
+            .field("cipher_suites", &self.cipher_suites)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+/// a builder of minimal ClientHello records, e.g. for tests or decoys
+///
+/// the hello offers three cipher suites, null compression and no session
+/// id, followed by the server_name extension, if any, and the extensions
+/// added, in order. no server will finish a handshake from it; it's only
+/// well-formed, so that [`is_valid_client_hello`] holds for it.
+#[derive(Clone, Default)]
+pub struct ClientHelloBuilder {
+    sni: Option<String>,
+    random: Option<[u8; 32]>,
+    extensions: Vec<(u16, Vec<u8>)>,
+}
+
+impl ClientHelloBuilder {
+    /// make a new builder, for a hello without any extension
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// send `sni` in the server_name extension
+    pub fn sni(&mut self, sni: &str) -> &mut Self {
+        self.sni = Some(sni.to_owned());
+        self
+    }
+
+    /// use `random` instead of a fresh one, e.g. to build the same hello
+    /// twice
+    pub fn random(&mut self, random: [u8; 32]) -> &mut Self {
+        self.random = Some(random);
+        self
+    }
+
+    /// add an extension of `ext_type`, with `data` as is. its length is
+    /// filled in, but nothing checks the data fits the type.
+    pub fn extension(&mut self, ext_type: u16, data: Vec<u8>) -> &mut Self {
+        self.extensions.push((ext_type, data));
+        self
+    }
+
+    /// build the record, header included; `None` if it's longer than a
+    /// record may be
+    pub fn build(&self) -> Option<Vec<u8>> {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+
+        // the lengths are all checked here, before any of them is cast to
+        // the u16 it's sent as: a single one too long would wrap around.
+        // the version, random and the rest before the extensions are 45
+        // bytes; each extension adds a header of 4, the server_name one 5
+        // more for its list
+        let sni_len = self.sni.as_ref().map_or(0, |sni| 9 + sni.len());
+        let extensions_len = sni_len
+            + self.extensions.iter().map(|(_, data)| 4 + data.len()).sum::<usize>();
+        let body_len = 45 + if extensions_len > 0 { 2 + extensions_len } else { 0 };
+        if 4 + body_len > MAX_RECORD_LEN {
+            return None;
+        }
+
+        let mut body = Vec::with_capacity(body_len);
+        body.extend_from_slice(&[0x03, 0x03]);
+        match self.random {
+            Some(random) => body.extend_from_slice(&random),
+            // a fresh RandomState never repeats; good enough for a hello
+            // no one will finish
+            None => {
+                for _ in 0..4 {
+                    let rand = RandomState::new().build_hasher().finish();
+                    body.extend_from_slice(&rand.to_be_bytes());
+                }
+            },
+        }
+        // no session id; TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384,
+        // TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256; null compression
+        body.extend_from_slice(&[0, 0, 6, 0x13, 0x01, 0x13, 0x02, 0xc0, 0x2f, 1, 0]);
+
+        let mut extensions = Vec::new();
+        if let Some(sni) = &self.sni {
+            // server_name with a single host_name
+            let sni = sni.as_bytes();
+            let mut data = Vec::with_capacity(5 + sni.len());
+            data.extend_from_slice(&(3 + sni.len() as u16).to_be_bytes());
+            data.push(HOST_NAME);
+            data.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+            data.extend_from_slice(sni);
+            push_extension(&mut extensions, SERVER_NAME, &data);
+        }
+        for (ext_type, data) in &self.extensions {
+            push_extension(&mut extensions, *ext_type, data);
+        }
+        // a hello without any extension leaves out their length too
+        if !extensions.is_empty() {
+            body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+            body.extend_from_slice(&extensions);
+        }
+
+        debug_assert_eq!(body.len(), body_len);
+        let mut record = Vec::with_capacity(9 + body.len());
+        record.extend_from_slice(&[HANDSHAKE, 0x03, 0x01]);
+        record.extend_from_slice(&(4 + body.len() as u16).to_be_bytes());
+        record.push(CLIENT_HELLO);
+        record.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        record.extend_from_slice(&body);
+        Some(record)
+    }
+}
+
+impl fmt::Debug for ClientHelloBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientHelloBuilder")
+            .field("sni", &redacted(self.sni.as_deref()))
+            .field("random", &self.random.is_some())
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+// struct { ExtensionType extension_type; opaque extension_data<0..2^16-1>; }
+// the caller checks `data` fits
+fn push_extension(out: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+// the names of the extensions a browser may send, from the IANA registry
+fn extension_name(ext_type: u16) -> &'static str {
+    match ext_type {
+        0 => "server_name",
+        1 => "max_fragment_length",
+        5 => "status_request",
+        10 => "supported_groups",
+        11 => "ec_point_formats",
+        13 => "signature_algorithms",
+        16 => "application_layer_protocol_negotiation",
+        18 => "signed_certificate_timestamp",
+        21 => "padding",
+        22 => "encrypt_then_mac",
+        23 => "extended_master_secret",
+        27 => "compress_certificate",
+        28 => "record_size_limit",
+        35 => "session_ticket",
+        41 => "pre_shared_key",
+        42 => "early_data",
+        43 => "supported_versions",
+        44 => "cookie",
+        45 => "psk_key_exchange_modes",
+        49 => "post_handshake_auth",
+        50 => "signature_algorithms_cert",
+        51 => "key_share",
+        0xfe0d => "encrypted_client_hello",
+        0xff01 => "renegotiation_info",
+        // RFC 8701; 0x0a0a, 0x1a1a, ..., 0xfafa
+        _ if ext_type & 0x0f0f == 0x0a0a && ext_type >> 8 == ext_type & 0xff => "grease",
+        _ => "unknown",
+    }
+}
+
+// parse the hello through, see is_valid_client_hello
+fn validate(data: &[u8]) -> Result<(), ParseError> {
+    let (mut offset, ext_end) = match find_extensions(data)? {
+        Some(extensions) => extensions,
+        None => return Ok(()),
+    };
+
+    while offset < ext_end {
+        offset = next_extension(data, offset, ext_end)?.2;
+    }
+
+    // nothing but the extensions may follow the compression methods
+    let hello_len = u32::from_be_bytes([0, data[6], data[7], data[8]]) as usize;
+    if ext_end != 9 + hello_len {
+        return Err(ParseError::LengthOverflow(6));
+    }
+    find_hostname(data).map(|_| ())
+}
+
+// find the data of the first extension of a type, as (start, len)
+fn find_extension(data: &[u8], ext_type: u16) -> Result<Option<(usize, usize)>, ParseError> {
+    let (mut offset, ext_end) = match find_extensions(data)? {
+        Some(extensions) => extensions,
+        None => return Ok(None),
+    };
+
+    while offset < ext_end {
+        let (this_type, ext_len, next) = next_extension(data, offset, ext_end)?;
+        if this_type == ext_type {
+            return Ok(Some((offset + 4, ext_len)));
+        }
+        offset = next;
+    }
+
+    Ok(None)
+}
+
+// find the first extension and the end of them all, as (start, end)
+fn find_extensions(data: &[u8]) -> Result<Option<(usize, usize)>, ParseError> {
+    // uint16 ProtocolVersion;
+    // opaque Random[32];
+    //
+    // uint8 CipherSuite[2];    /* Cryptographic suite selector */
+    //
+    // struct {
+    //     ProtocolVersion legacy_version = 0x0303;    /* TLS v1.2 */
+    //     Random random;
+    //     opaque legacy_session_id<0..32>;
+    //     CipherSuite cipher_suites<2..2^16-2>;
+    //     opaque legacy_compression_methods<1..2^8-1>;
+    //     Extension extensions<8..2^16-1>;
+    // } ClientHello;
+
+    // a record may carry another handshake message after the hello; don't
+    // read into it
+    let hello_len = u32::from_be_bytes([0, u8_at(data, 6)?, u8_at(data, 7)?, u8_at(data, 8)?]);
+    let data = &data[..data.len().min(9 + hello_len as usize)];
+
+    // skip all headers we're not interested in
+
+    // skip up to `random`
+    let mut offset = 5 + 4 + 2 + 32;
+
+    // legacy_session_id; empty from tls 1.2 clients, while tls 1.3 clients
+    // often send a fake one of 32 bytes
+    let session_id_len = u8_at(data, offset)? as usize;
+    if session_id_len > 32 || offset + 1 + session_id_len > data.len() {
+        return Err(ParseError::LengthOverflow(offset));
+    }
+    offset += 1 + session_id_len;
+
+    // cipher_suites; at least one, and two bytes each. none at all would
+    // leave the server nothing to pick, so such a hello is rejected anyway
+    let suites_len = u16_at(data, offset)? as usize;
+    if suites_len < 2 || !suites_len.is_multiple_of(2) || offset + 2 + suites_len > data.len() {
+        return Err(ParseError::LengthOverflow(offset));
+    }
+    offset += 2 + suites_len;
+
+    // legacy_compression_methods; always {0} in tls 1.3, but a tls 1.2
+    // client may offer more
+    let methods_len = u8_at(data, offset)? as usize;
+    if methods_len == 0 || offset + 1 + methods_len > data.len() {
+        return Err(ParseError::LengthOverflow(offset));
+    }
+    offset += 1 + methods_len;
+
+    // extensions; a hello without any is valid, just not interesting
+    if offset == data.len() {
+        return Ok(None);
+    }
+    // an extensions length running past the hello isn't scanned up to the
+    // end of the buffer instead: blocks cut short can't be trusted, and a
+    // hello like that is rejected by the server anyway
+    let ext_end = offset + 2 + u16_at(data, offset)? as usize;
+    if ext_end > data.len() {
+        return Err(ParseError::LengthOverflow(offset));
+    }
+
+    Ok(Some((offset + 2, ext_end)))
+}
+
+// read the extension at `offset`, as (type, len, where the next one starts)
+fn next_extension(
+    data: &[u8],
+    offset: usize,
+    ext_end: usize,
+) -> Result<(u16, usize, usize), ParseError> {
+    // struct {
+    //     ExtensionType extension_type;
+    //     opaque extension_data<0..2^16-1>;
+    // } Extension;
+
+    // every extension is read whole, type (2) + length (2) + data,
+    // before moving on to the next one right after it
+    if offset + 4 > ext_end {
+        return Err(ParseError::BadExtension(offset));
+    }
+    let this_type = u16_at(data, offset)?;
+    let ext_len = u16_at(data, offset + 2)? as usize;
+    if offset + 4 + ext_len > ext_end {
+        return Err(ParseError::BadExtension(offset));
+    }
+
+    Ok((this_type, ext_len, offset + 4 + ext_len))
+}
+
+fn u8_at(data: &[u8], at: usize) -> Result<u8, ParseError> {
+    data.get(at).copied().ok_or(ParseError::UnexpectedEof(at))
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, ParseError> {
+    Ok(u16::from_be_bytes([u8_at(data, at)?, u8_at(data, at + 1)?]))
+}
+
+// find where to cut; the middle of the server_name extension
+pub(crate) fn find_sni(data: &[u8]) -> Result<Option<usize>, ParseError> {
+    // counted from the extension's length field, which is 2 bytes before
+    // the list's; the list fills the extension, so it's as long plus 2
+    let list = find_name_list(data)?;
+    Ok(list.map(|(start, len)| start - 4 + (2 + len) / 2))
+}
+
+// find the server_name_list, as (start, len) of the names in it
+//
+// struct {
+//     ServerName server_name_list<1..2^16-1>
+// } ServerNameList;
+fn find_name_list(data: &[u8]) -> Result<Option<(usize, usize)>, ParseError> {
+    // extension_type == server_name
+    let (start, len) = match find_extension(data, SERVER_NAME)? {
+        Some(sni) => sni,
+        None => return Ok(None),
+    };
+
+    // the extension holds the list and nothing else, so its length and
+    // the list's must agree; a malformed hello may have them disagree
+    // either way, and neither can be trusted then
+    let list_len = u16_at(data, start)? as usize;
+    if 2 + list_len > len {
+        return Err(ParseError::LengthOverflow(start));
+    }
+    // at least one name_type (1) + name length (2)
+    if 2 + list_len < len || list_len < 3 {
+        return Err(ParseError::BadExtension(start - 4));
+    }
+    Ok(Some((start + 2, list_len)))
+}
+
+// find exactly where the hostname is, as (start, len)
+pub(crate) fn find_hostname(data: &[u8]) -> Result<Option<(usize, usize)>, ParseError> {
+    // struct {
+    //     NameType name_type;
+    //     select (name_type) {
+    //         case host_name: HostName;
+    //     } name;
+    // } ServerName;
+    //
+    // opaque HostName<1..2^16-1>;
+    let (start, list_len) = match find_name_list(data)? {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+
+    // the list may hold other types of names before the hostname; they're
+    // all laid out the same way, so skip them
+    let end = start + list_len;
+    let mut offset = start;
+    while offset + 3 <= end {
+        let name_len = u16_at(data, offset + 1)? as usize;
+        if offset + 3 + name_len > end {
+            return Err(ParseError::LengthOverflow(offset + 1));
+        }
+        if data[offset] == HOST_NAME {
+            return Ok(Some((offset + 3, name_len)));
+        }
+        offset += 3 + name_len;
+    }
+
+    Err(ParseError::BadExtension(start - 6))
+}
+
+// the hostname in plain text, if it's valid
+pub(crate) fn hostname(data: &[u8]) -> Result<Option<&str>, ParseError> {
+    let (start, len) = match find_hostname(data)? {
+        Some(hostname) => hostname,
+        None => return Ok(None),
+    };
+    Ok(std::str::from_utf8(&data[start..start + len]).ok())
+}
+
+// a minimal ClientHello to `sni`, which no one will ever finish
+pub(crate) fn decoy(sni: &str) -> Vec<u8> {
+    // a hostname is never longer, and the hello always fits a record then
+    let mut end = sni.len().min(255);
+    while !sni.is_char_boundary(end) {
+        end -= 1;
+    }
+    ClientHelloBuilder::new().sni(&sni[..end]).build().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::hello;
+
+    #[test]
+    fn only_handshake_records() {
+        let hello = hello("example.com");
+        assert!(is_hello(&hello));
+
+        // change_cipher_spec, alert, application_data
+        for content_type in [0x14, 0x15, 0x17] {
+            let mut record = hello.clone();
+            record[0] = content_type;
+            assert!(!is_hello(&record));
+        }
+
+        // nor any handshake message but a ClientHello, e.g. a
+        // ClientKeyExchange, however much it looks like one
+        let mut record = hello.clone();
+        record[5] = 0x10;
+        assert!(!is_hello(&record));
+        assert!(!is_valid_client_hello(&record));
+    }
+
+    #[test]
+    fn encrypted_handshake() {
+        // an encrypted handshake record, e.g. a NewSessionTicket, whose first
+        // byte happens to be that of a ClientHello, with a length fitting in
+        // the record; the rest is as good as random
+        let mut record = vec![0x16, 0x03, 0x03, 0x00, 0x40, 0x01, 0x00, 0x00, 0x3c];
+        record.extend((0..0x3c).map(|i| (i * 37 + 11) as u8));
+        assert_ne!(record[9], 0x03);
+        assert!(!is_hello(&record));
+
+        // even with the version right, a length too short for a hello
+        record[9] = 0x03;
+        record[8] = MIN_HELLO_LEN as u8 - 1;
+        assert!(!is_hello(&record));
+    }
+
+    #[test]
+    fn valid_client_hello() {
+        let hello = hello("example.com");
+        let bare = ClientHelloBuilder::new().build().unwrap();
+        for record in [&hello, &crate::mock::anonymous_hello(), &bare] {
+            assert!(is_valid_client_hello(record));
+        }
+
+        // a byte more or less than the record, a handshake a byte shorter
+        // than it, and a hostname a byte longer than its extension
+        let (start, _) = find_hostname(&hello).unwrap().unwrap();
+        let mut malformed = vec![[&hello[..], &[0]].concat(), hello[..hello.len() - 1].to_vec()];
+        let mut record = hello.clone();
+        record[8] -= 1;
+        malformed.push(record);
+        let mut record = hello.clone();
+        record[start - 1] += 1;
+        malformed.push(record);
+        for record in &malformed {
+            assert!(!is_valid_client_hello(record));
+        }
+    }
+
+    #[cfg(not(feature = "debug-sni"))]
+    #[test]
+    fn sni_redacted() {
+        let hello = ClientHello::parse(&hello("example.com")).unwrap();
+        let builder = ClientHelloBuilder::new().sni("example.com").clone();
+
+        let debug = format!("{:?} {:?}", hello, builder);
+        assert!(!debug.contains("example.com"));
+        assert_eq!(debug.matches("<redacted>").count(), 2);
+    }
+
+    #[test]
+    fn longest_hello() {
+        let sni = |len| ClientHelloBuilder::new().sni(&"a".repeat(len)).build();
+        let padding = |len| ClientHelloBuilder::new().extension(PADDING, vec![0; len]).build();
+
+        // filling the record up to its last byte; the server_name extension
+        // takes 5 bytes more than the padding around its data
+        for hello in [sni(16324), padding(16329)] {
+            let hello = hello.unwrap();
+            assert_eq!(hello.len(), 5 + MAX_RECORD_LEN);
+            assert!(is_valid_client_hello(&hello));
+        }
+
+        // a byte more doesn't fit, nor does one too long for its u16
+        assert!(sni(16325).is_none());
+        assert!(padding(16330).is_none());
+        for len in [65534, 65535, 65536] {
+            assert!(sni(len).is_none());
+            assert!(padding(len).is_none());
+        }
+    }
+
+    #[test]
+    fn over_max_record_len() {
+        let hello = hello("example.com");
+
+        // a record claiming more than the spec allows isn't taken for a
+        // hello, whether the bytes it claims are there or not
+        for len in [MAX_RECORD_LEN + 1, 0xffff] {
+            let mut record = hello.clone();
+            record[3..5].copy_from_slice(&(len as u16).to_be_bytes());
+            assert!(!is_hello(&record), "{}", len);
+            record.resize(5 + len, 0);
+            assert!(!is_hello(&record), "{}", len);
+        }
+
+        // while one right at the limit still is
+        let mut record = hello;
+        record[3..5].copy_from_slice(&(MAX_RECORD_LEN as u16).to_be_bytes());
+        record.resize(5 + MAX_RECORD_LEN, 0);
+        assert!(is_hello(&record));
+    }
+
+    const PADDING: u16 = 21;
+
+    #[test]
+    fn describe() {
+        let hello = ClientHello::parse(&hello("example.com")).unwrap();
+        assert_eq!(hello.legacy_version(), 0x0303);
+        assert_eq!(hello.cipher_suites(), 3);
+        assert_eq!(hello.extensions(), [0, 21]);
+
+        // the hostname shows, unlike in Debug output
+        let expected = concat!(
+            "ClientHello\n",
+            "  legacy_version: 0x0303\n",
+            "  sni: example.com\n",
+            "  cipher_suites: 3\n",
+            "  extensions: 2\n",
+            "    server_name (0x0000)\n",
+            "    padding (0x0015)\n",
+        );
+        assert_eq!(hello.describe(), expected);
+
+        let anonymous = ClientHello::parse(&ClientHelloBuilder::new().build().unwrap()).unwrap();
+        let described = anonymous.describe();
+        assert!(described.contains("  sni: (none)\n"));
+        assert!(described.ends_with("  extensions: 0\n"));
+    }
+
+    // `hello` with `bytes` put in at `at`, and the lengths of the record and
+    // of the handshake grown to match
+    fn inserted(hello: &[u8], at: usize, bytes: &[u8]) -> Vec<u8> {
+        let mut hello = [&hello[..at], bytes, &hello[at..]].concat();
+        let record_len = hello.len() - 5;
+        hello[3..5].copy_from_slice(&(record_len as u16).to_be_bytes());
+        hello[6..9].copy_from_slice(&(record_len as u32 - 4).to_be_bytes()[1..]);
+        hello
+    }
+
+    #[test]
+    fn session_id() {
+        let hello = hello("example.com");
+
+        // empty from tls 1.2 clients, and a fake one of 32 bytes from tls 1.3
+        for len in [0, 32] {
+            let mut record = inserted(&hello, 44, &vec![0xaa; len]);
+            record[43] = len as u8;
+            assert_eq!(hostname(&record), Ok(Some("example.com")), "{}", len);
+            assert!(is_valid_client_hello(&record));
+        }
+
+        // but never longer, even if the record has room for it
+        let mut record = inserted(&hello, 44, &[0xaa; 33]);
+        record[43] = 33;
+        assert_eq!(hostname(&record), Err(ParseError::LengthOverflow(43)));
+    }
+
+    #[test]
+    fn compression_methods() {
+        // null compression alone, after three cipher suites
+        let hello = hello("example.com");
+        assert_eq!(hello[52..54], [1, 0]);
+
+        // a tls 1.2 client offering deflate and lzs too
+        let mut record = inserted(&hello, 54, &[0x01, 0x40]);
+        record[52] = 3;
+        assert_eq!(hostname(&record), Ok(Some("example.com")));
+        assert!(is_valid_client_hello(&record));
+
+        // there's always one at least
+        let mut record = hello.clone();
+        record[52] = 0;
+        assert_eq!(hostname(&record), Err(ParseError::LengthOverflow(52)));
+    }
+
+    #[test]
+    fn extensions_overflow() {
+        let hello = hello("example.com");
+        let len = u16::from_be_bytes([hello[54], hello[55]]) as usize;
+        assert_eq!(56 + len, hello.len());
+
+        // a byte more than the hello has, or as much as the field can say
+        for len in [len + 1, 0xffff] {
+            let mut record = hello.clone();
+            record[54..56].copy_from_slice(&(len as u16).to_be_bytes());
+            assert_eq!(hostname(&record), Err(ParseError::LengthOverflow(54)), "{}", len);
+            assert!(!is_valid_client_hello(&record));
+        }
+    }
+
+    #[test]
+    fn cipher_suites_length() {
+        let hello = hello("example.com");
+        assert_eq!(u16::from_be_bytes([hello[44], hello[45]]), 6);
+
+        // none at all, or half of one, rather than the three of the hello
+        for len in [0, 3] {
+            let suites = &hello[46..46 + len];
+            let mut record = [&hello[..44], &[0, len as u8], suites, &hello[52..]].concat();
+            let body_len = (record.len() - 9) as u32;
+            record[3..5].copy_from_slice(&(body_len as u16 + 4).to_be_bytes());
+            record[6..9].copy_from_slice(&body_len.to_be_bytes()[1..]);
+            assert_eq!(hostname(&record), Err(ParseError::LengthOverflow(44)), "{}", len);
+            assert!(!is_valid_client_hello(&record));
+        }
+    }
+
+    // the data of a server_name extension listing `names`, by type
+    fn server_name(names: &[(u8, &str)]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (name_type, name) in names {
+            list.push(*name_type);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+        }
+        [&(list.len() as u16).to_be_bytes()[..], &list].concat()
+    }
+
+    #[test]
+    fn sni_last_of_ten() {
+        // nine extensions, each longer than the last, before the hostname
+        let mut builder = ClientHelloBuilder::new();
+        for ext_type in 1..10 {
+            builder.extension(ext_type, vec![0xaa; ext_type as usize * 7]);
+        }
+        let record = builder.extension(SERVER_NAME, server_name(&[(HOST_NAME, "example.com")]));
+        let record = record.build().unwrap();
+
+        let start = record.len() - "example.com".len();
+        assert_eq!(find_hostname(&record), Ok(Some((start, 11))));
+        assert_eq!(hostname(&record), Ok(Some("example.com")));
+        assert!(is_valid_client_hello(&record));
+    }
+
+    #[test]
+    fn several_server_names() {
+        // a name of another type before the hostname is skipped
+        let names = server_name(&[(1, "not.a.host"), (HOST_NAME, "example.com")]);
+        let record = ClientHelloBuilder::new().extension(SERVER_NAME, names).build().unwrap();
+        let start = record.len() - "example.com".len();
+        assert_eq!(find_hostname(&record), Ok(Some((start, 11))));
+        assert_eq!(hostname(&record), Ok(Some("example.com")));
+
+        // but a list without a hostname is malformed
+        let names = server_name(&[(1, "not.a.host")]);
+        let record = ClientHelloBuilder::new().extension(SERVER_NAME, names).build().unwrap();
+        assert_eq!(find_hostname(&record), Err(ParseError::BadExtension(56)));
+    }
+
+    #[test]
+    fn sni_from_fragments() {
+        let hello = hello("example.com");
+        let fragments = crate::fragment_record(&hello, crate::SplitStrategy::EveryByte).unwrap();
+        let data = fragments.concat();
+        let sni = |fragments: &[&[u8]]| parse_sni_from_fragments(fragments);
+
+        // the records may be sliced any way, and followed by others
+        assert_eq!(sni(&[&data]).as_deref(), Some("example.com"));
+        assert_eq!(sni(&[&data[..7], &data[7..100], &data[100..]]).as_deref(), Some("example.com"));
+        let early = [0x17, 0x03, 0x03, 0x00, 0x01, 0xaa];
+        assert_eq!(sni(&[&hello, &early]).as_deref(), Some("example.com"));
+
+        // but not cut short, nor without a hostname
+        assert_eq!(sni(&[&data[..data.len() - 1]]), None);
+        assert_eq!(sni(&[]), None);
+        assert_eq!(sni(&[&crate::mock::anonymous_hello()]), None);
+    }
+
+    #[test]
+    fn name_list_length() {
+        let names = server_name(&[(HOST_NAME, "example.com")]);
+        let sni = |names: Vec<u8>| {
+            let record = ClientHelloBuilder::new().extension(SERVER_NAME, names).build().unwrap();
+            (find_sni(&record), hostname(&record).map(|_| ()))
+        };
+
+        // the split is the middle of the extension, from its length field
+        let split = Ok(Some(58 + names.len() / 2));
+        assert_eq!(sni(names.clone()), (split, Ok(())));
+
+        // a list shorter than its extension leaves bytes nothing accounts for
+        let mut short = names.clone();
+        short[1] -= 1;
+        let e = ParseError::BadExtension(56);
+        assert_eq!(sni(short), (Err(e), Err(e)));
+
+        // and one longer runs past it
+        let mut long = names;
+        long[1] += 1;
+        let e = ParseError::LengthOverflow(60);
+        assert_eq!(sni(long), (Err(e), Err(e)));
+    }
+}
@@ -11,7 +11,7 @@
 //! ## Example
 //!
 //! ```no_run
-//! use hyper_tls::HttpsConnector;
+//! use detour::HttpsConnector;
 //! use hyper::Client;
 //!
 //! #[tokio::main(flavor = "current_thread")]
@@ -32,10 +32,44 @@
 #[doc(hidden)]
 pub extern crate native_tls;
 
-pub use client::{HttpsConnecting, HttpsConnector};
-pub use stream::{MaybeHttpsStream, TlsStream};
-pub use detour::Detour;
+pub use client::{Attempt, ConnectPhase, Error, HttpsConnecting, HttpsConnector, HttpsConnectorBuilder};
+pub use stream::{HttpsInfo, MaybeHttpsStream, TlsStream};
+pub use detour::{fragment_record, Detour, DetourDecision, DetourInfo, FragmentInfo};
+pub use connect::{connect, DetourConnecting, DetourConnector};
+pub use config::{DetourBuilder, DetourConfig, DetourHandle};
+pub use strategy::{FallbackStrategy, NetworkHint, SplitStrategy};
+pub use socket::AsTcpStream;
+pub use hello::{
+    is_valid_client_hello, parse_sni_from_fragments, ClientHello, ClientHelloBuilder, ParseError,
+};
+pub use proxy::ProxyProtocol;
+pub use policy::PolicyMode;
+pub use sni::{with_sni, with_tls};
+#[cfg(feature = "client")]
+pub use builder::DetourClientBuilder;
+#[cfg(feature = "sync")]
+pub use sync::SyncDetour;
+#[cfg(feature = "socks")]
+pub use socks::Socks5Connector;
 
 mod client;
 mod stream;
 mod detour;
+mod connect;
+mod config;
+mod hello;
+mod strategy;
+mod socket;
+mod proxy;
+mod policy;
+mod sni;
+#[cfg(feature = "client")]
+mod builder;
+#[cfg(feature = "sync")]
+mod sync;
+#[cfg(feature = "socks")]
+mod socks;
+#[cfg(feature = "pcap")]
+mod pcap;
+#[cfg(test)]
+mod mock;
@@ -0,0 +1,181 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+// a socket for the tests, keeping what it's told to do
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_native_tls::{TlsAcceptor, TlsConnector};
+
+use crate::hello::ClientHelloBuilder;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    Write(Vec<u8>),
+    Flush,
+}
+
+// takes at most `max_write` bytes a write, and with `pending`, answers
+// every other poll with Pending
+#[derive(Debug, Default)]
+pub(crate) struct Mock {
+    pub(crate) max_write: Option<usize>,
+    pub(crate) pending: bool,
+    pub(crate) events: Vec<Event>,
+    // whether the next poll is let through, with `pending`
+    ready: bool,
+}
+
+impl Mock {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    // taking at most `n` bytes a write
+    pub(crate) fn short(n: usize) -> Self {
+        Self { max_write: Some(n), ..Self::default() }
+    }
+
+    // answering every other poll with Pending
+    pub(crate) fn pending() -> Self {
+        Self { pending: true, ..Self::default() }
+    }
+
+    // every write, in order
+    pub(crate) fn writes(&self) -> Vec<&[u8]> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Write(data) => Some(&data[..]),
+                Event::Flush => None,
+            })
+            .collect()
+    }
+
+    // every byte written, in order
+    pub(crate) fn written(&self) -> Vec<u8> {
+        self.writes().concat()
+    }
+
+    // Pending every other time, with `pending`
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.ready = !self.ready;
+        if self.pending && self.ready {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> usize {
+        let n = self.max_write.map_or(buf.len(), |max| max.min(buf.len()));
+        self.events.push(Event::Write(buf[..n].to_vec()));
+        n
+    }
+}
+
+impl AsyncWrite for Mock {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(()) => Poll::Ready(Ok(self.write(buf))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(()) => {
+                self.events.push(Event::Flush);
+                Poll::Ready(Ok(()))
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl io::Write for Mock {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(Mock::write(self, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.events.push(Event::Flush);
+        Ok(())
+    }
+}
+
+// a ClientHello record to `sni`, padded to be long enough to fragment
+pub(crate) fn hello(sni: &str) -> Vec<u8> {
+    ClientHelloBuilder::new()
+        .sni(sni)
+        .extension(PADDING, vec![0; 256])
+        .build()
+        .unwrap()
+}
+
+// the same, without SNI, as a hello to an ip address
+pub(crate) fn anonymous_hello() -> Vec<u8> {
+    ClientHelloBuilder::new()
+        .extension(PADDING, vec![0; 256])
+        .build()
+        .unwrap()
+}
+
+const PADDING: u16 = 21;
+
+// a self-signed certificate for localhost, and its key
+pub(crate) const CERT: &[u8] = include_bytes!("../tests/handshake/cert.pem");
+const KEY: &[u8] = include_bytes!("../tests/handshake/key.pem");
+
+// a tls connector trusting the certificate of `tls_server`
+pub(crate) fn tls_connector() -> TlsConnector {
+    let root = native_tls::Certificate::from_pem(CERT).unwrap();
+    let tls = native_tls::TlsConnector::builder()
+        .add_root_certificate(root)
+        .build()
+        .unwrap();
+    tls.into()
+}
+
+// a tls acceptor of the certificate `tls_connector` trusts, to tune
+pub(crate) fn tls_acceptor() -> native_tls::TlsAcceptorBuilder {
+    native_tls::TlsAcceptor::builder(native_tls::Identity::from_pkcs8(CERT, KEY).unwrap())
+}
+
+// a tls server for localhost on the returned port, holding every connection
+// until the client is done with it. the first `drop_first` connections are
+// closed right away instead, failing their handshake
+pub(crate) async fn tls_server(drop_first: usize) -> u16 {
+    let acceptor = TlsAcceptor::from(tls_acceptor().build().unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut dropped = 0;
+        while let Ok((tcp, _)) = listener.accept().await {
+            if dropped < drop_first {
+                dropped += 1;
+                continue;
+            }
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut tls) = acceptor.accept(tcp).await {
+                    let _ = tls.read(&mut [0]).await;
+                }
+            });
+        }
+    });
+    port
+}
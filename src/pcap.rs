@@ -0,0 +1,221 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// pcap file format reference from draft-ietf-opsawg-pcap
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+// bare ipv4 packets, without a link layer
+const LINKTYPE_RAW: u32 = 101;
+
+// the made up flow every write is put into; wireshark decodes port 443 as
+// tls by itself
+const SRC: [u8; 4] = [10, 0, 0, 1];
+const DST: [u8; 4] = [10, 0, 0, 2];
+const SRC_PORT: u16 = 49152;
+const DST_PORT: u16 = 443;
+
+const IP_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+const MAX_SEGMENT: usize = SNAPLEN as usize - IP_HEADER_LEN - TCP_HEADER_LEN;
+
+// a tap writing every write it's told of as a tcp segment of a pcap file.
+//
+// the file is written by a thread of its own, so the socket never waits for
+// it; the thread exits once the tap is dropped, or the writer fails.
+pub(crate) fn tap<W>(mut writer: W) -> impl FnMut(&[u8]) + Send
+where
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<(SystemTime, Vec<u8>)>();
+
+    thread::spawn(move || {
+        let mut seq = 1u32;
+        writer.write_all(&file_header())?;
+        for (time, bytes) in rx {
+            for segment in bytes.chunks(MAX_SEGMENT) {
+                writer.write_all(&packet(time, seq, segment))?;
+                seq = seq.wrapping_add(segment.len() as u32);
+            }
+            writer.flush()?;
+        }
+        std::io::Result::Ok(())
+    });
+
+    move |bytes| {
+        // the time is taken here, in the order the socket took the bytes
+        let _ = tx.send((SystemTime::now(), bytes.to_vec()));
+    }
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&VERSION.0.to_le_bytes());
+    header.extend_from_slice(&VERSION.1.to_le_bytes());
+    // thiszone, sigfigs
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+// a pcap record of an ipv4 packet carrying `payload` in a tcp segment
+fn packet(time: SystemTime, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let len = IP_HEADER_LEN + TCP_HEADER_LEN + payload.len();
+
+    let mut packet = Vec::with_capacity(16 + len);
+    // uint32 ts_sec; uint32 ts_usec; uint32 incl_len; uint32 orig_len;
+    packet.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    packet.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    packet.extend_from_slice(&(len as u32).to_le_bytes());
+    packet.extend_from_slice(&(len as u32).to_le_bytes());
+
+    let mut ip = [0; IP_HEADER_LEN];
+    ip[0] = 0x45; // version 4, 5 words long
+    ip[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    ip[6] = 0x40; // don't fragment
+    ip[8] = 64; // ttl
+    ip[9] = 6; // tcp
+    ip[12..16].copy_from_slice(&SRC);
+    ip[16..20].copy_from_slice(&DST);
+    let checksum = ip_checksum(&ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&ip);
+
+    // the tcp checksum is left out; wireshark doesn't check it by default
+    let mut tcp = [0; TCP_HEADER_LEN];
+    tcp[0..2].copy_from_slice(&SRC_PORT.to_be_bytes());
+    tcp[2..4].copy_from_slice(&DST_PORT.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&1u32.to_be_bytes());
+    tcp[12] = 0x50; // 5 words long
+    tcp[13] = 0x18; // psh, ack
+    tcp[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    // a file shared with the tap's thread, to look into afterwards
+    #[derive(Clone, Default)]
+    struct File(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for File {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ip_header() {
+        let packet = packet(UNIX_EPOCH, 1, b"hello");
+        let ip = &packet[16..16 + IP_HEADER_LEN];
+        assert_eq!(u16::from_be_bytes([ip[2], ip[3]]), 45);
+        // a header summed with its checksum comes to all ones
+        assert_eq!(ip_checksum(ip), 0);
+        assert_eq!(&packet[16 + 40..], b"hello");
+    }
+
+    #[test]
+    fn segments() {
+        let file = File::default();
+        let mut tap = tap(file.clone());
+        tap(b"first");
+        tap(&vec![0; MAX_SEGMENT + 1]);
+        drop(tap);
+
+        // the tap's thread writes the file in its own time; wait for it
+        let mut packets = Vec::new();
+        for _ in 0..100 {
+            let data = file.0.lock().unwrap().clone();
+            packets = parse(&data);
+            if packets.len() == 3 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // the second write is cut in two, its sequence numbers following on
+        let data = file.0.lock().unwrap();
+        assert_eq!(data[..24], file_header()[..]);
+        let lens: Vec<_> = packets.iter().map(|(_, len)| *len).collect();
+        assert_eq!(lens, [5, MAX_SEGMENT, 1]);
+        let seqs: Vec<_> = packets.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [1, 6, 6 + MAX_SEGMENT as u32]);
+    }
+
+    #[tokio::test]
+    async fn tap_pcap() {
+        use tokio::io::AsyncWriteExt;
+
+        let file = File::default();
+        let hello = crate::mock::hello("example.com");
+        let mut detour = crate::DetourBuilder::new()
+            .tap_pcap(file.clone())
+            .build(crate::mock::Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        let sizes = detour.fragment_sizes().to_vec();
+        drop(detour);
+
+        // a segment for each fragment, header and all
+        let mut packets = Vec::new();
+        for _ in 0..100 {
+            packets = parse(&file.0.lock().unwrap());
+            if packets.len() == sizes.len() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let lens: Vec<_> = packets.iter().map(|(_, len)| *len).collect();
+        let expected: Vec<_> = sizes.iter().map(|size| 5 + size).collect();
+        assert_eq!(lens, expected);
+    }
+
+    // the sequence number and payload length of every packet in a file
+    fn parse(mut data: &[u8]) -> Vec<(u32, usize)> {
+        let mut packets = Vec::new();
+        data = data.get(24..).unwrap_or_default();
+        while data.len() >= 16 {
+            let len = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
+            if data.len() < 16 + len {
+                break;
+            }
+            let tcp = &data[16 + IP_HEADER_LEN..];
+            let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+            packets.push((seq, len - IP_HEADER_LEN - TCP_HEADER_LEN));
+            data = &data[16 + len..];
+        }
+        packets
+    }
+}
@@ -0,0 +1,341 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use crate::config::{DetourBuilder, DetourConfig};
+use crate::strategy::{FallbackStrategy, SplitStrategy};
+
+// a rule telling which hostnames should be fragmented
+#[derive(Debug, Clone)]
+pub(crate) enum HostRule {
+    // the hostname itself
+    Exact(String),
+    // any subdomain of it, from `*.example.com`; the dot is kept
+    Suffix(String),
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+}
+
+impl HostRule {
+    pub(crate) fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => HostRule::Suffix(suffix.to_owned()),
+            _ => HostRule::Exact(pattern),
+        }
+    }
+
+    // hostnames are case-insensitive, and may end with a dot
+    pub(crate) fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        match self {
+            HostRule::Exact(name) => host.eq_ignore_ascii_case(name),
+            HostRule::Suffix(suffix) => {
+                host.len() > suffix.len()
+                    && host.is_char_boundary(host.len() - suffix.len())
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            },
+            #[cfg(feature = "regex")]
+            HostRule::Regex(regex) => regex.is_match(host),
+        }
+    }
+}
+
+/// how the hosts given to [`DetourBuilder::enable_for`] are taken
+///
+/// either way, without any host every hello is fragmented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolicyMode {
+    /// fragment only the hellos to the hosts (the default). a hello without
+    /// SNI is sent as is.
+    #[default]
+    Allowlist,
+    /// fragment every hello but the ones to the hosts. a hello without SNI
+    /// is fragmented.
+    Denylist,
+}
+
+const BAD_FLAG: &str = "expected on or off";
+
+// read a policy file, see DetourConfig::from_reader
+pub(crate) fn parse<R: Read>(reader: R) -> io::Result<DetourConfig> {
+    let mut builder = DetourBuilder::new();
+
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |what: &str| {
+            let message = format!("policy line {}: {}: {}", i + 1, what, line);
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        };
+
+        let mut words = line.split_whitespace();
+        let option = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        match (option, args.as_slice()) {
+            ("strategy", args) => {
+                let strategy = parse_strategy(args).ok_or_else(|| invalid("bad strategy"))?;
+                builder.strategy(strategy);
+            },
+            ("fallback", args) => {
+                let fallback = parse_fallback(args).ok_or_else(|| invalid("bad fallback"))?;
+                builder.fallback(fallback);
+            },
+            ("max-fragments", [n]) => {
+                builder.max_fragments(n.parse().map_err(|_| invalid("bad number"))?);
+            },
+            ("split-jitter", [f]) => {
+                builder.split_jitter(f.parse().map_err(|_| invalid("bad number"))?);
+            },
+            ("max-overhead-bytes", [n]) => {
+                builder.max_overhead_bytes(n.parse().map_err(|_| invalid("bad number"))?);
+            },
+            ("min-record-len", [n]) => {
+                builder.min_record_len(n.parse().map_err(|_| invalid("bad number"))?);
+            },
+            ("retry", [n]) => {
+                builder.retry(n.parse().map_err(|_| invalid("bad number"))?);
+            },
+            ("unfragmented-retry", [flag]) => {
+                builder.unfragmented_retry(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("flush-between", [flag]) => {
+                builder.flush_between(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("anti-coalesce", [flag]) => {
+                builder.anti_coalesce(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("separate-segments", [flag]) => {
+                builder.separate_segments(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("single-write", [flag]) => {
+                builder.single_write(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("first-write-only", [flag]) => {
+                builder.first_write_only(parse_flag(flag).ok_or_else(|| invalid(BAD_FLAG))?);
+            },
+            ("enable-for", [pattern]) => {
+                builder.enable_for(pattern);
+            },
+            ("policy-mode", [mode]) => {
+                let mode = parse_mode(mode).ok_or_else(|| invalid("bad policy mode"))?;
+                builder.policy_mode(mode);
+            },
+            ("only-ports", ports) => {
+                let ports = ports.iter().map(|port| port.parse()).collect::<Result<_, _>>();
+                builder.only_ports(ports.map_err(|_| invalid("bad port"))?);
+            },
+            ("host-strategy", [pattern, args @ ..]) => {
+                let strategy = parse_strategy(args).ok_or_else(|| invalid("bad strategy"))?;
+                builder.host_strategy(pattern, strategy);
+            },
+            ("deterministic", []) => {
+                builder.deterministic();
+            },
+            _ => return Err(invalid("unknown option")),
+        }
+    }
+
+    Ok(builder.into_config())
+}
+
+// read the environment, see DetourConfig::from_env
+pub(crate) fn from_env() -> io::Result<DetourConfig> {
+    let mut builder = DetourBuilder::new();
+
+    if let Some(strategy) = var("DETOUR_STRATEGY")? {
+        let args: Vec<&str> = strategy.split_whitespace().collect();
+        let strategy = parse_strategy(&args);
+        builder.strategy(strategy.ok_or_else(|| invalid_var("DETOUR_STRATEGY", "bad strategy"))?);
+    }
+    if let Some(n) = var("DETOUR_SPLIT")? {
+        let n = n.trim().parse().map_err(|_| invalid_var("DETOUR_SPLIT", "bad number"))?;
+        builder.strategy(SplitStrategy::FixedOffset(n));
+    }
+    if let Some(fallback) = var("DETOUR_FALLBACK")? {
+        let args: Vec<&str> = fallback.split_whitespace().collect();
+        let fallback = parse_fallback(&args);
+        builder.fallback(fallback.ok_or_else(|| invalid_var("DETOUR_FALLBACK", "bad fallback"))?);
+    }
+    if let Some(n) = var("DETOUR_MAX_FRAGMENTS")? {
+        let n = n.trim().parse().map_err(|_| invalid_var("DETOUR_MAX_FRAGMENTS", "bad number"))?;
+        builder.max_fragments(n);
+    }
+    if let Some(n) = var("DETOUR_RETRY")? {
+        let n = n.trim().parse().map_err(|_| invalid_var("DETOUR_RETRY", "bad number"))?;
+        builder.retry(n);
+    }
+
+    Ok(builder.into_config())
+}
+
+// an unset variable is left to the default; an empty one too
+fn var(name: &str) -> io::Result<Option<String>> {
+    match std::env::var(name) {
+        Ok(value) if value.trim().is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => Err(invalid_var(name, "not unicode")),
+    }
+}
+
+fn invalid_var(name: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, what))
+}
+
+fn parse_strategy(args: &[&str]) -> Option<SplitStrategy> {
+    match args {
+        ["sni-midpoint"] => Some(SplitStrategy::SniMidpoint),
+        ["sni-fraction", f] => f.parse().ok().map(SplitStrategy::SniFraction),
+        ["before-sni"] => Some(SplitStrategy::BeforeSni),
+        ["after-sni"] => Some(SplitStrategy::AfterSni),
+        ["fixed-offset", n] => n.parse().ok().map(SplitStrategy::FixedOffset),
+        ["from-end", n] => n.parse().ok().map(SplitStrategy::FromEnd),
+        ["random"] => Some(SplitStrategy::Random),
+        ["every-byte"] => Some(SplitStrategy::EveryByte),
+        ["identity"] => Some(SplitStrategy::Identity),
+        _ => None,
+    }
+}
+
+fn parse_fallback(args: &[&str]) -> Option<FallbackStrategy> {
+    match args {
+        ["midpoint"] => Some(FallbackStrategy::Midpoint),
+        ["passthrough"] => Some(FallbackStrategy::Passthrough),
+        ["fixed", n] => n.parse().ok().map(FallbackStrategy::Fixed),
+        _ => None,
+    }
+}
+
+fn parse_mode(mode: &str) -> Option<PolicyMode> {
+    match mode {
+        "allowlist" => Some(PolicyMode::Allowlist),
+        "denylist" => Some(PolicyMode::Denylist),
+        _ => None,
+    }
+}
+
+fn parse_flag(flag: &str) -> Option<bool> {
+    match flag {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_rules() {
+        let exact = HostRule::new("Example.com.");
+        assert!(exact.matches("example.com"));
+        assert!(exact.matches("EXAMPLE.COM."));
+        assert!(!exact.matches("www.example.com"));
+
+        // a wildcard is for the subdomains alone
+        let suffix = HostRule::new("*.example.com");
+        assert!(suffix.matches("www.example.com"));
+        assert!(suffix.matches("a.b.Example.Com"));
+        assert!(!suffix.matches("example.com"));
+        assert!(!suffix.matches("badexample.com"));
+        assert!(!suffix.matches("é.com"));
+    }
+
+    #[test]
+    fn policy_file() {
+        let policy = b"
+            # a comment, and blank lines, are skipped
+
+            strategy sni-fraction 0.25
+            fallback fixed 3
+            max-fragments 4
+            min-record-len 100
+            flush-between on
+            first-write-only off
+            retry 2
+            enable-for *.example.com
+            policy-mode denylist
+            only-ports 443 8443
+            host-strategy example.org from-end 7
+            deterministic
+        ";
+        let config = parse(&policy[..]).unwrap();
+        assert_eq!(config.strategy, SplitStrategy::SniFraction(0.25));
+        assert_eq!(config.fallback, FallbackStrategy::Fixed(3));
+        assert_eq!(config.max_fragments, 4);
+        assert_eq!(config.min_record_len, 100);
+        assert!(config.flush_between && !config.first_write_only && config.deterministic);
+        assert_eq!(config.retries, 2);
+        assert!(config.hosts[0].matches("www.example.com"));
+        assert_eq!(config.policy_mode, PolicyMode::Denylist);
+        assert_eq!(config.ports, [443, 8443]);
+        assert_eq!(config.host_strategies[0].1, SplitStrategy::FromEnd(7));
+
+        // an empty file keeps every default
+        let config = parse(&b""[..]).unwrap();
+        assert_eq!(config.strategy, SplitStrategy::SniMidpoint);
+        assert_eq!(config.ports, [443]);
+
+        let config = parse(&b"strategy identity"[..]).unwrap();
+        assert_eq!(config.strategy, SplitStrategy::Identity);
+    }
+
+    #[test]
+    fn policy_errors() {
+        let error = |policy: &str| parse(policy.as_bytes()).unwrap_err();
+
+        let e = error("strategy sni-midpoint\n\nflush-between yes");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "policy line 3: expected on or off: flush-between yes");
+        assert!(error("strategy middle").to_string().contains("bad strategy"));
+        assert!(error("max-fragments many").to_string().contains("bad number"));
+        assert!(error("only-ports 443 https").to_string().contains("bad port"));
+        assert!(error("retry").to_string().contains("unknown option"));
+        assert!(error("fragment-harder on").to_string().contains("unknown option"));
+    }
+
+    // the only test touching these variables, so that none reads them
+    // halfway through
+    #[test]
+    fn environment() {
+        std::env::set_var("DETOUR_STRATEGY", "sni-fraction 0.25");
+        std::env::set_var("DETOUR_FALLBACK", "passthrough");
+        std::env::set_var("DETOUR_MAX_FRAGMENTS", " 4 ");
+        std::env::set_var("DETOUR_RETRY", "");
+        let config = from_env().unwrap();
+        assert_eq!(config.strategy, SplitStrategy::SniFraction(0.25));
+        assert_eq!(config.fallback, FallbackStrategy::Passthrough);
+        assert_eq!(config.max_fragments, 4);
+        assert_eq!(config.retries, 0);
+
+        // DETOUR_SPLIT takes over DETOUR_STRATEGY
+        std::env::set_var("DETOUR_SPLIT", "3");
+        assert_eq!(from_env().unwrap().strategy, SplitStrategy::FixedOffset(3));
+
+        std::env::set_var("DETOUR_MAX_FRAGMENTS", "many");
+        let e = from_env().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "DETOUR_MAX_FRAGMENTS: bad number");
+
+        for var in ["STRATEGY", "SPLIT", "FALLBACK", "MAX_FRAGMENTS", "RETRY"] {
+            std::env::remove_var(format!("DETOUR_{}", var));
+        }
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_rule() {
+        let rule = HostRule::Regex(regex::Regex::new(r"^(www\.)?example\.(com|org)$").unwrap());
+        assert!(rule.matches("example.org."));
+        assert!(rule.matches("www.example.com"));
+        assert!(!rule.matches("cdn.example.com"));
+    }
+}
@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpStream;
+
+// protocol reference from haproxy's proxy-protocol.txt
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// the version of HAProxy's PROXY protocol header to send
+///
+/// the header is written on the raw stream right after it's connected,
+/// before anything else. it tells the addresses of the tcp socket, found
+/// through [`AsTcpStream`](crate::AsTcpStream) by a connector reaching it,
+/// see `HttpsConnector::with_tcp_access`; a stream without one gets an
+/// `UNKNOWN` (v1) or `LOCAL` (v2) header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// the human readable header
+    V1,
+    /// the binary header
+    V2,
+}
+
+impl ProxyProtocol {
+    // the header for the connection of `sock`, from us to the server
+    pub(crate) fn header(&self, sock: Option<&TcpStream>) -> Vec<u8> {
+        let addrs = sock.and_then(|sock| {
+            Some((sock.local_addr().ok()?, sock.peer_addr().ok()?))
+        });
+
+        match self {
+            ProxyProtocol::V1 => v1(addrs).into_bytes(),
+            ProxyProtocol::V2 => v2(addrs),
+        }
+    }
+}
+
+fn v1(addrs: Option<(SocketAddr, SocketAddr)>) -> String {
+    match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            src.ip(), dst.ip(), src.port(), dst.port()
+        ),
+        Some((SocketAddr::V6(src), SocketAddr::V6(dst))) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            src.ip(), dst.ip(), src.port(), dst.port()
+        ),
+        _ => String::from("PROXY UNKNOWN\r\n"),
+    }
+}
+
+fn v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+
+    let mut body = Vec::with_capacity(36);
+    let (command, family) = match addrs {
+        Some((SocketAddr::V4(src), SocketAddr::V4(dst))) => {
+            body.extend_from_slice(&src.ip().octets());
+            body.extend_from_slice(&dst.ip().octets());
+            body.extend_from_slice(&src.port().to_be_bytes());
+            body.extend_from_slice(&dst.port().to_be_bytes());
+            (V2_PROXY, V2_TCP4)
+        },
+        Some((SocketAddr::V6(src), SocketAddr::V6(dst))) => {
+            body.extend_from_slice(&src.ip().octets());
+            body.extend_from_slice(&dst.ip().octets());
+            body.extend_from_slice(&src.port().to_be_bytes());
+            body.extend_from_slice(&dst.port().to_be_bytes());
+            (V2_PROXY, V2_TCP6)
+        },
+        _ => (V2_LOCAL, V2_UNSPEC),
+    };
+
+    header.push(command);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn addrs(src: &str, dst: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    #[test]
+    fn v1_header() {
+        let v4 = addrs("192.0.2.1:50000", "198.51.100.2:443");
+        assert_eq!(v1(v4), "PROXY TCP4 192.0.2.1 198.51.100.2 50000 443\r\n");
+        let v6 = addrs("[2001:db8::1]:50000", "[2001:db8::2]:443");
+        assert_eq!(v1(v6), "PROXY TCP6 2001:db8::1 2001:db8::2 50000 443\r\n");
+        // the families don't mix
+        assert_eq!(v1(addrs("192.0.2.1:1", "[2001:db8::2]:2")), "PROXY UNKNOWN\r\n");
+        assert_eq!(v1(None), "PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_header() {
+        let header = v2(addrs("192.0.2.1:50000", "198.51.100.2:443"));
+        assert_eq!(header[..12], V2_SIGNATURE[..]);
+        assert_eq!(header[12..16], [V2_PROXY, V2_TCP4, 0, 12]);
+        assert_eq!(header[16..], [192, 0, 2, 1, 198, 51, 100, 2, 0xc3, 0x50, 0x01, 0xbb]);
+
+        let header = v2(addrs("[2001:db8::1]:50000", "[2001:db8::2]:443"));
+        assert_eq!(header[12..16], [V2_PROXY, V2_TCP6, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+
+        assert_eq!(v2(None)[12..], [V2_LOCAL, V2_UNSPEC, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn from_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (src, dst) = (tcp.local_addr().unwrap(), tcp.peer_addr().unwrap());
+
+        let expected = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n", src.port(), dst.port());
+        assert_eq!(ProxyProtocol::V1.header(Some(&tcp)), expected.into_bytes());
+        assert_eq!(ProxyProtocol::V1.header(None), b"PROXY UNKNOWN\r\n");
+    }
+}
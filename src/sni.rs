@@ -0,0 +1,105 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::future::Future;
+
+use tokio_native_tls::TlsConnector;
+
+tokio::task_local! {
+    static SNI_OVERRIDE: String;
+    static TLS_OVERRIDE: TlsConnector;
+}
+
+/// run `fut` with connections made in it sending `sni` as the hostname
+///
+/// a connector is only given the uri, so there's no other way to tell it
+/// e.g. the `Host` header of a request to an ip address behind a CDN.
+/// wrap the request in this, e.g. `with_sni("example.com", client.get(uri))`,
+/// and the tls handshake verifies and sends `example.com` instead of the ip;
+/// the detour splits around it, too.
+///
+/// note that hyper pools connections by the uri alone; a pooled connection
+/// to the same address may have been made with a different hostname.
+pub async fn with_sni<F: Future>(sni: impl Into<String>, fut: F) -> F::Output {
+    SNI_OVERRIDE.scope(sni.into(), fut).await
+}
+
+// the hostname set by `with_sni` for the current task, if any
+pub(crate) fn sni_override() -> Option<String> {
+    SNI_OVERRIDE.try_with(String::clone).ok()
+}
+
+/// run `fut` with connections made in it using `tls` for the handshake
+///
+/// this is for a request needing other tls options than the rest, most
+/// often other ALPN protocols, e.g. when probing which ones a server
+/// speaks: build a `TlsConnector` with `request_alpns` and wrap the request
+/// in this, like [`with_sni`]. the options go into the ClientHello as the
+/// tls backend writes it; the detour only cuts it, and can't rewrite any
+/// extension without breaking the handshake.
+///
+/// the same note on pooled connections as for [`with_sni`] applies.
+pub async fn with_tls<F: Future>(tls: TlsConnector, fut: F) -> F::Output {
+    TLS_OVERRIDE.scope(tls, fut).await
+}
+
+// the connector set by `with_tls` for the current task, if any
+pub(crate) fn tls_override() -> Option<TlsConnector> {
+    TLS_OVERRIDE.try_with(TlsConnector::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::service::Service;
+    use hyper::Uri;
+
+    use super::*;
+    use crate::mock::{tls_connector, tls_server};
+    use crate::{DetourBuilder, HttpsConnector, MaybeHttpsStream};
+
+    fn ip(port: u16) -> Uri {
+        format!("https://127.0.0.1:{}/", port).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sni_scope() {
+        assert_eq!(sni_override(), None);
+        let inner = with_sni("example.com", async { sni_override() }).await;
+        assert_eq!(inner.as_deref(), Some("example.com"));
+        assert_eq!(sni_override(), None);
+    }
+
+    #[tokio::test]
+    async fn sni_for_ip() {
+        let port = tls_server(0).await;
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut builder = HttpsConnector::builder();
+        let mut https = builder.tls(tls_connector()).detour(detour).build().unwrap();
+
+        // the certificate is for localhost, not the ip
+        assert!(https.call(ip(port)).await.is_err());
+
+        // which the handshake sends, verifies, and the detour splits around
+        let conn = with_sni("localhost", https.call(ip(port))).await.unwrap();
+        let detour = match &conn {
+            MaybeHttpsStream::Https(tls) => tls.get_ref().get_ref().get_ref(),
+            MaybeHttpsStream::Http(_) => panic!("not a tls connection"),
+        };
+        assert_eq!(detour.sni(), Some("localhost"));
+        assert!(detour.fragment_info().is_some());
+    }
+
+    #[tokio::test]
+    async fn tls_for_request() {
+        let port = tls_server(0).await;
+        let localhost: Uri = format!("https://localhost:{}/", port).parse().unwrap();
+        // trusting the system's roots alone, which don't know of the server
+        let mut https = HttpsConnector::new();
+        assert!(https.call(localhost.clone()).await.is_err());
+
+        let conn = with_tls(tls_connector(), https.call(localhost)).await;
+        assert!(conn.unwrap().is_https());
+    }
+}
@@ -0,0 +1,129 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::detour::Detour;
+use crate::stream::{MaybeHttpsStream, TlsStream};
+
+/// access to the tcp socket at the bottom of a connection, if any
+///
+/// it's implemented down the whole stack of a connection made by
+/// `HttpsConnector<HttpConnector>`, so socket options (nodelay, keepalive,
+/// ...) can be tuned on the raw stream under the detour and tls layers.
+/// the default method returns `None`, so a stream which isn't backed by
+/// tcp can opt out with an empty impl.
+pub trait AsTcpStream {
+    /// the underlying tcp stream
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+
+    /// the local address of the underlying tcp stream, e.g. for logging.
+    /// fails with `Unsupported` if there's none.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.as_tcp_stream().ok_or_else(not_tcp)?.local_addr()
+    }
+
+    /// the remote address of the underlying tcp stream. fails with
+    /// `Unsupported` if there's none.
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.as_tcp_stream().ok_or_else(not_tcp)?.peer_addr()
+    }
+}
+
+fn not_tcp() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "not backed by a tcp stream")
+}
+
+impl AsTcpStream for TcpStream {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl<T: AsyncWrite + AsTcpStream> AsTcpStream for Detour<T> {
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        (**self).as_tcp_stream()
+    }
+}
+
+impl<T> AsTcpStream for TlsStream<T>
+where
+    T: AsyncRead + AsyncWrite + AsTcpStream + Unpin,
+{
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        self.get_ref().get_ref().get_ref().as_tcp_stream()
+    }
+}
+
+impl<T> AsTcpStream for MaybeHttpsStream<T>
+where
+    T: AsyncRead + AsyncWrite + AsTcpStream + Unpin,
+{
+    fn as_tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            MaybeHttpsStream::Http(s) => s.as_tcp_stream(),
+            MaybeHttpsStream::Https(s) => s.as_tcp_stream(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::service::Service;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::mock::{tls_connector, tls_server, Mock};
+    use crate::HttpsConnector;
+
+    // every layer of a connection reaches the same socket
+    #[tokio::test]
+    async fn through_the_stack() {
+        let mut https = HttpsConnector::builder().tls(tls_connector()).build().unwrap();
+
+        let port = tls_server(0).await;
+        let uri = format!("https://localhost:{}/", port).parse().unwrap();
+        let conn = https.call(uri).await.unwrap();
+        let tcp = conn.as_tcp_stream().unwrap();
+        assert_eq!(tcp.peer_addr().unwrap().port(), port);
+        match &conn {
+            MaybeHttpsStream::Https(tls) => {
+                let detour = tls.get_ref().get_ref().get_ref();
+                assert!(std::ptr::eq(tls.as_tcp_stream().unwrap(), tcp));
+                assert!(std::ptr::eq(detour.as_tcp_stream().unwrap(), tcp));
+            },
+            MaybeHttpsStream::Http(_) => panic!("not a tls connection"),
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let uri = format!("http://127.0.0.1:{}/", port).parse().unwrap();
+        let conn = https.call(uri).await.unwrap();
+        assert!(!conn.is_https());
+        assert_eq!(conn.as_tcp_stream().unwrap().peer_addr().unwrap().port(), port);
+    }
+
+    impl AsTcpStream for Mock {}
+
+    #[tokio::test]
+    async fn addrs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (local, peer) = (tcp.local_addr().unwrap(), tcp.peer_addr().unwrap());
+
+        let detour = Detour::new(tcp);
+        assert_eq!(AsTcpStream::local_addr(&detour).unwrap(), local);
+        assert_eq!(AsTcpStream::peer_addr(&detour).unwrap(), peer);
+
+        // a stream without a socket beneath has no address
+        let e = AsTcpStream::peer_addr(&Detour::new(Mock::new())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,341 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::{service::Service, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// SOCKS protocol reference from RFC 1928 and RFC 1929
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const AUTH_VERSION: u8 = 0x01;
+const CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+
+// enum { ... } ATYP;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+const DEFAULT_PORT: u16 = 1080;
+
+/// a connector which tunnels every connection through a SOCKS5 proxy
+///
+/// the proxy is dialed with the inner connector, so wrap it in an
+/// `HttpsConnector` to run the detoured handshake over the tunnel:
+/// `HttpsConnector::from((Socks5Connector::new(http, proxy), tls))`.
+/// if the inner one is an `HttpConnector`, turn off its `enforce_http`, and
+/// add `.with_tcp_access()` to reach the tcp stream to the proxy.
+#[derive(Clone)]
+pub struct Socks5Connector<T> {
+    inner: T,
+    proxy: Uri,
+    auth: Option<(String, String)>,
+}
+
+impl<T> Socks5Connector<T> {
+    /// tunnel through the proxy at `proxy`, e.g. `socks5://127.0.0.1:1080`.
+    ///
+    /// only the host and the port of the uri are used; the port defaults
+    /// to 1080.
+    pub fn new(inner: T, proxy: Uri) -> Self {
+        let host = proxy.host().unwrap_or("");
+        let port = proxy.port_u16().unwrap_or(DEFAULT_PORT);
+        // the inner connector only knows how to dial http uris
+        let proxy = format!("http://{}:{}", host, port)
+            .parse()
+            .unwrap_or(proxy);
+
+        Self {
+            inner,
+            proxy,
+            auth: None,
+        }
+    }
+
+    /// authenticate to the proxy with a username and a password. both must
+    /// be 1 to 255 bytes long.
+    pub fn set_auth(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        self.auth = Some((username.into(), password.into()));
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Socks5Connector<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Connector")
+            .field("inner", &self.inner)
+            .field("proxy", &self.proxy)
+            .field("username", &self.auth.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+impl<T> Service<Uri> for Socks5Connector<T>
+where
+    T: Service<Uri>,
+    T::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    T::Future: Send + 'static,
+    T::Error: Into<BoxError>,
+{
+    type Response = T::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<T::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let connecting = self.inner.call(self.proxy.clone());
+        let auth = self.auth.clone();
+
+        Box::pin(async move {
+            let mut sock = connecting.await.map_err(Into::into)?;
+            let host = dst.host().ok_or(Socks5Error("uri has no host"))?;
+            let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+
+            handshake(&mut sock, auth.as_ref()).await?;
+            connect(&mut sock, host, port).await?;
+            Ok(sock)
+        })
+    }
+}
+
+// pick an auth method, and authenticate if needed
+async fn handshake<S>(sock: &mut S, auth: Option<&(String, String)>) -> Result<(), BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let greeting: &[u8] = match auth {
+        Some(_) => &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD],
+        None => &[VERSION, 1, NO_AUTH],
+    };
+    sock.write_all(greeting).await?;
+
+    let mut reply = [0; 2];
+    sock.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(Socks5Error("not a socks5 proxy").into());
+    }
+
+    match (reply[1], auth) {
+        (NO_AUTH, _) => Ok(()),
+        (USERNAME_PASSWORD, Some((user, pass))) => authenticate(sock, user, pass).await,
+        (NO_ACCEPTABLE_METHODS, _) => Err(Socks5Error("no acceptable auth method").into()),
+        _ => Err(Socks5Error("unexpected auth method").into()),
+    }
+}
+
+async fn authenticate<S>(sock: &mut S, user: &str, pass: &str) -> Result<(), BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // uint8 ver; uint8 ulen; opaque uname[ulen]; uint8 plen; opaque passwd[plen];
+    let (user, pass) = (user.as_bytes(), pass.as_bytes());
+    if !(1..=255).contains(&user.len()) || !(1..=255).contains(&pass.len()) {
+        return Err(Socks5Error("username or password too long or empty").into());
+    }
+
+    let mut request = Vec::with_capacity(3 + user.len() + pass.len());
+    request.push(AUTH_VERSION);
+    request.push(user.len() as u8);
+    request.extend_from_slice(user);
+    request.push(pass.len() as u8);
+    request.extend_from_slice(pass);
+    sock.write_all(&request).await?;
+
+    let mut reply = [0; 2];
+    sock.read_exact(&mut reply).await?;
+    if reply[1] != SUCCEEDED {
+        return Err(Socks5Error("authentication failed").into());
+    }
+    Ok(())
+}
+
+// ask the proxy to open the tunnel to host:port
+async fn connect<S>(sock: &mut S, host: &str, port: u16) -> Result<(), BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // uint8 ver; uint8 cmd; uint8 rsv; uint8 atyp; opaque dst_addr; uint16 dst_port;
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+        // let the proxy resolve the name, so it doesn't leak from here
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(Socks5Error("hostname too long").into());
+            }
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    sock.write_all(&request).await?;
+
+    // same layout as the request, with the bound address
+    let mut reply = [0; 4];
+    sock.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(Socks5Error("not a socks5 proxy").into());
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(Socks5Error("proxy refused to connect").into());
+    }
+
+    let addr_len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => sock.read_u8().await? as usize,
+        _ => return Err(Socks5Error("unexpected address type").into()),
+    };
+    // we don't care where the proxy is bound to
+    let mut bound = vec![0; addr_len + 2];
+    sock.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Socks5Error(&'static str);
+
+impl fmt::Display for Socks5Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "socks5: {}", self.0)
+    }
+}
+
+impl std::error::Error for Socks5Error {}
+
+#[cfg(test)]
+mod tests {
+    use hyper::client::connect::HttpConnector;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::client::HttpsConnector;
+    use crate::config::DetourBuilder;
+    use crate::mock::{tls_connector, tls_server};
+    use crate::stream::MaybeHttpsStream;
+
+    // a socks5 proxy on the returned port, tunneling to anywhere; with
+    // `auth`, it asks for that username and password
+    async fn proxy(auth: Option<(&'static str, &'static str)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(tunnel(tcp, auth));
+            }
+        });
+        port
+    }
+
+    async fn tunnel(mut client: TcpStream, auth: Option<(&str, &str)>) -> std::io::Result<()> {
+        let mut greeting = [0; 2];
+        client.read_exact(&mut greeting).await?;
+        let mut methods = vec![0; greeting[1] as usize];
+        client.read_exact(&mut methods).await?;
+
+        if let Some((user, pass)) = auth {
+            client.write_all(&[VERSION, USERNAME_PASSWORD]).await?;
+            let mut request = vec![0; 2];
+            client.read_exact(&mut request).await?;
+            let mut given_user = vec![0; request[1] as usize];
+            client.read_exact(&mut given_user).await?;
+            let mut given_pass = vec![0; client.read_u8().await? as usize];
+            client.read_exact(&mut given_pass).await?;
+
+            let ok = given_user == user.as_bytes() && given_pass == pass.as_bytes();
+            client.write_all(&[AUTH_VERSION, if ok { SUCCEEDED } else { 1 }]).await?;
+            if !ok {
+                return Ok(());
+            }
+        } else {
+            client.write_all(&[VERSION, NO_AUTH]).await?;
+        }
+
+        // the client sends a name, and leaves it for the proxy to resolve
+        let mut request = [0; 4];
+        client.read_exact(&mut request).await?;
+        assert_eq!(request[3], DOMAIN_NAME);
+        let mut host = vec![0; client.read_u8().await? as usize];
+        client.read_exact(&mut host).await?;
+        let port = client.read_u16().await?;
+
+        let mut target = TcpStream::connect((&*String::from_utf8_lossy(&host), port)).await?;
+        client.write_all(&[VERSION, SUCCEEDED, 0, IPV4, 0, 0, 0, 0, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut target).await?;
+        Ok(())
+    }
+
+    type Connector = HttpsConnector<Socks5Connector<HttpConnector>>;
+
+    // a connector tunneling through the proxy on `port`
+    fn connector(port: u16, auth: Option<(&str, &str)>) -> Connector {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let proxy = format!("socks5://127.0.0.1:{}", port).parse().unwrap();
+        let mut socks = Socks5Connector::new(http, proxy);
+        if let Some((user, pass)) = auth {
+            socks.set_auth(user, pass);
+        }
+
+        let mut detour = DetourBuilder::new();
+        detour.only_ports(Vec::new());
+        let mut https = HttpsConnector::from((socks, tls_connector())).with_tcp_access();
+        https.set_detour(&detour);
+        https
+    }
+
+    // the number of records the hello went out in, through the tunnel
+    async fn connect(https: &mut Connector, port: u16) -> Result<usize, BoxError> {
+        let dst = format!("https://localhost:{}/", port).parse().unwrap();
+        match https.call(dst).await? {
+            MaybeHttpsStream::Https(tls) => {
+                Ok(tls.get_ref().get_ref().get_ref().fragment_sizes().len())
+            },
+            MaybeHttpsStream::Http(_) => panic!("not a tls connection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_auth() {
+        let server = tls_server(0).await;
+        let mut https = connector(proxy(None).await, None);
+        assert_eq!(connect(&mut https, server).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn username_password() {
+        let server = tls_server(0).await;
+        let proxy = proxy(Some(("user", "pass"))).await;
+
+        let mut https = connector(proxy, Some(("user", "pass")));
+        assert_eq!(connect(&mut https, server).await.unwrap(), 2);
+
+        let mut https = connector(proxy, Some(("user", "wrong")));
+        let e = connect(&mut https, server).await.unwrap_err();
+        assert_eq!(e.to_string(), "socks5: authentication failed");
+    }
+}
@@ -0,0 +1,562 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "experimental")]
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::hello::{find_hostname, find_sni};
+
+/// where to cut the ClientHello into fragments
+///
+/// every offset is chosen so that both fragments carry some payload. if a
+/// strategy needs the server_name extension but the hello has none, the
+/// [`FallbackStrategy`] decides what happens instead.
+///
+/// an offset may also fall into a handshake message following the hello in
+/// the same record, which the server reassembles just as well.
+///
+/// any offset is safe for the handshake, even one inside the binders of the
+/// pre_shared_key extension of a resumed session: the server reassembles
+/// the hello before hashing it, so it checks the binders against the same
+/// bytes either way.
+///
+/// every fragment is a well-formed record, with a length matching its
+/// payload. a record lying about its length would throw the server off the
+/// stream just as much as a DPI box, so the only strategy doing that is an
+/// experiment, behind the `experimental` feature.
+///
+/// the cuts are always made at the record layer. tls has no framing for a
+/// handshake message in pieces, unlike dtls with its fragment offsets: a
+/// hello split into several handshake messages would be several broken
+/// hellos to any server. a hello spanning several records is the only
+/// handshake fragmentation tls allows, and is what every strategy does.
+///
+/// a fragment is never longer than the record it's cut from, so there's no
+/// size limit for the fragments to respect. the record_size_limit and
+/// max_fragment_length extensions of the hello limit what the server
+/// sends; the hello offers them, and isn't bound by them itself.
+#[derive(Clone, Default)]
+pub enum SplitStrategy {
+    /// cut around the middle of the server_name extension (the default)
+    #[default]
+    SniMidpoint,
+    /// cut inside the hostname, at the given fraction of its length.
+    ///
+    /// `0.5` cuts the hostname in the middle, `0.25` after its first quarter.
+    /// the fraction is clamped to `0.0..=1.0`.
+    SniFraction(f32),
+    /// cut right before the hostname, so the second fragment starts with it
+    BeforeSni,
+    /// cut right after the hostname, so it ends the first fragment. a hello
+    /// ending with the hostname can't be cut there, and falls back.
+    AfterSni,
+    /// cut the given number of bytes into the record's payload, clamped so
+    /// that both fragments carry some payload
+    FixedOffset(usize),
+    /// cut the given number of bytes before the end of the record's
+    /// payload, clamped the same way, so the second fragment carries them
+    FromEnd(usize),
+    /// cut at a random place, drawn from `DetourBuilder::seed` if set
+    Random,
+    /// cut after every single byte of the payload.
+    ///
+    /// this is pathological, but useful to stress DPI and to confirm that
+    /// servers reassemble arbitrary fragmentation. the number of records is
+    /// still capped by `DetourBuilder::max_fragments`, so raise it as well.
+    EveryByte,
+    /// cut nowhere, leaving a single record identical to the original.
+    ///
+    /// this is for debugging: the hello still goes through the whole
+    /// fragmenting machinery, and is told of as fragmented into one, with
+    /// [`FragmentInfo`](crate::FragmentInfo) and the like, so a detour which
+    /// ran but changed no byte can be told apart from one which skipped it.
+    Identity,
+    /// cut with the strategy paired with the network of the connection,
+    /// as given by `DetourBuilder::network` or `DetourHandle::set_network`.
+    ///
+    /// the first pair for the network wins; one for
+    /// [`NetworkHint::Unknown`] stands in for any network without a pair
+    /// of its own. without either, the [`FallbackStrategy`] applies.
+    ByNetwork(Vec<(NetworkHint, SplitStrategy)>),
+    /// cut like `FixedOffset` at the first number, then have the header of
+    /// the leading record claim the second number of bytes more than it
+    /// holds, up to the most a length can say.
+    ///
+    /// this is an experiment, for DPI boxes which wait for the bytes
+    /// claimed: a server parsing the records as the spec says takes them
+    /// from the next record, and loses the stream from there, so it breaks
+    /// the handshake with any strict one. only with the `experimental`
+    /// feature.
+    #[cfg(feature = "experimental")]
+    MismatchedLength(usize, u16),
+    /// cut wherever the closure says.
+    ///
+    /// it's given the whole ClientHello record, header included, and
+    /// returns the offsets into it to cut at, e.g. from a parser of your
+    /// own. offsets leaving a fragment without payload are dropped, and
+    /// the rest are capped by `DetourBuilder::max_fragments` like those of
+    /// any other strategy; without any left, the [`FallbackStrategy`]
+    /// applies. it's called in the middle of a write, so keep it quick.
+    Fn(Arc<SplitFn>),
+}
+
+// offsets to cut a ClientHello record at, see SplitStrategy::Fn
+type SplitFn = dyn Fn(&[u8]) -> Vec<usize> + Send + Sync;
+
+impl fmt::Debug for SplitStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitStrategy::SniMidpoint => f.write_str("SniMidpoint"),
+            SplitStrategy::SniFraction(fraction) => {
+                f.debug_tuple("SniFraction").field(fraction).finish()
+            },
+            SplitStrategy::BeforeSni => f.write_str("BeforeSni"),
+            SplitStrategy::AfterSni => f.write_str("AfterSni"),
+            SplitStrategy::FixedOffset(n) => f.debug_tuple("FixedOffset").field(n).finish(),
+            SplitStrategy::FromEnd(n) => f.debug_tuple("FromEnd").field(n).finish(),
+            SplitStrategy::Random => f.write_str("Random"),
+            SplitStrategy::EveryByte => f.write_str("EveryByte"),
+            SplitStrategy::Identity => f.write_str("Identity"),
+            SplitStrategy::ByNetwork(pairs) => f.debug_tuple("ByNetwork").field(pairs).finish(),
+            #[cfg(feature = "experimental")]
+            SplitStrategy::MismatchedLength(n, by) => {
+                f.debug_tuple("MismatchedLength").field(n).field(by).finish()
+            },
+            SplitStrategy::Fn(_) => f.write_str("Fn"),
+        }
+    }
+}
+
+// closures are equal only to themselves
+impl PartialEq for SplitStrategy {
+    fn eq(&self, other: &Self) -> bool {
+        use SplitStrategy::*;
+
+        match (self, other) {
+            (SniMidpoint, SniMidpoint)
+            | (BeforeSni, BeforeSni)
+            | (AfterSni, AfterSni)
+            | (Random, Random)
+            | (EveryByte, EveryByte)
+            | (Identity, Identity) => true,
+            (SniFraction(a), SniFraction(b)) => a == b,
+            (FixedOffset(a), FixedOffset(b)) => a == b,
+            (FromEnd(a), FromEnd(b)) => a == b,
+            (ByNetwork(a), ByNetwork(b)) => a == b,
+            #[cfg(feature = "experimental")]
+            (MismatchedLength(a, x), MismatchedLength(b, y)) => a == b && x == y,
+            (Fn(a), Fn(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl SplitStrategy {
+    // the strategy to cut with on `network`; a ByNetwork without a pair for
+    // it is left, to stand in with its pair for Unknown, if any
+    pub(crate) fn for_network(&self, network: &NetworkHint) -> &SplitStrategy {
+        match self {
+            SplitStrategy::ByNetwork(pairs) => pairs
+                .iter()
+                .find(|(hint, _)| hint == network)
+                .map_or(self, |(_, strategy)| strategy),
+            strategy => strategy,
+        }
+    }
+
+    // indices into the record to cut at; `hello` is a whole ClientHello
+    // record, and `rng` where a random cut comes from
+    pub(crate) fn split_points(&self, hello: &[u8], rng: &Rng) -> Option<Vec<usize>> {
+        let point = match *self {
+            // a hello we can't parse falls back just like one without SNI
+            SplitStrategy::SniMidpoint => find_sni(hello).ok().flatten(),
+            SplitStrategy::SniFraction(fraction) => {
+                let fraction = fraction.clamp(0.0, 1.0);
+                find_hostname(hello).ok().flatten().map(|(start, len)| {
+                    start + (len as f32 * fraction) as usize
+                })
+            },
+            SplitStrategy::BeforeSni => find_hostname(hello).ok().flatten().map(|(start, _)| start),
+            SplitStrategy::AfterSni => {
+                find_hostname(hello).ok().flatten()
+                    .map(|(start, len)| start + len)
+                    .filter(|&end| end < hello.len())
+            },
+            SplitStrategy::FixedOffset(n) => {
+                return FallbackStrategy::Fixed(n).split_points(hello)
+            },
+            SplitStrategy::FromEnd(n) => {
+                let payload_len = hello.len() - 5;
+                Some(hello.len() - n.clamp(1, payload_len - 1))
+            },
+            SplitStrategy::Random => Some(random_split(hello, rng)),
+            SplitStrategy::EveryByte => return Some((6..hello.len()).collect()),
+            SplitStrategy::Identity => return Some(Vec::new()),
+            SplitStrategy::ByNetwork(ref pairs) => {
+                let (_, strategy) = pairs.iter().find(|(hint, _)| *hint == NetworkHint::Unknown)?;
+                return strategy.split_points(hello, rng);
+            },
+            #[cfg(feature = "experimental")]
+            SplitStrategy::MismatchedLength(n, _) => {
+                return FallbackStrategy::Fixed(n).split_points(hello)
+            },
+            SplitStrategy::Fn(ref f) => {
+                let points = f(hello);
+                return Some(points).filter(|points| !points.is_empty());
+            },
+        };
+
+        point.map(|point| vec![point])
+    }
+
+    // with MismatchedLength, have the leading record of a hello cut in
+    // several claim more than it holds; see there
+    #[cfg(feature = "experimental")]
+    pub(crate) fn mislabel(&self, fragments: &mut VecDeque<Vec<u8>>) {
+        if let SplitStrategy::MismatchedLength(_, by) = *self {
+            if fragments.len() >= 2 {
+                let first = &mut fragments[0];
+                let len = u16::from_be_bytes([first[3], first[4]]).saturating_add(by);
+                first[3..5].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// the network the connections go out on, as the application tells it
+///
+/// the crate can't tell networks apart by itself; give it the hint with
+/// `DetourBuilder::network`, switch it with `DetourHandle::set_network` as
+/// the network changes, and cut differently on each with
+/// [`SplitStrategy::ByNetwork`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub enum NetworkHint {
+    /// no hint given (the default)
+    #[default]
+    Unknown,
+    /// a wifi network
+    Wifi,
+    /// a cellular network
+    Cellular,
+    /// a wired network
+    Ethernet,
+    /// any other network, named by the application, e.g. a given ssid
+    Other(String),
+}
+
+/// what to do with a ClientHello the [`SplitStrategy`] can't cut,
+/// e.g. because it has no server_name extension
+///
+/// this is always the case when connecting to an ip address, which tls
+/// stacks leave out of the hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FallbackStrategy {
+    /// cut at the middle of the record's payload (the default)
+    #[default]
+    Midpoint,
+    /// send the hello as is, without fragmenting it
+    Passthrough,
+    /// cut the given number of bytes into the record's payload, clamped so
+    /// that both fragments carry some payload
+    Fixed(usize),
+}
+
+impl FallbackStrategy {
+    // same as SplitStrategy::split_points; None for passthrough
+    pub(crate) fn split_points(&self, hello: &[u8]) -> Option<Vec<usize>> {
+        let payload_len = hello.len() - 5;
+
+        let point = match *self {
+            // split the payload into half
+            FallbackStrategy::Midpoint => 5 + payload_len / 2,
+            FallbackStrategy::Passthrough => return None,
+            FallbackStrategy::Fixed(n) => 5 + n.clamp(1, payload_len - 1),
+        };
+
+        Some(vec![point])
+    }
+}
+
+// where the random cuts come from: fresh entropy for each, or a splitmix64
+// sequence from a seed, shared by every detour of a config
+#[derive(Default)]
+pub(crate) struct Rng(Option<AtomicU64>);
+
+impl Rng {
+    pub(crate) fn seeded(seed: u64) -> Self {
+        Self(Some(AtomicU64::new(seed)))
+    }
+
+    pub(crate) fn next(&self) -> u64 {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+        let state = match &self.0 {
+            Some(state) => state,
+            // every RandomState is seeded differently; good enough for a split
+            None => return RandomState::new().build_hasher().finish(),
+        };
+        let mut z = state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+// a clone goes on from where the sequence is, on its own
+impl Clone for Rng {
+    fn clone(&self) -> Self {
+        Self(self.0.as_ref().map(|state| AtomicU64::new(state.load(Ordering::Relaxed))))
+    }
+}
+
+impl fmt::Debug for Rng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Rng(seeded)"),
+            None => f.write_str("Rng(random)"),
+        }
+    }
+}
+
+// move each cut of `hello` by up to `jitter` of its payload either way,
+// keeping it inside the payload. a deterministic jitter is hashed from the
+// hello, with the fixed keys of DefaultHasher; any other is drawn from `rng`
+pub(crate) fn jittered(
+    points: Vec<usize>,
+    hello: &[u8],
+    jitter: f32,
+    deterministic: bool,
+    rng: &Rng,
+) -> Vec<usize> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+
+    let payload_len = hello.len() - 5;
+    let span = (payload_len as f32 * jitter) as usize;
+    if span == 0 || payload_len < 2 {
+        return points;
+    }
+
+    points
+        .into_iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let rand = if deterministic {
+                let mut hasher = DefaultHasher::new();
+                (hello, i).hash(&mut hasher);
+                hasher.finish()
+            } else {
+                rng.next()
+            };
+            // anywhere in point - span ..= point + span
+            let offset = (rand % (2 * span as u64 + 1)) as usize;
+            (point + offset).saturating_sub(span).clamp(6, hello.len() - 1)
+        })
+        .collect()
+}
+
+// pick a random place to cut, leaving both fragments non-empty
+fn random_split(data: &[u8], rng: &Rng) -> usize {
+    6 + (rng.next() % (data.len() - 6) as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "experimental")]
+    use crate::mock::Mock;
+    use crate::mock::hello;
+
+    #[test]
+    fn seeded_jitter() {
+        let hello = hello("example.com");
+        let points = vec![50, 100, 150, 200, 250];
+        let span = ((hello.len() - 5) as f32 * 0.1) as usize;
+
+        let rng = Rng::seeded(42);
+        let moved = jittered(points.clone(), &hello, 0.1, false, &rng);
+        for (point, moved) in points.iter().zip(&moved) {
+            assert!(point - span <= *moved && *moved <= point + span);
+        }
+        // each cut is moved by its own offset
+        let offsets: Vec<_> = points
+            .iter()
+            .zip(&moved)
+            .map(|(point, moved)| *moved as isize - *point as isize)
+            .collect();
+        assert!(offsets.windows(2).any(|w| w[0] != w[1]));
+
+        // the same seed moves them the same way again, and goes on after
+        assert_eq!(jittered(points.clone(), &hello, 0.1, false, &Rng::seeded(42)), moved);
+        assert_ne!(jittered(points, &hello, 0.1, false, &rng), moved);
+    }
+
+    #[test]
+    fn seeded_random() {
+        let hello = hello("example.com");
+        let cuts = |rng: &Rng| -> Vec<_> {
+            (0..8).map(|_| SplitStrategy::Random.split_points(&hello, rng).unwrap()[0]).collect()
+        };
+
+        let first = cuts(&Rng::seeded(7));
+        assert!(first.iter().all(|&cut| 6 <= cut && cut < hello.len()));
+        assert!(first.windows(2).any(|w| w[0] != w[1]));
+        assert_eq!(cuts(&Rng::seeded(7)), first);
+        assert_ne!(cuts(&Rng::seeded(8)), first);
+    }
+
+    #[test]
+    fn sni_fraction() {
+        let sni = b"sub.domain.example";
+        let hello = hello("sub.domain.example");
+        let start = hello.windows(sni.len()).position(|window| window == sni).unwrap();
+        let rng = Rng::default();
+
+        // "sub." and "sub.domain.ex" are the fractions rounded down; the
+        // fraction is clamped to the hostname
+        for (fraction, len) in [(0.25, 4), (0.75, 13), (-1.0, 0), (2.0, sni.len())] {
+            let points = SplitStrategy::SniFraction(fraction).split_points(&hello, &rng);
+            assert_eq!(points, Some(vec![start + len]), "{}", fraction);
+        }
+    }
+
+    #[test]
+    fn from_end() {
+        let hello = hello("example.com");
+
+        // the second fragment carries the last 40 bytes of the payload
+        let fragments = crate::fragment_record(&hello, SplitStrategy::FromEnd(40)).unwrap();
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments[1][5..], hello[hello.len() - 40..]);
+
+        // clamped so that both carry some
+        let rng = Rng::default();
+        let points = |n| SplitStrategy::FromEnd(n).split_points(&hello, &rng);
+        assert_eq!(points(0), Some(vec![hello.len() - 1]));
+        assert_eq!(points(hello.len()), Some(vec![6]));
+    }
+
+    #[cfg(feature = "experimental")]
+    #[tokio::test]
+    async fn mismatched_length() {
+        use tokio::io::AsyncWriteExt;
+
+        let hello = hello("example.com");
+        let fixed = crate::fragment_record(&hello, SplitStrategy::FixedOffset(8)).unwrap();
+        let strategy = SplitStrategy::MismatchedLength(8, 3);
+        let fragments = crate::fragment_record(&hello, strategy.clone()).unwrap();
+
+        // a leading record of 8 bytes claiming 11, then the rest as is
+        assert_eq!(fragments[0][..5], [0x16, 0x03, 0x01, 0x00, 11]);
+        assert_eq!(fragments[0][5..], hello[5..13]);
+        assert_eq!(fragments[1..], fixed[1..]);
+
+        // a detour sends the same, and still tells of the sizes it cut
+        let mut detour = crate::DetourBuilder::new().strategy(strategy).build(Mock::new());
+        detour.write_all(&hello).await.unwrap();
+        assert_eq!(detour.written(), fragments.concat());
+        assert_eq!(detour.fragment_sizes(), [8, hello.len() - 13]);
+
+        // the claim stops at the most a length can say
+        let strategy = SplitStrategy::MismatchedLength(8, u16::MAX);
+        let fragments = crate::fragment_record(&hello, strategy).unwrap();
+        assert_eq!(fragments[0][3..5], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn psk_binders() {
+        // a resumed session's pre_shared_key, the last extension: one
+        // identity and its age, then one binder of 32 bytes
+        let identity = [&7u16.to_be_bytes()[..], &[1; 7], &[0; 4]].concat();
+        let binder = [&[32][..], &[0xbb; 32]].concat();
+        let psk = [
+            &(identity.len() as u16).to_be_bytes()[..],
+            &identity,
+            &(binder.len() as u16).to_be_bytes(),
+            &binder,
+        ]
+        .concat();
+        let hello = crate::ClientHelloBuilder::new()
+            .sni("example.com")
+            .extension(21, vec![0; 256])
+            .extension(41, psk)
+            .build()
+            .unwrap();
+
+        // cut in the middle of the binder; the server reassembles the same
+        // hello, and checks the binder against the same bytes
+        let cut = SplitStrategy::FixedOffset(hello.len() - 16);
+        let fragments = crate::fragment_record(&hello, cut).unwrap();
+        assert_eq!(fragments.len(), 2);
+        let payload: Vec<_> = fragments.iter().flat_map(|f| &f[5..]).copied().collect();
+        assert_eq!(payload, hello[5..]);
+        let fragments: Vec<_> = fragments.iter().map(Vec::as_slice).collect();
+        assert_eq!(crate::parse_sni_from_fragments(&fragments).as_deref(), Some("example.com"));
+        assert!(crate::is_valid_client_hello(&hello));
+    }
+
+    #[test]
+    fn before_sni() {
+        let sni = b"example.com";
+        let hello = hello("example.com");
+        let start = hello.windows(sni.len()).position(|window| window == sni).unwrap();
+
+        // the second fragment starts with the hostname
+        let points = SplitStrategy::BeforeSni.split_points(&hello, &Rng::default());
+        assert_eq!(points, Some(vec![start]));
+        let fragments = crate::fragment_record(&hello, SplitStrategy::BeforeSni).unwrap();
+        assert!(fragments[1][5..].starts_with(sni));
+
+        let config = crate::DetourConfig::from_reader(&b"strategy before-sni"[..]).unwrap();
+        assert_eq!(config.strategy, SplitStrategy::BeforeSni);
+    }
+
+    #[test]
+    fn after_sni() {
+        let sni = b"example.com";
+        let hello = hello("example.com");
+        let start = hello.windows(sni.len()).position(|window| window == sni).unwrap();
+
+        // the first fragment ends with the hostname
+        let points = SplitStrategy::AfterSni.split_points(&hello, &Rng::default());
+        assert_eq!(points, Some(vec![start + sni.len()]));
+        let fragments = crate::fragment_record(&hello, SplitStrategy::AfterSni).unwrap();
+        assert!(fragments[0].ends_with(sni));
+
+        // but it can't cut a hello ending with the hostname
+        let last = crate::ClientHelloBuilder::new().sni("example.com").build().unwrap();
+        assert!(last.ends_with(sni));
+        assert_eq!(SplitStrategy::AfterSni.split_points(&last, &Rng::default()), None);
+
+        let config = crate::DetourConfig::from_reader(&b"strategy after-sni"[..]).unwrap();
+        assert_eq!(config.strategy, SplitStrategy::AfterSni);
+    }
+
+    #[test]
+    fn split_fn() {
+        let hello = hello("example.com");
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let told = seen.clone();
+        let strategy = SplitStrategy::Fn(Arc::new(move |record| {
+            *told.lock().unwrap() = record.to_vec();
+            vec![40, 20]
+        }));
+
+        // given the whole record, and cut at its offsets, in order
+        let fragments = crate::fragment_record(&hello, strategy.clone()).unwrap();
+        let sizes: Vec<_> = fragments.iter().map(|fragment| fragment.len() - 5).collect();
+        assert_eq!(sizes, [15, 20, hello.len() - 40]);
+        assert_eq!(*seen.lock().unwrap(), hello);
+
+        // a closure is equal to itself alone
+        assert_eq!(strategy, strategy.clone());
+        assert_ne!(strategy, SplitStrategy::Fn(Arc::new(|_| vec![40, 20])));
+        assert_eq!(format!("{:?}", strategy), "Fn");
+    }
+}
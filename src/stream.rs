@@ -16,8 +16,35 @@ pub enum MaybeHttpsStream<T> {
     Https(TlsStream<T>),
 }
 
+/// Extra connection information, attached to `Connected` by
+/// `MaybeHttpsStream`.
+///
+/// hyper copies it into the extensions of every response received over the
+/// connection.
+#[derive(Clone, Copy, Debug)]
+pub struct HttpsInfo {
+    is_https: bool,
+}
+
+impl HttpsInfo {
+    /// Returns whether the connection is protected with TLS.
+    pub fn is_https(&self) -> bool {
+        self.is_https
+    }
+}
+
 // ===== impl MaybeHttpsStream =====
 
+impl<T> MaybeHttpsStream<T> {
+    /// Returns whether the stream is protected with TLS.
+    pub fn is_https(&self) -> bool {
+        match self {
+            MaybeHttpsStream::Http(_) => false,
+            MaybeHttpsStream::Https(_) => true,
+        }
+    }
+}
+
 impl<T: fmt::Debug> fmt::Debug for MaybeHttpsStream<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -103,9 +130,210 @@ impl<T: AsyncWrite + AsyncRead + Unpin> AsyncWrite for MaybeHttpsStream<T> {
 
 impl<T: AsyncRead + AsyncWrite + Connection + Unpin> Connection for MaybeHttpsStream<T> {
     fn connected(&self) -> Connected {
-        match self {
+        let connected = match self {
             MaybeHttpsStream::Http(s) => s.connected(),
-            MaybeHttpsStream::Https(s) => s.get_ref().get_ref().get_ref().connected(),
+            MaybeHttpsStream::Https(s) => https_connected(s),
+        };
+        // `extra` chains onto the extras of the inner stream; with the
+        // proxy and h2 flags, they all survive the wrapping
+        connected.extra(HttpsInfo {
+            is_https: self.is_https(),
+        })
+    }
+}
+
+fn https_connected<T>(s: &TlsStream<T>) -> Connected
+where
+    T: AsyncRead + AsyncWrite + Connection + Unpin,
+{
+    let connected = s.get_ref().get_ref().get_ref().connected();
+    // let hyper pick http2 by itself
+    #[cfg(feature = "alpn")]
+    {
+        if negotiated_h2(s) {
+            return connected.negotiated_h2();
+        }
+    }
+    connected
+}
+
+#[cfg(feature = "alpn")]
+fn negotiated_h2<T: AsyncRead + AsyncWrite + Unpin>(s: &TlsStream<T>) -> bool {
+    match s.get_ref().negotiated_alpn() {
+        Ok(Some(alpn)) => alpn == b"h2",
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::http::Extensions;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_native_tls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+    use crate::mock::{tls_acceptor, tls_connector};
+
+    // a tcp connection over loopback, and the server's side of it
+    async fn tcp() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    // a tls connection over `client`, through `tls`, to a server held
+    // until the client is done with it
+    async fn tls<T>(
+        client: T,
+        server: TcpStream,
+        tls: TlsConnector,
+        acceptor: TlsAcceptor,
+    ) -> TlsStream<T>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::spawn(async move {
+            let mut tls = acceptor.accept(server).await.unwrap();
+            let _ = tls.read(&mut [0]).await;
+        });
+        tls.connect("localhost", client).await.unwrap()
+    }
+
+    fn extras(connected: Connected) -> Extensions {
+        let mut extensions = Extensions::new();
+        connected.get_extras(&mut extensions);
+        extensions
+    }
+
+    #[tokio::test]
+    async fn https_info() {
+        let (client, _server) = tcp().await;
+        let http = MaybeHttpsStream::Http(client);
+        assert!(!http.is_https());
+        assert!(!extras(http.connected()).get::<HttpsInfo>().unwrap().is_https());
+
+        let (client, server) = tcp().await;
+        let acceptor = tls_acceptor().build().unwrap().into();
+        let https = MaybeHttpsStream::Https(tls(client, server, tls_connector(), acceptor).await);
+        assert!(https.is_https());
+        assert!(extras(https.connected()).get::<HttpsInfo>().unwrap().is_https());
+    }
+
+    #[tokio::test]
+    async fn inner_extras() {
+        // the detour's own info is kept along with HttpsInfo, over tls too
+        let (client, server) = tcp().await;
+        let http = MaybeHttpsStream::Http(crate::Detour::new(client));
+        let found = extras(http.connected());
+        assert!(found.get::<crate::DetourInfo>().is_some());
+        assert!(found.get::<HttpsInfo>().is_some());
+        drop(server);
+
+        let (client, server) = tcp().await;
+        let acceptor = tls_acceptor().build().unwrap().into();
+        let tls = tls(crate::Detour::new(client), server, tls_connector(), acceptor).await;
+        let found = extras(MaybeHttpsStream::Https(tls).connected());
+        let detour = found.get::<crate::DetourInfo>().unwrap();
+        assert_eq!(detour.sni(), Some("localhost"));
+        assert!(found.get::<HttpsInfo>().unwrap().is_https());
+    }
+
+    // a connection through a proxy, as the connector under us would tell
+    // of it, maybe with h2 agreed on already
+    struct Proxied(TcpStream, bool);
+
+    impl AsyncRead for Proxied {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Proxied {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    impl Connection for Proxied {
+        fn connected(&self) -> Connected {
+            let connected = Connected::new().proxy(true);
+            if self.1 {
+                return connected.negotiated_h2();
+            }
+            connected
+        }
+    }
+
+    #[tokio::test]
+    async fn inner_flags() {
+        // the flags of the inner stream go through the detour, and tls
+        let (client, server) = tcp().await;
+        let http = MaybeHttpsStream::Http(crate::Detour::new(Proxied(client, true)));
+        let connected = http.connected();
+        assert!(connected.is_proxied() && connected.is_negotiated_h2());
+        drop(server);
+
+        let (client, server) = tcp().await;
+        let acceptor = tls_acceptor().build().unwrap().into();
+        let detour = crate::Detour::new(Proxied(client, true));
+        let https = MaybeHttpsStream::Https(tls(detour, server, tls_connector(), acceptor).await);
+        let connected = https.connected();
+        assert!(connected.is_proxied() && connected.is_negotiated_h2());
+        assert!(extras(connected).get::<crate::DetourInfo>().is_some());
+    }
+
+    #[cfg(feature = "alpn")]
+    #[tokio::test]
+    async fn proxied_h2() {
+        // h2 agreed on over tls, through a proxy which knew nothing of it
+        let mut acceptor = tls_acceptor();
+        acceptor.accept_alpn(&["h2"]);
+        let root = native_tls::Certificate::from_pem(crate::mock::CERT).unwrap();
+        let mut connector = native_tls::TlsConnector::builder();
+        let connector = connector.add_root_certificate(root).request_alpns(&["h2"]);
+
+        let (client, server) = tcp().await;
+        let detour = crate::Detour::new(Proxied(client, false));
+        let connector = connector.build().unwrap().into();
+        let tls = tls(detour, server, connector, acceptor.build().unwrap().into()).await;
+        let connected = MaybeHttpsStream::Https(tls).connected();
+        assert!(connected.is_proxied() && connected.is_negotiated_h2());
+    }
+
+    #[cfg(feature = "alpn")]
+    #[tokio::test]
+    async fn negotiated_h2() {
+        let mut acceptor = tls_acceptor();
+        acceptor.accept_alpn(&["h2", "http/1.1"]);
+        let acceptor: TlsAcceptor = acceptor.build().unwrap().into();
+
+        for (offered, h2) in [(&["h2", "http/1.1"][..], true), (&["http/1.1"][..], false)] {
+            let root = native_tls::Certificate::from_pem(crate::mock::CERT).unwrap();
+            let mut connector = native_tls::TlsConnector::builder();
+            let connector = connector.add_root_certificate(root).request_alpns(offered);
+
+            let (client, server) = tcp().await;
+            let tls = tls(client, server, connector.build().unwrap().into(), acceptor.clone());
+            let https = MaybeHttpsStream::Https(tls.await);
+            assert_eq!(https.connected().is_negotiated_h2(), h2);
         }
     }
 }
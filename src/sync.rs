@@ -0,0 +1,271 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::DetourConfig;
+use crate::detour::{decide, plan, DetourDecision, FragmentInfo};
+use crate::hello::{is_hello, redacted, ParseError};
+
+/// a blocking [`Detour`](crate::Detour), for a `std::io::Write` socket
+///
+/// it fragments the ClientHello just like the async detour does with the
+/// same config, but writes all the fragments before returning. if writing
+/// one fails, the rest are dropped along with the connection.
+pub struct SyncDetour<T: Write> {
+    sock: T,
+    seen_hello: bool,
+    sni: Option<String>,
+    parse_error: Option<ParseError>,
+    fragment_sizes: Vec<usize>,
+    fragment_info: Option<FragmentInfo>,
+    decision: Option<DetourDecision>,
+    fragment_duration: Option<Duration>,
+    config: Arc<DetourConfig>,
+}
+
+impl<T: Write> SyncDetour<T> {
+    /// make a new detour from a stream
+    pub fn new(sock: T) -> Self {
+        Self::from_config(sock, Arc::default())
+    }
+
+    /// make a new detour from a stream, sharing a config with others
+    pub fn from_config(sock: T, config: Arc<DetourConfig>) -> Self {
+        Self {
+            sock,
+            seen_hello: false,
+            sni: None,
+            parse_error: None,
+            fragment_sizes: Vec::new(),
+            fragment_info: None,
+            decision: None,
+            fragment_duration: None,
+            config,
+        }
+    }
+
+    /// the hostname in the ClientHello fragmented by this detour, if any
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    /// why the ClientHello couldn't be parsed, if it couldn't
+    pub fn parse_error(&self) -> Option<ParseError> {
+        self.parse_error
+    }
+
+    /// the payload length of each record the ClientHello was cut into
+    pub fn fragment_sizes(&self) -> &[usize] {
+        &self.fragment_sizes
+    }
+
+    /// how the ClientHello was fragmented, if it was
+    pub fn fragment_info(&self) -> Option<&FragmentInfo> {
+        self.fragment_info.as_ref()
+    }
+
+    /// why the first record was fragmented or not, like
+    /// [`Detour::decision`](crate::Detour::decision)
+    pub fn decision(&self) -> Option<DetourDecision> {
+        self.decision
+    }
+
+    /// how long it took from writing the first fragment of the ClientHello
+    /// to writing the last one, if it was fragmented
+    pub fn fragment_duration(&self) -> Option<Duration> {
+        self.fragment_duration
+    }
+
+    /// the config this detour fragments with, shared with the others made
+    /// from it
+    pub fn config(&self) -> &Arc<DetourConfig> {
+        &self.config
+    }
+
+    /// consume self, return inner socket
+    pub fn into_inner(self) -> T {
+        self.sock
+    }
+
+    // write as is, showing the tap what the socket took
+    fn write_through(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.sock.write(buf)?;
+        if let Some(tap) = &self.config.tap {
+            tap.call(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Write + fmt::Debug> fmt::Debug for SyncDetour<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncDetour")
+            .field("sock", &self.sock)
+            .field("seen_hello", &self.seen_hello)
+            .field("sni", &redacted(self.sni.as_deref()))
+            .field("parse_error", &self.parse_error)
+            .field("fragment_sizes", &self.fragment_sizes)
+            .field("fragment_info", &self.fragment_info)
+            .field("decision", &self.decision)
+            .field("fragment_duration", &self.fragment_duration)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<T: Write> Deref for SyncDetour<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.sock
+    }
+}
+
+impl<T: Write> DerefMut for SyncDetour<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.sock
+    }
+}
+
+impl<T: Read + Write> Read for SyncDetour<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.read(buf)
+    }
+}
+
+impl<T: Write> Write for SyncDetour<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.seen_hello || !is_hello(buf) {
+            // same as Detour; the hello, if any, always comes first
+            if !self.seen_hello {
+                self.decision = Some(DetourDecision::SkippedNotHello);
+                decide(&self.config, DetourDecision::SkippedNotHello);
+            }
+            self.seen_hello |= self.config.first_write_only && !buf.is_empty();
+            return self.write_through(buf);
+        }
+
+        let plan = plan(&self.config, 0, buf);
+        if let Some(e) = plan.error() {
+            return Err(e);
+        }
+        self.sni = plan.sni;
+        self.parse_error = plan.parse_error;
+        self.decision = plan.decision;
+        self.seen_hello = true;
+
+        let fragments = match plan.fragments {
+            Some(fragments) => fragments,
+            None => return self.write_through(buf),
+        };
+        self.fragment_sizes = plan.fragment_sizes;
+        self.fragment_info = plan.info;
+
+        let started = Instant::now();
+        let last = fragments.len() - 1;
+        for (i, fragment) in fragments.iter().enumerate() {
+            self.sock.write_all(fragment)?;
+            if let Some(tap) = &self.config.tap {
+                tap.call(fragment);
+            }
+            if i == last {
+                break;
+            }
+            if self.config.flush_between {
+                self.sock.flush()?;
+            }
+            if self.config.anti_coalesce {
+                std::thread::yield_now();
+            }
+        }
+        let duration = Some(started.elapsed());
+        #[cfg(feature = "metrics")]
+        crate::detour::record_duration(duration);
+        self.fragment_duration = duration;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::config::DetourBuilder;
+    use crate::mock::{hello, Event, Mock};
+    use crate::strategy::SplitStrategy;
+
+    #[tokio::test]
+    async fn same_as_async() {
+        let hello = hello("example.com");
+        for strategy in [SplitStrategy::SniMidpoint, SplitStrategy::EveryByte] {
+            let config = DetourBuilder::new().strategy(strategy).flush_between(true).build_shared();
+
+            let mut detour = crate::Detour::from_config(Mock::new(), config.clone());
+            detour.write_all(&hello).await.unwrap();
+            let mut sync = SyncDetour::from_config(Mock::new(), config);
+            sync.write_all(&hello).unwrap();
+            assert!(Arc::ptr_eq(sync.config(), detour.config()));
+
+            assert_eq!(sync.events, detour.events);
+            assert_eq!(sync.fragment_sizes(), detour.fragment_sizes());
+            assert_eq!(sync.decision(), detour.decision());
+            assert_eq!(sync.sni(), Some("example.com"));
+        }
+    }
+
+    #[test]
+    fn short_writes() {
+        let hello = hello("example.com");
+        let fragments = crate::fragment_record(&hello, SplitStrategy::default()).unwrap();
+
+        // the write returns once every fragment is out, whole
+        let mut sync = SyncDetour::new(Mock::short(7));
+        assert_eq!(sync.write(&hello).unwrap(), hello.len());
+        assert_eq!(sync.written(), fragments.concat());
+        assert!(sync.writes().iter().all(|write| write.len() <= 7));
+        assert!(sync.fragment_duration().is_some());
+
+        // and what follows goes out as is
+        sync.write_all(b"after").unwrap();
+        assert_eq!(sync.written(), [&fragments.concat()[..], b"after"].concat());
+    }
+
+    #[test]
+    fn not_hello() {
+        let mut sync = SyncDetour::new(Mock::new());
+        sync.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(sync.events, vec![Event::Write(b"GET / HTTP/1.1\r\n\r\n".to_vec())]);
+        assert_eq!(sync.decision(), Some(DetourDecision::SkippedNotHello));
+        assert_eq!(sync.fragment_duration(), None);
+    }
+
+    #[test]
+    fn first_write_only() {
+        let hello = hello("example.com");
+
+        // the same as Detour; an empty write isn't the first one
+        for (enable, fragmented) in [(true, false), (false, true)] {
+            let config = DetourBuilder::new().first_write_only(enable).build_shared();
+            let mut sync = SyncDetour::from_config(Mock::new(), config);
+            assert_eq!(sync.write(&[]).unwrap(), 0);
+            sync.write_all(b"STARTTLS\r\n").unwrap();
+            sync.write_all(&hello).unwrap();
+            assert_eq!(!sync.fragment_sizes().is_empty(), fragmented, "{}", enable);
+        }
+
+        let mut sync = SyncDetour::new(Mock::new());
+        assert_eq!(sync.write(&[]).unwrap(), 0);
+        sync.write_all(&hello).unwrap();
+        assert_eq!(sync.decision(), Some(DetourDecision::Fragmented));
+    }
+}
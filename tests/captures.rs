@@ -0,0 +1,65 @@
+use detour::{fragment_record, parse_sni_from_fragments, ClientHello, SplitStrategy};
+
+// ClientHellos captured off the wire, and the hostname each one carries.
+// every one is checked to parse, and to come back whole from being cut
+// with each strategy, so a change to the parser shows up against hellos
+// written by real tls stacks rather than by hand.
+//
+// each is the first record the client sent to a local listener on debian
+// 12, saved as is: `curl` with `--resolve example.com:<port>:127.0.0.1`,
+// `openssl s_client -servername www.example.org`, `wget` to localhost,
+// and python's `ssl.create_default_context().wrap_socket()`. there's no
+// browser among them yet; to add one, e.g. chrome or firefox, save the
+// first record it sends the same way, and list it here with its version.
+const CAPTURES: &[(&str, &[u8], Option<&str>)] = &[
+    ("curl 7.88.1 (openssl 3.0)", include_bytes!("captures/curl.bin"), Some("example.com")),
+    ("openssl s_client 3.0", include_bytes!("captures/openssl.bin"), Some("www.example.org")),
+    ("wget 1.21.3 (gnutls 3.7.9)", include_bytes!("captures/wget.bin"), Some("localhost")),
+    ("python 3.11 (openssl 3)", include_bytes!("captures/python.bin"), Some("python.example.net")),
+];
+
+#[test]
+fn captures_parse() {
+    for &(name, record, sni) in CAPTURES {
+        let hello = ClientHello::parse(record);
+        let hello = hello.unwrap_or_else(|| panic!("{}: not a ClientHello", name));
+        assert_eq!(hello.sni(), sni, "{}", name);
+    }
+}
+
+#[test]
+fn captures_reassemble() {
+    let strategies = [
+        SplitStrategy::SniMidpoint,
+        SplitStrategy::SniFraction(0.25),
+        SplitStrategy::BeforeSni,
+        SplitStrategy::AfterSni,
+        SplitStrategy::FixedOffset(1),
+        SplitStrategy::FromEnd(1),
+        SplitStrategy::Random,
+        SplitStrategy::EveryByte,
+        SplitStrategy::Identity,
+    ];
+
+    for &(name, record, sni) in CAPTURES {
+        for strategy in &strategies {
+            let fragments = fragment_record(record, strategy.clone()).unwrap();
+
+            // every fragment keeps the header of the record, and the
+            // payloads add up to the original one
+            let mut payload = Vec::new();
+            for fragment in &fragments {
+                let len = u16::from_be_bytes([fragment[3], fragment[4]]) as usize;
+                assert_eq!(fragment[..3], record[..3], "{}: {:?}", name, strategy);
+                assert_eq!(len, fragment.len() - 5, "{}: {:?}", name, strategy);
+                assert_ne!(len, 0, "{}: {:?}", name, strategy);
+                payload.extend_from_slice(&fragment[5..]);
+            }
+            assert_eq!(payload, record[5..], "{}: {:?}", name, strategy);
+
+            let fragments: Vec<&[u8]> = fragments.iter().map(Vec::as_slice).collect();
+            let parsed = parse_sni_from_fragments(&fragments);
+            assert_eq!(parsed.as_deref(), sni, "{}: {:?}", name, strategy);
+        }
+    }
+}
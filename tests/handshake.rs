@@ -0,0 +1,263 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use detour::{DetourBuilder, SplitStrategy};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls;
+
+// whole handshakes through a detour, against a local tls server which
+// reassembles the fragments like any conforming one would. the certificate
+// is a self-signed one for localhost, trusted by the client alone.
+const CERT: &[u8] = include_bytes!("handshake/cert.pem");
+const KEY: &[u8] = include_bytes!("handshake/key.pem");
+
+// the server's side of a tcp stream, keeping every byte read from it
+struct Recorded {
+    inner: TcpStream,
+    read: Arc<Mutex<Vec<u8>>>,
+}
+
+impl AsyncRead for Recorded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read.lock().unwrap().extend_from_slice(&buf.filled()[filled..]);
+        poll
+    }
+}
+
+impl AsyncWrite for Recorded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// how many records the ClientHello at the start of `stream` came in
+fn hello_records(stream: &[u8]) -> usize {
+    let mut hello = Vec::new();
+    let mut records = 0;
+    let mut rest = stream;
+    while rest.len() >= 5 && rest[0] == 0x16 {
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        hello.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+        records += 1;
+        // the handshake header tells the length of the whole hello
+        if hello.len() >= 4 {
+            let hello_len = u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize;
+            if hello.len() >= 4 + hello_len {
+                break;
+            }
+        }
+    }
+    records
+}
+
+// a tls server of the certificate, and a client trusting it
+fn acceptor() -> tokio_native_tls::TlsAcceptor {
+    let identity = native_tls::Identity::from_pkcs8(CERT, KEY).unwrap();
+    native_tls::TlsAcceptor::new(identity).unwrap().into()
+}
+
+fn connector() -> tokio_native_tls::TlsConnector {
+    let root = native_tls::Certificate::from_pem(CERT).unwrap();
+    native_tls::TlsConnector::builder().add_root_certificate(root).build().unwrap().into()
+}
+
+// a server of `acceptor` echoing a byte to a single client; it tells how
+// many records the hello came in
+async fn echo_server(
+    acceptor: tokio_native_tls::TlsAcceptor,
+) -> (SocketAddr, JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Recorded { inner: tcp, read: read.clone() };
+        let mut tls = acceptor.accept(recorded).await.unwrap();
+        let mut byte = [0];
+        tls.read_exact(&mut byte).await.unwrap();
+        tls.write_all(&byte).await.unwrap();
+        tls.shutdown().await.unwrap();
+        let read = read.lock().unwrap();
+        hello_records(&read)
+    });
+    (addr, server)
+}
+
+// echo a byte over `stream`
+async fn echo<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+    stream.write_all(b"!").await.unwrap();
+    let mut byte = [0];
+    stream.read_exact(&mut byte).await.unwrap();
+    assert_eq!(&byte, b"!");
+}
+
+// run a handshake detoured by `detour`, and echo a byte over it; returns
+// how many records the client counted, and the server saw
+async fn handshake(detour: DetourBuilder) -> (usize, usize) {
+    let (addr, server) = echo_server(acceptor()).await;
+
+    let tls = connector();
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tls.connect("localhost", detour.build(tcp)).await.unwrap();
+    let fragments = stream.get_ref().get_ref().get_ref().fragment_sizes().len();
+    echo(&mut stream).await;
+
+    (fragments, server.await.unwrap())
+}
+
+#[tokio::test]
+async fn sni_midpoint() {
+    assert_eq!(handshake(DetourBuilder::new()).await, (2, 2));
+}
+
+#[tokio::test]
+async fn every_byte() {
+    let mut detour = DetourBuilder::new();
+    detour.strategy(SplitStrategy::EveryByte).max_fragments(4);
+    assert_eq!(handshake(detour).await, (4, 4));
+}
+
+#[tokio::test]
+async fn identity() {
+    let mut detour = DetourBuilder::new();
+    detour.strategy(SplitStrategy::Identity);
+    assert_eq!(handshake(detour).await, (1, 1));
+}
+
+#[tokio::test]
+async fn rustls() {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName};
+
+    // the detour knows nothing of the tls library above it. webpki takes no
+    // self-signed certificate for a server, so this one has an authority
+    const CA: &[u8] = include_bytes!("handshake/ca.pem");
+    const LEAF: &[u8] = include_bytes!("handshake/leaf.pem");
+    const LEAF_KEY: &[u8] = include_bytes!("handshake/leaf-key.pem");
+
+    let identity = native_tls::Identity::from_pkcs8(LEAF, LEAF_KEY).unwrap();
+    let (addr, server) = echo_server(native_tls::TlsAcceptor::new(identity).unwrap().into()).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(CA).unwrap()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tls = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut stream = tls.connect(name, DetourBuilder::new().build(tcp)).await.unwrap();
+    let detour = stream.get_ref().0;
+    assert_eq!(detour.sni(), Some("localhost"));
+    let fragments = detour.fragment_sizes().len();
+    echo(&mut stream).await;
+
+    assert_eq!((fragments, server.await.unwrap()), (2, 2));
+}
+
+// read from `stream` up to the end of the head of an http message
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Vec<u8> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    head
+}
+
+#[tokio::test]
+async fn upgrade() {
+    // a server switching to websocket, and echoing whatever comes after
+    let acceptor = acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut tls = acceptor.accept(tcp).await.unwrap();
+        read_head(&mut tls).await;
+        let head = "HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        tls.write_all(head.as_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        loop {
+            match tls.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => tls.write_all(&buf[..n]).await.unwrap(),
+            }
+        }
+    });
+
+    // every write is looked at until the hello, and none after it
+    let mut detour = DetourBuilder::new();
+    detour.first_write_only(false);
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut stream = connector().connect("localhost", detour.build(tcp)).await.unwrap();
+    let request = "GET /chat HTTP/1.1\r\nHost: localhost\r\n\
+                   Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    assert!(read_head(&mut stream).await.starts_with(b"HTTP/1.1 101 "));
+
+    // the upgraded stream carries every byte as it is, and the detour
+    // cuts nothing more
+    let frames: Vec<u8> = (0..=255).cycle().take(1024).collect();
+    stream.write_all(&frames).await.unwrap();
+    let mut echoed = vec![0; frames.len()];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, frames);
+    assert_eq!(stream.get_ref().get_ref().get_ref().fragment_sizes().len(), 2);
+}
+
+#[tokio::test]
+async fn large_download() {
+    // a body of several megabytes, many records and reads long
+    let body: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+    let sent = body.clone();
+
+    let acceptor = acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut tls = acceptor.accept(tcp).await.unwrap();
+        tls.write_all(&sent).await.unwrap();
+        tls.shutdown().await.unwrap();
+    });
+
+    // every byte read through the detour comes out as the server sent it
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let detour = DetourBuilder::new().build(tcp);
+    let mut stream = connector().connect("localhost", detour).await.unwrap();
+    assert_eq!(stream.get_ref().get_ref().get_ref().fragment_sizes().len(), 2);
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await.unwrap();
+    assert!(received == body, "{} bytes of {} read back", received.len(), body.len());
+}
@@ -0,0 +1,95 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use detour::Detour;
+use tokio::io::AsyncWrite;
+
+// the allocator of this test binary, counting the allocations made on the
+// thread measuring them; the harness keeps allocating on its own threads
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// the allocations `f` makes on this thread
+fn allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    COUNTING.with(|counting| counting.set(true));
+    let result = f();
+    COUNTING.with(|counting| counting.set(false));
+    (result, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+// a socket taking every write whole, counting them
+#[derive(Default)]
+struct Sink {
+    writes: usize,
+}
+
+impl AsyncWrite for Sink {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writes += 1;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn passthrough_allocates_nothing() {
+    // a whole application_data record, as long as a record may be
+    let mut record = vec![0x17, 0x03, 0x03, 0x40, 0x00];
+    record.resize(5 + (1 << 14), 0xaa);
+
+    let mut detour = Detour::new(Sink::default());
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // the first write, told apart from a hello, and every one after it
+    for writes in 1..=2 {
+        let (poll, allocations) =
+            allocations(|| Pin::new(&mut detour).poll_write(&mut cx, &record));
+        assert!(matches!(poll, Poll::Ready(Ok(n)) if n == record.len()));
+        assert_eq!(allocations, 0);
+        assert_eq!(detour.writes, writes);
+    }
+}